@@ -128,10 +128,14 @@ impl CanvasUtils {
             VncEvent::JpegImage(_rect, _data) => {
                 tracing::warn!("Jpeg event got, but ignore it");
             }
-            VncEvent::SetCursor(rect, data) => {
-                if rect.width != 0 {
-                    self.draw(rect, data)?;
-                }
+            VncEvent::SetCursor(rect, _rgba, hotspot) => {
+                // the window keeps its own cursor, the image is RGBA and no part of the screen
+                tracing::info!(
+                    "Cursor of {}x{} with its hotspot at {:?}",
+                    rect.width,
+                    rect.height,
+                    hotspot
+                );
             }
             VncEvent::Text(string) => {
                 tracing::info!("Got clipboard message {}", string);
@@ -410,26 +414,15 @@ async fn main() -> Result<()> {
                 }
             }
 
-            // Handle scroll wheel
-            if let Some((_scroll_x, scroll_y)) = canvas.window.get_scroll_wheel() {
-                if scroll_y != 0.0 {
-                    // VNC scroll wheel: button 4 for up, button 5 for down
-                    let scroll_button = if scroll_y > 0.0 { 8 } else { 16 }; // bit 3 for up, bit 4 for down
-                    let mouse_event = ClientMouseEvent {
-                        position_x: last_mouse_pos.0 as u16,
-                        position_y: last_mouse_pos.1 as u16,
-                        bottons: scroll_button,
-                    };
-                    let _ = vnc.input(X11Event::PointerEvent(mouse_event)).await;
-
-                    // Send button release immediately after
-                    let mouse_event_release = ClientMouseEvent {
-                        position_x: last_mouse_pos.0 as u16,
-                        position_y: last_mouse_pos.1 as u16,
-                        bottons: 0,
-                    };
-                    let _ = vnc.input(X11Event::PointerEvent(mouse_event_release)).await;
-                }
+            // Handle scroll wheel, as the buttons 4 to 7 configured by `ScrollConfig`
+            if let Some(delta) = canvas.window.get_scroll_wheel() {
+                let _ = vnc
+                    .scroll(
+                        (last_mouse_pos.0 as u16, last_mouse_pos.1 as u16),
+                        last_mouse_buttons,
+                        delta,
+                    )
+                    .await;
             }
         }
 
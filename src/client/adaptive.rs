@@ -0,0 +1,192 @@
+use std::time::Duration;
+use tracing::info;
+
+use super::connection::DecodeStats;
+use crate::{VncClient, VncEncoding, VncError};
+
+/// Thresholds of the [AdaptiveEncodingController]
+///
+/// Rates are taken from [DecodeStats], i.e. the pixels per second while updates are arriving
+///
+/// Pixels rather than bytes, as the bytes depend on the encoding in use,
+///
+/// a fast link under [VncEncoding::Tight] carries few of them but still updates the screen quickly
+#[derive(Debug, Clone)]
+pub struct AdaptiveConfig {
+    /// time between two evaluations in [AdaptiveEncodingController::run]
+    pub interval: Duration,
+    /// intervals with fewer updated pixels tell too little to decide on
+    pub min_pixels: u64,
+    /// prefer [VncEncoding::Raw] once updates cover at least this many pixels per second
+    pub fast_link: u64,
+    /// prefer a compressed encoding once updates cover at most this many pixels per second
+    pub slow_link: u64,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            min_pixels: 1_000_000,
+            fast_link: 20_000_000,
+            slow_link: 1_000_000,
+        }
+    }
+}
+
+/// Re-orders the encodings of a [VncClient] to suit the measured link
+///
+/// On a fast link [VncEncoding::Raw] spares the decoding work on both ends,
+///
+/// while on a slow one the first of [VncEncoding::Tight], [VncEncoding::Zrle]
+///
+/// and [VncEncoding::Trle] in the current list is moved to the front
+///
+/// Only the encodings already informed are re-ordered, none is added
+pub struct AdaptiveEncodingController {
+    config: AdaptiveConfig,
+    last: DecodeStats,
+}
+
+impl AdaptiveEncodingController {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            config,
+            last: DecodeStats::default(),
+        }
+    }
+
+    /// Look at the updates received since the last call and send SetEncodings if it pays off
+    ///
+    /// Returns the new order of the encodings if they changed
+    pub async fn evaluate(
+        &mut self,
+        vnc: &VncClient,
+    ) -> Result<Option<Vec<VncEncoding>>, VncError> {
+        let stats = vnc.decode_stats().await;
+        let Some(reordered) = self.decide(stats, vnc.encodings().await) else {
+            return Ok(None);
+        };
+        vnc.set_encoding_priority(&reordered).await?;
+        Ok(Some(reordered))
+    }
+
+    /// The new order of `encodings` given the totals `stats`, which become the base of the next call
+    fn decide(
+        &mut self,
+        stats: DecodeStats,
+        encodings: Vec<VncEncoding>,
+    ) -> Option<Vec<VncEncoding>> {
+        let pixels = stats.pixels - self.last.pixels;
+        let busy = stats.busy - self.last.busy;
+        self.last = stats;
+        if pixels < self.config.min_pixels || busy.is_zero() {
+            return None;
+        }
+
+        let rate = pixels as f64 / busy.as_secs_f64();
+        let preferred = if rate >= self.config.fast_link as f64 {
+            Some(VncEncoding::Raw)
+        } else if rate <= self.config.slow_link as f64 {
+            encodings
+                .iter()
+                .find(|e| {
+                    matches!(
+                        e,
+                        VncEncoding::Tight | VncEncoding::Zrle | VncEncoding::Trle
+                    )
+                })
+                .copied()
+        } else {
+            None
+        };
+        let preferred = preferred?;
+        if encodings.first() == Some(&preferred) || !encodings.contains(&preferred) {
+            return None;
+        }
+
+        let mut reordered = vec![preferred];
+        reordered.extend(encodings.into_iter().filter(|e| *e != preferred));
+        Some(reordered)
+    }
+
+    /// Evaluate every [AdaptiveConfig::interval] until `vnc` is closed
+    ///
+    /// ```no_run
+    /// use vnc::client::adaptive::{AdaptiveConfig, AdaptiveEncodingController};
+    /// # async fn spawn(vnc: vnc::VncClient) {
+    /// tokio::spawn(AdaptiveEncodingController::new(AdaptiveConfig::default()).run(vnc.clone()));
+    /// # }
+    /// ```
+    pub async fn run(mut self, vnc: VncClient) -> Result<(), VncError> {
+        while !vnc.is_closed() {
+            tokio::time::sleep(self.config.interval).await;
+            match self.evaluate(&vnc).await {
+                Ok(Some(encodings)) => info!("Encodings re-ordered to {:?}", encodings),
+                Ok(None) => (),
+                Err(VncError::ClientNotRunning) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Totals after `secs` busy seconds covering `pixels` in all
+    fn stats(pixels: u64, secs: u64) -> DecodeStats {
+        DecodeStats {
+            updates: 1,
+            bytes: 0,
+            pixels,
+            busy: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn fast_link_prefers_raw() {
+        let mut controller = AdaptiveEncodingController::new(AdaptiveConfig::default());
+        let encodings = vec![
+            VncEncoding::Tight,
+            VncEncoding::Raw,
+            VncEncoding::CursorPseudo,
+        ];
+        // 40 megapixels a second, however few bytes Tight made of them
+        assert_eq!(
+            controller.decide(stats(80_000_000, 2), encodings),
+            Some(vec![
+                VncEncoding::Raw,
+                VncEncoding::Tight,
+                VncEncoding::CursorPseudo
+            ])
+        );
+        // already first
+        let encodings = vec![VncEncoding::Raw, VncEncoding::Tight];
+        assert_eq!(controller.decide(stats(160_000_000, 4), encodings), None);
+    }
+
+    #[test]
+    fn slow_link_prefers_compression() {
+        let mut controller = AdaptiveEncodingController::new(AdaptiveConfig::default());
+        let encodings = vec![VncEncoding::Raw, VncEncoding::Zrle, VncEncoding::Tight];
+        // half a megapixel a second
+        assert_eq!(
+            controller.decide(stats(2_000_000, 4), encodings.clone()),
+            Some(vec![
+                VncEncoding::Zrle,
+                VncEncoding::Raw,
+                VncEncoding::Tight
+            ])
+        );
+        // only the delta counts, 5 megapixels a second is neither fast nor slow
+        assert_eq!(
+            controller.decide(stats(12_000_000, 6), encodings.clone()),
+            None
+        );
+        // too few pixels to tell
+        assert_eq!(controller.decide(stats(12_500_000, 10), encodings), None);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::protocol::security::{des, types::AuthResult};
 use crate::VncError;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Credentials for VNC authentication
@@ -7,22 +8,36 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 pub struct Credentials {
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Passwords to try in turn if `password` is rejected
+    ///
+    /// Only used by [crate::client::builder::VncState::try_start_with_fallback]
+    pub fallback_passwords: Vec<String>,
 }
 
 impl Credentials {
     /// Create credentials with no authentication
     pub fn new(username: Option<String>, password: Option<String>) -> Self {
-        Self { username, password }
+        Self {
+            username,
+            password,
+            fallback_passwords: Vec::new(),
+        }
+    }
+
+    /// Add another password to try if the previous ones are rejected
+    pub fn add_fallback_password(mut self, password: String) -> Self {
+        self.fallback_passwords.push(password);
+        self
     }
 }
 
-pub(super) struct AuthHelper {
+pub(crate) struct AuthHelper {
     challenge: [u8; 16],
     key: [u8; 8],
 }
 
 impl AuthHelper {
-    pub(super) async fn read<S>(reader: &mut S, password: &str) -> Result<Self, VncError>
+    pub(crate) async fn read<S>(reader: &mut S, password: &str) -> Result<Self, VncError>
     where
         S: AsyncRead + Unpin,
     {
@@ -47,7 +62,7 @@ impl AuthHelper {
         Ok(Self { challenge, key })
     }
 
-    pub(super) async fn write<S>(&self, writer: &mut S) -> Result<(), VncError>
+    pub(crate) async fn write<S>(&self, writer: &mut S) -> Result<(), VncError>
     where
         S: AsyncWrite + Unpin,
     {
@@ -56,11 +71,14 @@ impl AuthHelper {
         Ok(())
     }
 
-    pub(super) async fn finish<S>(self, reader: &mut S) -> Result<AuthResult, VncError>
+    pub(super) async fn finish<S>(
+        self,
+        reader: &mut S,
+        lenient: Option<Duration>,
+    ) -> Result<AuthResult, VncError>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        let result = reader.read_u32().await?;
-        Ok(result.into())
+        AuthResult::read(reader, lenient).await
     }
 }
@@ -1,11 +1,21 @@
 use crate::client::auth::AuthHelper;
-use crate::protocol::security::vencrypt::{VeNCryptAuth, VncStream};
-use crate::protocol::security::{AuthResult, SecurityType};
+#[cfg(feature = "ard")]
+use crate::client::security::ard;
+use crate::protocol::messages::DEFAULT_MAX_NAME_LEN;
+use crate::protocol::security::vencrypt::{
+    TlsOptions, TlsVerification, VeNCryptAuth, VeNCryptSubtype, VeNCryptSubtypeSelector, VncStream,
+};
+use crate::protocol::security::{tight, AuthResult, SecurityType};
+use crate::protocol::text::read_reason;
 use crate::{Credentials, VncClient};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tracing::{info, trace};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tracing::{error, info, trace};
 
-use crate::{PixelFormat, VncEncoding, VncError, VncVersion};
+use crate::{PixelFormat, QualityPreset, VncEncoding, VncError, VncVersion};
 
 pub enum VncState<S>
 where
@@ -20,7 +30,31 @@ impl<S> VncState<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
 {
-    pub async fn try_start(mut self) -> Result<Self, VncError> {
+    /// Go through the handshake and authentication until connected
+    ///
+    /// With [VncConnector::set_read_timeout] the whole of it has to finish within the timeout,
+    ///
+    /// as a server never idles there, otherwise it fails with [VncError::ReadTimeout]
+    ///
+    pub async fn try_start(self) -> Result<Self, VncError> {
+        let timeout = match &self {
+            VncState::Handshake(connector) | VncState::Authenticate(connector) => {
+                connector.config.read_timeout
+            }
+            VncState::Connected(_) => None,
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.negotiate())
+                .await
+                .map_err(|_| {
+                    error!("Handshake not finished within {:?}", timeout);
+                    VncError::ReadTimeout
+                })?,
+            None => self.negotiate().await,
+        }
+    }
+
+    async fn negotiate(mut self) -> Result<Self, VncError> {
         loop {
             self = match self {
                 VncState::Connected(_) => return Ok(self),
@@ -32,34 +66,64 @@ where
                     };
                     trace!(
                         "Our version {:?}, server version {:?}",
-                        connector.rfb_version,
+                        connector.config.rfb_version,
                         rfbversion
                     );
 
                     // Record the negotiated rfbversion
-                    connector.rfb_version = connector.rfb_version.min(rfbversion);
-                    trace!("Negotiated rfb version: {:?}", connector.rfb_version);
+                    connector.config.rfb_version = connector.config.rfb_version.min(rfbversion);
+                    trace!("Negotiated rfb version: {:?}", connector.config.rfb_version);
 
-                    match &mut connector.stream {
-                        VncStream::Plain(stream) => rfbversion.write(stream).await?,
-                        VncStream::Tls(stream) => rfbversion.write(stream).await?,
+                    match connector.config.version_string {
+                        // only send the customized banner if it still describes what we speak
+                        Some(banner)
+                            if VncVersion::from(banner) == connector.config.rfb_version =>
+                        {
+                            trace!("Send customized version {:?}", banner);
+                            match &mut connector.stream {
+                                VncStream::Plain(stream) => stream.write_all(&banner).await?,
+                                VncStream::Tls(stream) => stream.write_all(&banner).await?,
+                            };
+                        }
+                        _ => match &mut connector.stream {
+                            VncStream::Plain(stream) => {
+                                connector.config.rfb_version.write(stream).await?
+                            }
+                            VncStream::Tls(stream) => {
+                                connector.config.rfb_version.write(stream).await?
+                            }
+                        },
                     };
                     VncState::Authenticate(connector)
                 }
                 VncState::Authenticate(mut connector) => {
+                    let lenient = connector.config.lenient_security_result;
                     let security_types = match &mut connector.stream {
                         VncStream::Plain(stream) => {
-                            SecurityType::read(stream, &connector.rfb_version).await?
+                            SecurityType::read(stream, &connector.config.rfb_version).await?
                         }
                         VncStream::Tls(stream) => {
-                            SecurityType::read(stream, &connector.rfb_version).await?
+                            SecurityType::read(stream, &connector.config.rfb_version).await?
                         }
                     };
 
                     assert!(!security_types.is_empty());
+                    let mut apple_auth = false;
+
+                    // the 3.3 server picks the type alone, and only None and VncAuth exist in 3.3
+                    if let (VncVersion::RFB33, [security_type]) =
+                        (connector.config.rfb_version, security_types.as_slice())
+                    {
+                        if !matches!(security_type, SecurityType::None | SecurityType::VncAuth) {
+                            error!("RFB 3.3 server chose {:?}", security_type);
+                            return Err(VncError::General(format!(
+                                "RFB 3.3 server chose {security_type:?}, which doesn't exist in 3.3"
+                            )));
+                        }
+                    }
 
                     if security_types.contains(&SecurityType::None) {
-                        match connector.rfb_version {
+                        match connector.config.rfb_version {
                             VncVersion::RFB33 => {
                                 info!("No auth needed in vnc3.3");
                             }
@@ -79,22 +143,25 @@ where
                                 match &mut connector.stream {
                                     VncStream::Plain(stream) => {
                                         SecurityType::write(&SecurityType::None, stream).await?;
-                                        let mut ok = [0; 4];
-                                        stream.read_exact(&mut ok).await?;
+                                        AuthResult::read(stream, lenient).await?;
                                     }
                                     VncStream::Tls(stream) => {
                                         SecurityType::write(&SecurityType::None, stream).await?;
-                                        let mut ok = [0; 4];
-                                        stream.read_exact(&mut ok).await?;
+                                        AuthResult::read(stream, lenient).await?;
                                     }
                                 }
                             }
                         }
                     } else {
                         // choose a auth method
-                        if security_types.contains(&SecurityType::VeNCrypt) {
+                        if security_types.contains(&SecurityType::VeNCrypt)
+                            && !connector
+                                .config
+                                .excluded_security
+                                .contains(&SecurityType::VeNCrypt)
+                        {
                             // Handle VeNCrypt authentication (preferred)
-                            if connector.rfb_version != VncVersion::RFB33 {
+                            if connector.config.rfb_version != VncVersion::RFB33 {
                                 match &mut connector.stream {
                                     VncStream::Plain(stream) => {
                                         SecurityType::write(&SecurityType::VeNCrypt, stream).await?
@@ -105,14 +172,17 @@ where
                                 };
                             }
 
-                            let Some(password) = connector.credentials.password else {
+                            let Some(password) = connector.config.credentials.password.clone()
+                            else {
                                 return Err(VncError::MisingPassword);
                             };
 
                             let username = connector
+                                .config
                                 .credentials
                                 .username
-                                .unwrap_or(String::default())
+                                .clone()
+                                .unwrap_or_default()
                                 .to_string();
 
                             // Perform VeNCrypt authentication
@@ -125,38 +195,101 @@ where
                                     ))
                                 }
                             };
-                            connector.stream = VeNCryptAuth::authenticate(
+                            let tls = TlsOptions {
+                                verification: connector.config.tls_verification,
+                                root_certificates: connector.config.tls_roots.clone(),
+                                server_name: connector.config.tls_server_name.clone(),
+                            };
+                            connector.stream = match VeNCryptAuth::authenticate(
                                 plain_stream,
-                                "localhost",
+                                &tls,
                                 Some(username.as_ref()),
                                 Some(&password),
+                                connector.config.vencrypt_subtype_selector.as_ref(),
                             )
-                            .await?;
+                            .await
+                            {
+                                Ok((stream, offered)) => {
+                                    connector.config.vencrypt_offered = offered;
+                                    stream
+                                }
+                                Err(e)
+                                    if connector.config.security_fallback
+                                        && security_types.contains(&SecurityType::VncAuth) =>
+                                {
+                                    error!("VeNCrypt failed with VncAuth still offered: {}", e);
+                                    return Err(VncError::SecurityFallback(
+                                        SecurityType::VeNCrypt,
+                                        Box::new(e),
+                                    ));
+                                }
+                                Err(e) => return Err(e),
+                            };
 
                             // Read SecurityResult after VeNCrypt auth
                             let result = match &mut connector.stream {
-                                VncStream::Plain(stream) => stream.read_u32().await?,
-                                VncStream::Tls(stream) => stream.read_u32().await?,
+                                VncStream::Plain(stream) => {
+                                    AuthResult::read(stream, lenient).await?
+                                }
+                                VncStream::Tls(stream) => AuthResult::read(stream, lenient).await?,
                             };
-                            let auth_result: AuthResult = result.into();
-                            if let AuthResult::Failed = auth_result {
+                            if let AuthResult::Failed = result {
                                 match &mut connector.stream {
                                     VncStream::Plain(stream) => {
-                                        let _ = stream.read_u32().await?;
-                                        let mut err_msg = String::new();
-                                        stream.read_to_string(&mut err_msg).await?;
+                                        let err_msg = read_reason(stream).await?;
                                         return Err(VncError::General(err_msg));
                                     }
                                     VncStream::Tls(stream) => {
-                                        let _ = stream.read_u32().await?;
-                                        let mut err_msg = String::new();
-                                        stream.read_to_string(&mut err_msg).await?;
+                                        let err_msg = read_reason(stream).await?;
                                         return Err(VncError::General(err_msg));
                                     }
                                 };
                             }
+                        } else if cfg!(feature = "ard")
+                            && security_types.contains(&SecurityType::AppleRemoteDesktop)
+                            && !connector
+                                .config
+                                .excluded_security
+                                .contains(&SecurityType::AppleRemoteDesktop)
+                            // ARD needs a username, without one plain VncAuth may still do
+                            && (connector.config.credentials.username.is_some()
+                                || !security_types.contains(&SecurityType::VncAuth))
+                        {
+                            #[cfg(feature = "ard")]
+                            {
+                                let Some(password) = &connector.config.credentials.password else {
+                                    return Err(VncError::MisingPassword);
+                                };
+                                let Some(username) = &connector.config.credentials.username else {
+                                    return Err(VncError::General(
+                                        "Username required for Apple Remote Desktop authentication"
+                                            .to_string(),
+                                    ));
+                                };
+                                match &mut connector.stream {
+                                    VncStream::Plain(stream) => {
+                                        SecurityType::write(
+                                            &SecurityType::AppleRemoteDesktop,
+                                            stream,
+                                        )
+                                        .await?;
+                                        ard::authenticate(stream, username, password, lenient)
+                                            .await?
+                                    }
+                                    VncStream::Tls(stream) => {
+                                        SecurityType::write(
+                                            &SecurityType::AppleRemoteDesktop,
+                                            stream,
+                                        )
+                                        .await?;
+                                        ard::authenticate(stream, username, password, lenient)
+                                            .await?
+                                    }
+                                };
+                            }
+                            apple_auth = true;
                         } else if security_types.contains(&SecurityType::VncAuth) {
-                            if connector.rfb_version != VncVersion::RFB33 {
+                            if connector.config.rfb_version != VncVersion::RFB33 {
                                 match &mut connector.stream {
                                     VncStream::Plain(stream) => {
                                         SecurityType::write(&SecurityType::VncAuth, stream).await?
@@ -167,7 +300,7 @@ where
                                 };
                             }
 
-                            let Some(password) = &connector.credentials.password else {
+                            let Some(password) = &connector.config.credentials.password else {
                                 return Err(VncError::MisingPassword);
                             };
 
@@ -176,57 +309,160 @@ where
                                 VncStream::Plain(stream) => {
                                     let auth = AuthHelper::read(stream, password).await?;
                                     auth.write(stream).await?;
-                                    let result = auth.finish(stream).await?;
+                                    let result = auth.finish(stream, lenient).await?;
                                     if let AuthResult::Failed = result {
-                                        if let VncVersion::RFB37 = connector.rfb_version {
-                                            return Err(VncError::WrongPassword);
-                                        } else {
-                                            let _ = stream.read_u32().await?;
-                                            let mut err_msg = String::new();
-                                            stream.read_to_string(&mut err_msg).await?;
-                                            return Err(VncError::General(err_msg));
-                                        }
+                                        // only 3.8 servers tell us the reason
+                                        let reason = match connector.config.rfb_version {
+                                            VncVersion::RFB38 => Some(read_reason(stream).await?),
+                                            _ => None,
+                                        };
+                                        error!("VncAuth failed: {:?}", reason);
+                                        return Err(VncError::WrongPassword(reason));
                                     }
                                 }
                                 VncStream::Tls(stream) => {
                                     let auth = AuthHelper::read(stream, password).await?;
                                     auth.write(stream).await?;
-                                    let result = auth.finish(stream).await?;
+                                    let result = auth.finish(stream, lenient).await?;
                                     if let AuthResult::Failed = result {
-                                        if let VncVersion::RFB37 = connector.rfb_version {
-                                            return Err(VncError::WrongPassword);
-                                        } else {
-                                            let _ = stream.read_u32().await?;
-                                            let mut err_msg = String::new();
-                                            stream.read_to_string(&mut err_msg).await?;
-                                            return Err(VncError::General(err_msg));
-                                        }
+                                        // only 3.8 servers tell us the reason
+                                        let reason = match connector.config.rfb_version {
+                                            VncVersion::RFB38 => Some(read_reason(stream).await?),
+                                            _ => None,
+                                        };
+                                        error!("VncAuth failed: {:?}", reason);
+                                        return Err(VncError::WrongPassword(reason));
                                     }
                                 }
                             };
+                        } else if security_types.contains(&SecurityType::Tight)
+                            && !connector
+                                .config
+                                .excluded_security
+                                .contains(&SecurityType::Tight)
+                        {
+                            let password = connector.config.credentials.password.as_deref();
+                            let version = connector.config.rfb_version;
+                            match &mut connector.stream {
+                                VncStream::Plain(stream) => {
+                                    SecurityType::write(&SecurityType::Tight, stream).await?;
+                                    tight::authenticate(stream, password, version, lenient).await?
+                                }
+                                VncStream::Tls(stream) => {
+                                    SecurityType::write(&SecurityType::Tight, stream).await?;
+                                    tight::authenticate(stream, password, version, lenient).await?
+                                }
+                            };
+                            connector.config.tight_security = true;
                         } else {
-                            return Err(VncError::General(format!(
-                                "Security types {:?} not supported",
-                                security_types
-                            )));
+                            return Err(VncError::NoSupportedSecurityType(security_types));
                         }
                     }
                     info!("Auth done, client connected");
 
+                    if let VncStream::Plain(_) = connector.stream {
+                        let reason = if security_types.contains(&SecurityType::None) {
+                            "No authentication and no encryption"
+                        } else if connector.config.tight_security {
+                            "Tight security doesn't encrypt the session"
+                        } else if apple_auth {
+                            "Apple Remote Desktop only protects the credentials, the session is not encrypted"
+                        } else if connector
+                            .config
+                            .excluded_security
+                            .contains(&SecurityType::VeNCrypt)
+                            || !security_types.contains(&SecurityType::VeNCrypt)
+                        {
+                            "VncAuth only protects the password, the session is not encrypted"
+                        } else {
+                            "VeNCrypt without TLS, the credentials and the session are not encrypted"
+                        };
+                        connector.config.security_warning = Some(reason.to_string());
+                    }
+
                     return Ok(VncState::Connected(
-                        VncClient::new(
-                            connector.stream,
-                            connector.allow_shared,
-                            connector.pixel_format,
-                            connector.encodings,
-                        )
-                        .await?,
+                        VncClient::new(connector.stream, connector.config).await?,
                     ));
                 }
             };
         }
     }
 
+    /// Same as [VncState::try_start], but go through every password of the [Credentials]
+    ///
+    /// VncAuth servers drop the connection once a password is rejected,
+    ///
+    /// so `reconnect` is called for a fresh stream before trying the next one
+    ///
+    /// The same goes for VeNCrypt with [VncConnector::allow_security_fallback]
+    ///
+    /// It stops at the first rejection saying there were too many failures, which the next try would only prolong
+    ///
+    /// ```no_run
+    /// use vnc::{Credentials, VncConnector, VncEncoding, VncError};
+    /// use tokio::{self, net::TcpStream};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), VncError> {
+    ///     let addr = "127.0.0.1:5900";
+    ///     let credentials = Credentials::new(None, Some("password".to_string()))
+    ///         .add_fallback_password("secret".to_string());
+    ///     let vnc = VncConnector::new(TcpStream::connect(addr).await?)
+    ///         .set_credentials(credentials)
+    ///         .add_encoding(VncEncoding::Raw)
+    ///         .build()?
+    ///         .try_start_with_fallback(|| async { Ok(TcpStream::connect(addr).await?) })
+    ///         .await?
+    ///         .finish()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    pub async fn try_start_with_fallback<F, Fut>(self, mut reconnect: F) -> Result<Self, VncError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<S, VncError>>,
+    {
+        let mut connector = match self {
+            VncState::Handshake(connector) => connector,
+            _ => return self.try_start().await,
+        };
+        let mut fallbacks =
+            std::mem::take(&mut connector.config.credentials.fallback_passwords).into_iter();
+        loop {
+            let config = connector.config.clone();
+            match VncState::Handshake(connector).try_start().await {
+                Err(VncError::SecurityFallback(security_type, e)) => {
+                    info!(
+                        "{:?} failed ({}), connect again without it",
+                        security_type, e
+                    );
+                    connector = VncConnector {
+                        stream: VncStream::Plain(reconnect().await?),
+                        config,
+                    };
+                    connector.config.excluded_security.push(security_type);
+                }
+                Err(VncError::WrongPassword(Some(reason))) if blacklisted(&reason) => {
+                    // another attempt would only prolong the ban
+                    return Err(VncError::WrongPassword(Some(reason)));
+                }
+                Err(VncError::WrongPassword(reason)) => {
+                    let Some(password) = fallbacks.next() else {
+                        return Err(VncError::WrongPassword(reason));
+                    };
+                    info!("Password rejected, try the next one");
+                    connector = VncConnector {
+                        stream: VncStream::Plain(reconnect().await?),
+                        config,
+                    };
+                    connector.config.credentials.password = Some(password);
+                }
+                result => return result,
+            }
+        }
+    }
+
     pub fn finish(self) -> Result<VncClient, VncError> {
         match self {
             VncState::Connected(client) => Ok(client),
@@ -235,17 +471,65 @@ where
     }
 }
 
+/// Whether a rejection `reason` tells that the server stopped taking passwords from us for a while
+///
+/// Such as "Too many authentication failures" from RealVNC and TigerVNC's "Too many security failures"
+fn blacklisted(reason: &str) -> bool {
+    let reason = reason.to_ascii_lowercase();
+    reason.contains("too many") || reason.contains("blacklist")
+}
+
 /// Connection Builder to setup a vnc client
 pub struct VncConnector<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     stream: VncStream<S>,
-    credentials: crate::client::auth::Credentials,
-    rfb_version: VncVersion,
-    allow_shared: bool,
-    pixel_format: Option<PixelFormat>,
-    encodings: Vec<VncEncoding>,
+    pub(super) config: ConnectorConfig,
+}
+
+/// Everything the connector knows besides the stream itself
+///
+/// Kept apart so that a fresh stream can be set up the same way
+#[derive(Clone)]
+pub(super) struct ConnectorConfig {
+    pub(super) credentials: Credentials,
+    pub(super) rfb_version: VncVersion,
+    pub(super) version_string: Option<[u8; 12]>,
+    pub(super) allow_shared: bool,
+    pub(super) manual_updates: bool,
+    pub(super) keep_encoded: bool,
+    pub(super) cursor_only: bool,
+    pub(super) composite_frames: bool,
+    pub(super) framebuffer_capacity: Option<(u16, u16)>,
+    pub(super) full_redraws: bool,
+    pub(super) zrlee: bool,
+    pub(super) detect_endianness: bool,
+    pub(super) normalize_newlines: bool,
+    pub(super) auto_continuous_updates: bool,
+    pub(super) lenient_security_result: Option<Duration>,
+    pub(super) security_fallback: bool,
+    /// the security types that failed on an earlier connection
+    pub(super) excluded_security: Vec<SecurityType>,
+    /// why the negotiated transport is insecure, found during the handshake
+    pub(super) security_warning: Option<String>,
+    /// the VeNCrypt subtypes the server offered, found during the handshake
+    pub(super) vencrypt_offered: Vec<VeNCryptSubtype>,
+    /// the Tight security type was used, which adds capabilities to the ServerInit
+    pub(super) tight_security: bool,
+    pub(super) extensions: HashMap<u8, usize>,
+    pub(super) pixel_format: Option<PixelFormat>,
+    pub(super) always_send_pixel_format: bool,
+    pub(super) encodings: Vec<VncEncoding>,
+    pub(super) read_timeout: Option<Duration>,
+    pub(super) update_interval: Option<Duration>,
+    pub(super) read_rate_limit: Option<u64>,
+    pub(super) max_name_len: usize,
+    pub(super) vencrypt_subtype_selector: Option<VeNCryptSubtypeSelector>,
+    pub(super) tls_verification: TlsVerification,
+    /// PEM
+    pub(super) tls_roots: Vec<u8>,
+    pub(super) tls_server_name: Option<String>,
 }
 
 impl<S> VncConnector<S>
@@ -282,17 +566,46 @@ where
     pub fn new(stream: S) -> Self {
         Self {
             stream: VncStream::Plain(stream),
-            credentials: Credentials::default(),
-            allow_shared: true,
-            rfb_version: VncVersion::RFB38,
-            pixel_format: None,
-            encodings: Vec::new(),
+            config: ConnectorConfig {
+                credentials: Credentials::default(),
+                allow_shared: true,
+                manual_updates: false,
+                keep_encoded: false,
+                cursor_only: false,
+                composite_frames: false,
+                framebuffer_capacity: None,
+                full_redraws: false,
+                zrlee: false,
+                detect_endianness: false,
+                normalize_newlines: false,
+                auto_continuous_updates: false,
+                lenient_security_result: None,
+                security_fallback: false,
+                excluded_security: Vec::new(),
+                security_warning: None,
+                vencrypt_offered: Vec::new(),
+                tight_security: false,
+                extensions: HashMap::new(),
+                rfb_version: VncVersion::RFB38,
+                version_string: None,
+                pixel_format: None,
+                always_send_pixel_format: false,
+                encodings: Vec::new(),
+                read_timeout: None,
+                update_interval: None,
+                read_rate_limit: None,
+                max_name_len: DEFAULT_MAX_NAME_LEN,
+                vencrypt_subtype_selector: None,
+                tls_verification: TlsVerification::default(),
+                tls_roots: Vec::new(),
+                tls_server_name: None,
+            },
         }
     }
 
     /// Set credentials for VNC authentication
     pub fn set_credentials(mut self, credentials: Credentials) -> Self {
-        self.credentials = credentials;
+        self.config.credentials = credentials;
         self
     }
 
@@ -301,7 +614,23 @@ where
     /// Version should be one of the [VncVersion]
     ///
     pub fn set_version(mut self, version: VncVersion) -> Self {
-        self.rfb_version = version;
+        self.config.rfb_version = version;
+        self
+    }
+
+    /// Send exactly `banner` as our ProtocolVersion message
+    ///
+    /// Some servers only enable extensions for the clients they know,
+    ///
+    /// this allows to introduce ourselves as one of them
+    ///
+    /// The handshake still follows the [VncVersion] that `banner` maps to,
+    ///
+    /// and `banner` must look like `b"RFB 003.008\n"`, which is checked by [VncConnector::build]
+    ///
+    pub fn set_version_string(mut self, banner: [u8; 12]) -> Self {
+        self.config.rfb_version = banner.into();
+        self.config.version_string = Some(banner);
         self
     }
 
@@ -318,7 +647,20 @@ where
     /// In this condition, the client will get a [crate::VncEvent::SetPixelFormat] event notified
     ///
     pub fn set_pixel_format(mut self, pf: PixelFormat) -> Self {
-        self.pixel_format = Some(pf);
+        self.config.pixel_format = Some(pf);
+        self
+    }
+
+    /// Send SetPixelFormat right after the ServerInit even if no format is set
+    ///
+    /// The server's own format is then sent back to it,
+    ///
+    /// which some servers (e.g. a few QEMU builds) need to set up their conversion predictably
+    ///
+    /// Disabled by default
+    ///
+    pub fn always_send_pixel_format(mut self, always: bool) -> Self {
+        self.config.always_send_pixel_format = always;
         self
     }
 
@@ -331,7 +673,141 @@ where
     /// other clients.
     ///
     pub fn allow_shared(mut self, allow_shared: bool) -> Self {
-        self.allow_shared = allow_shared;
+        self.config.allow_shared = allow_shared;
+        self
+    }
+
+    /// Never send a FramebufferUpdateRequest on our own, not even for the first frame
+    ///
+    /// Updates then arrive only after [VncClient::request_update] (or [crate::X11Event::Refresh])
+    ///
+    /// which gives the frontend full control over the update timing
+    ///
+    pub fn manual_updates(mut self, manual: bool) -> Self {
+        self.config.manual_updates = manual;
+        self
+    }
+
+    /// Also report the undecoded payload of every rectangle
+    ///
+    /// A [crate::VncEvent::EncodedRectangle] follows the decoded events of each rectangle,
+    ///
+    /// which e.g. lets a proxy forward the original bytes instead of encoding them again
+    ///
+    pub fn keep_encoded_rectangles(mut self, keep: bool) -> Self {
+        self.config.keep_encoded = keep;
+        self
+    }
+
+    /// Report a [crate::VncEvent::FullRedraw] after every update that repaints the whole screen
+    ///
+    /// e.g. the non-incremental updates some servers push after a mode switch
+    ///
+    pub fn report_full_redraws(mut self, report: bool) -> Self {
+        self.config.full_redraws = report;
+        self
+    }
+
+    /// Decode [VncEncoding::Zrle] as ZRLEE, where every rectangle is a zlib stream of its own
+    ///
+    /// ZRLE keeps a single stream for the whole session, some servers (e.g. UltraVNC)
+    ///
+    /// start a new one with each rectangle instead, which breaks from the second rectangle on
+    ///
+    /// Disabled by default
+    ///
+    pub fn zrle_stream_per_rect(mut self, per_rect: bool) -> Self {
+        self.config.zrlee = per_rect;
+        self
+    }
+
+    /// Keep a framebuffer inside the client and report one [crate::VncEvent::Frame] per update
+    ///
+    /// except for the empty ones, which some servers send as keepalives
+    ///
+    /// The rectangles of an update are painted into it as they are decoded,
+    ///
+    /// so [crate::VncEvent::RawImage] and [crate::VncEvent::Copy] are no longer reported
+    ///
+    /// [crate::VncEvent::JpegImage] still is, as jpeg data is left to the frontend to decode
+    ///
+    pub fn composite_frames(mut self, composite: bool) -> Self {
+        self.config.composite_frames = composite;
+        self
+    }
+
+    /// Allocate the framebuffer of [VncConnector::composite_frames] for `max_width` x `max_height` up front
+    ///
+    /// Resizes up to that size then reuse the same memory, only a larger desktop reallocates
+    ///
+    /// A [crate::VncEvent::Frame] still held by the frontend keeps its pixels to itself,
+    ///
+    /// so the memory is only reused once the frames are dropped
+    ///
+    pub fn preallocate_framebuffer(mut self, max_width: u16, max_height: u16) -> Self {
+        self.config.framebuffer_capacity = Some((max_width, max_height));
+        self
+    }
+
+    /// Only follow the cursor, for tools that never show the framebuffer
+    ///
+    /// The encodings added are replaced by [VncEncoding::Raw], [VncEncoding::CursorPseudo]
+    ///
+    /// and [VncEncoding::PointerPosPseudo], and update requests cover a single pixel
+    ///
+    /// The pixels that still come along are skipped instead of decoded,
+    ///
+    /// only [crate::VncEvent::SetCursor] and [crate::VncEvent::CursorPosition] are reported
+    ///
+    /// As for any other update, the server only sends cursor changes after a request,
+    ///
+    /// so keep sending [crate::X11Event::Refresh]
+    ///
+    pub fn cursor_only(mut self) -> Self {
+        self.config.cursor_only = true;
+        self
+    }
+
+    /// Work around servers that send 32bpp pixels in the opposite byte order to the pixel format
+    ///
+    /// The first update covering the whole screen is checked: the byte left out of the colours
+    ///
+    /// should keep one value, if it doesn't but its mirror byte does, the pixels are taken as swapped
+    ///
+    /// From then on every [crate::VncEvent::RawImage] is swapped back and the whole screen is requested again
+    ///
+    /// A heuristic, so only turn it on for a server known to have the bug; formats without a free byte aren't checked
+    ///
+    pub fn detect_endianness(mut self) -> Self {
+        self.config.detect_endianness = true;
+        self
+    }
+
+    /// Convert the line endings of clipboard text, off by default so the text is kept as is
+    ///
+    /// When on, CRLF becomes LF in [crate::VncEvent::Text], and LF becomes CRLF in [crate::X11Event::CopyText],
+    ///
+    /// which smooths over sharing the clipboard between Windows servers and Unix clients
+    ///
+    /// Only the legacy cut text is affected, the extended clipboard always uses CRLF on the wire and LF here;
+    ///
+    /// binary clipboard formats are never touched
+    ///
+    pub fn normalize_clipboard_newlines(mut self, normalize: bool) -> Self {
+        self.config.normalize_newlines = normalize;
+        self
+    }
+
+    /// Accept the server message `msg_type` with a body of `length` bytes
+    ///
+    /// Such messages are reported as [crate::VncEvent::ExtensionMessage] instead of
+    ///
+    /// closing the connection with [VncError::UnknownMessage]
+    ///
+    /// The messages defined by the RFC can't be overridden
+    ///
+    pub fn register_server_message(mut self, msg_type: u8, length: usize) -> Self {
+        self.config.extensions.insert(msg_type, length);
         self
     }
 
@@ -344,16 +820,908 @@ where
     /// The order to add encodings is the order to inform the server
     ///
     pub fn add_encoding(mut self, encoding: VncEncoding) -> Self {
-        self.encodings.push(encoding);
+        self.config.encodings.push(encoding);
+        self
+    }
+
+    /// Let the server send a quick JPEG first and refresh the same area losslessly later on
+    ///
+    /// Advertises the Tight JPEG quality / compression levels and their TurboVNC
+    ///
+    /// fine-grained variants, with which servers enable this two-pass mode
+    ///
+    /// Both passes arrive as regular updates, [VncEncoding::Tight] must be added as well
+    ///
+    pub fn enable_lossy_refresh(self) -> Self {
+        self.set_quality_preset(QualityPreset::Balanced)
+    }
+
+    /// Inform the pseudo-encodings of `preset` instead of picking the numeric levels by hand
+    ///
+    /// They only tune [VncEncoding::Tight] (and the compression level [VncEncoding::Zrle] on some servers),
+    ///
+    /// which must be added as well
+    ///
+    /// Any level informed before, by hand or by an earlier preset, is replaced
+    ///
+    pub fn set_quality_preset(mut self, preset: QualityPreset) -> Self {
+        self.config
+            .encodings
+            .retain(|&encoding| !QualityPreset::is_level(encoding));
+        self.config.encodings.extend_from_slice(preset.encodings());
+        self
+    }
+
+    /// Tune the session for text, e.g. a remote terminal, where latency matters more than the image
+    ///
+    /// Replaces the encodings added so far by lossless [VncEncoding::Tight] at the highest compression,
+    ///
+    /// without any JPEG quality level so the server never sends JPEG, then [VncEncoding::CopyRect]
+    ///
+    /// and [VncEncoding::Raw], along with the cursor, resizing, fence and continuous updates pseudo-encodings
+    ///
+    /// Continuous updates are turned on as soon as the server announces them,
+    ///
+    /// keep sending [crate::X11Event::Refresh] until then, servers without them ignore the pseudo-encoding
+    ///
+    pub fn optimize_for_text(mut self) -> Self {
+        self.config.encodings = vec![
+            VncEncoding::Tight,
+            VncEncoding::CopyRect,
+            VncEncoding::Raw,
+            VncEncoding::CompressLevel9Pseudo,
+            VncEncoding::CursorPseudo,
+            VncEncoding::DesktopSizePseudo,
+            VncEncoding::LastRectPseudo,
+            VncEncoding::FencePseudo,
+            VncEncoding::ContinuousUpdatesPseudo,
+        ];
+        self.config.auto_continuous_updates = true;
+        self
+    }
+
+    /// Refuse desktop names longer than `max_len` bytes, [DEFAULT_MAX_NAME_LEN] by default
+    ///
+    /// Applies to the ServerInit message and to [VncEncoding::DesktopNamePseudo],
+    ///
+    /// a longer one closes the connection with [VncError::OversizedMessage] instead of being allocated
+    ///
+    pub fn set_max_name_length(mut self, max_len: usize) -> Self {
+        self.config.max_name_len = max_len;
+        self
+    }
+
+    /// Give up if a single read stalls for longer than `timeout` in the middle of a server message
+    ///
+    /// Waiting for the next message is never timed, as an idle server sends nothing,
+    ///
+    /// but the handshake in [VncState::try_start] has to finish within `timeout` as a whole
+    ///
+    /// Large rectangles over slow links take a while, so be generous
+    ///
+    /// The connection is then closed with a [VncError::ReadTimeout] reported as [crate::VncEvent::Error]
+    ///
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Read no more than about `bytes_per_sec` from the server once connected
+    ///
+    /// The socket is simply read less often, which slows the server down through TCP flow control
+    ///
+    /// A single read may take up to 64KiB at once, so the limit holds on average rather than per instant
+    ///
+    pub fn set_read_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.config.read_rate_limit = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Repeat the last update request as an incremental one once `interval` passed without any
+    ///
+    /// Keeps idle connections alive through NAT gateways and servers that stop pushing otherwise
+    ///
+    /// Every [crate::X11Event::Refresh] restarts the wait, so explicit requests are never doubled up
+    ///
+    /// Nothing is sent before the first request; `None`, the default, disables it
+    ///
+    pub fn set_update_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.update_interval = interval;
+        self
+    }
+
+    /// Decide which of the VeNCrypt subtypes offered by the server to use
+    ///
+    /// Returning `None`, or a subtype the server didn't offer, aborts the connection
+    ///
+    /// Without a selector the TLS secured subtypes with a password are preferred
+    ///
+    /// ```no_run
+    /// use vnc::protocol::security::vencrypt::VeNCryptSubtype;
+    /// # fn configure(connector: vnc::VncConnector<tokio::net::TcpStream>) {
+    /// // never send the password without a certificate-secured transport
+    /// let connector = connector.set_vencrypt_subtype_selector(|offered| {
+    ///     offered.iter().copied().find(|s| *s == VeNCryptSubtype::X509Plain)
+    /// });
+    /// # }
+    /// ```
+    ///
+    pub fn set_vencrypt_subtype_selector<F>(mut self, selector: F) -> Self
+    where
+        F: Fn(&[VeNCryptSubtype]) -> Option<VeNCryptSubtype> + Send + Sync + 'static,
+    {
+        self.config.vencrypt_subtype_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Choose how the certificate of a VeNCrypt server is checked
+    ///
+    /// Only the X509 subtypes have one, the Tls ones are anonymous and taken as they are with a warning
+    ///
+    /// [TlsVerification::Strict] by default, which needs [VncConnector::set_tls_root_certificates]
+    ///
+    /// and the name the certificate was issued for from [VncConnector::set_tls_server_name]
+    ///
+    /// [TlsVerification::AcceptAll] takes any certificate, which is only safe on trusted networks
+    ///
+    pub fn set_tls_verification_mode(mut self, verification: TlsVerification) -> Self {
+        self.config.tls_verification = verification;
+        self
+    }
+
+    /// Trust the root certificates in `pem`, e.g. the bundle of a private CA
+    ///
+    /// Read a file with [std::fs::read] first, the certificates are parsed during the handshake
+    ///
+    pub fn set_tls_root_certificates(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.config.tls_roots = pem.into();
+        self
+    }
+
+    /// The host name sent with the TLS handshake (SNI), which the certificate must be issued for
+    ///
+    /// Use the name the server was reached by, e.g. `"vnc.internal.example.com"`,
+    ///
+    /// the stream doesn't tell it
+    ///
+    /// Required by [TlsVerification::Strict] for the X509 subtypes, otherwise `"localhost"` is sent if it isn't set
+    ///
+    pub fn set_tls_server_name(mut self, name: String) -> Self {
+        self.config.tls_server_name = Some(name);
+        self
+    }
+
+    /// Give up on VeNCrypt if its TLS setup fails while the server also offers VncAuth
+    ///
+    /// The failed connection can't be used anymore, so this takes effect with
+    ///
+    /// [VncState::try_start_with_fallback], which connects again and goes with VncAuth
+    ///
+    /// [VncState::try_start] just reports such failures as [VncError::SecurityFallback]
+    ///
+    /// Note that VncAuth sends the password challenge without any encryption
+    ///
+    pub fn allow_security_fallback(mut self, allow: bool) -> Self {
+        self.config.security_fallback = allow;
+        self
+    }
+
+    /// Also accept a single byte SecurityResult instead of the U32 required by the RFC
+    ///
+    /// Some embedded servers get this wrong, which otherwise desyncs the connection
+    ///
+    /// A zero byte is then taken as success if nothing follows it within `wait`,
+    ///
+    /// as such a server waits for the ClientInit while a U32 always goes on right away
+    ///
+    /// Every successful handshake takes `wait` longer, so only enable this for such servers
+    ///
+    /// and keep `wait` short, 500ms is plenty unless the link itself is that slow
+    ///
+    pub fn lenient_security_result(mut self, wait: Duration) -> Self {
+        self.config.lenient_security_result = Some(wait);
         self
     }
 
     /// Complete the client configuration
     ///
-    pub fn build(self) -> Result<VncState<S>, VncError> {
-        if self.encodings.is_empty() {
+    pub fn build(mut self) -> Result<VncState<S>, VncError> {
+        if self.config.cursor_only {
+            self.config.encodings = vec![
+                VncEncoding::Raw,
+                VncEncoding::CursorPseudo,
+                VncEncoding::PointerPosPseudo,
+            ];
+        }
+        if self.config.encodings.is_empty() {
             return Err(VncError::NoEncoding);
         }
+        if let Some(banner) = &self.config.version_string {
+            if !VncVersion::is_valid_banner(banner) {
+                return Err(VncError::General(format!(
+                    "Invalid RFB version string {:?}",
+                    String::from_utf8_lossy(banner)
+                )));
+            }
+        }
         Ok(VncState::Handshake(self))
     }
 }
+
+impl VncConnector<DuplexStream> {
+    /// A connector over an in-memory pipe, along with the server end of it
+    ///
+    /// Handy to test against a scripted server without any socket,
+    ///
+    /// `max_buf_size` is the most bytes either end buffers, see [tokio::io::duplex]
+    ///
+    /// ```
+    /// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    /// use vnc::{VncConnector, VncEncoding, VncError};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), VncError> {
+    ///     let (connector, mut server) = VncConnector::new_duplex(4096);
+    ///     let state = connector.add_encoding(VncEncoding::Raw).build()?;
+    ///     let client = tokio::spawn(state.try_start());
+    ///
+    ///     server.write_all(b"RFB 003.008\n").await?;
+    ///     let mut version = [0; 12];
+    ///     server.read_exact(&mut version).await?;
+    ///     assert_eq!(&version, b"RFB 003.008\n");
+    ///     // ... play the rest of the server side
+    ///     # drop(server);
+    ///     # let _ = client.await;
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    pub fn new_duplex(max_buf_size: usize) -> (Self, DuplexStream) {
+        let (client, server) = tokio::io::duplex(max_buf_size);
+        (Self::new(client), server)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl VncConnector<tokio::net::TcpStream> {
+    /// Connect over TCP, trying up to `attempts` times with `delay` in between
+    ///
+    /// Only the TCP connect is retried, which covers a server that isn't listening yet,
+    ///
+    /// such as a VM still booting when its console is opened. The last error is returned if all attempts fail
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use vnc::{PixelFormat, VncConnector, VncEncoding, VncError};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), VncError> {
+    ///     let vnc = VncConnector::connect_with_tcp_retry("127.0.0.1:5900", 30, Duration::from_secs(1))
+    ///         .await?
+    ///         .add_encoding(VncEncoding::Raw)
+    ///         .set_pixel_format(PixelFormat::bgra())
+    ///         .build()?
+    ///         .try_start()
+    ///         .await?
+    ///         .finish()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    pub async fn connect_with_tcp_retry<A>(
+        addr: A,
+        attempts: usize,
+        delay: Duration,
+    ) -> Result<Self, VncError>
+    where
+        A: tokio::net::ToSocketAddrs + Clone,
+    {
+        let mut attempt = 1;
+        loop {
+            match tokio::net::TcpStream::connect(addr.clone()).await {
+                Ok(stream) => return Ok(Self::new(stream)),
+                Err(e) if attempt < attempts => {
+                    info!("TCP connect attempt {} failed: {}", attempt, e);
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::auth::AuthHelper;
+    use crate::VncEvent;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    #[tokio::test]
+    async fn silent_server_times_out() {
+        // the server end is kept open, but never says a word
+        let (connector, _server) = VncConnector::new_duplex(4096);
+        let started = std::time::Instant::now();
+        let result = connector
+            .add_encoding(VncEncoding::Raw)
+            .set_read_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .try_start()
+            .await;
+        assert!(matches!(result, Err(VncError::ReadTimeout)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn handshake_without_auth() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        let mut version = [0; 12];
+        server.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+
+        // only SecurityType::None
+        server.write_all(&[1, 1]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_u32(0).await.unwrap();
+
+        // ClientInit, shared by default
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 3]).await.unwrap();
+        server.write_all(b"vnc").await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SecurityWarning { .. }
+        ));
+        match vnc.recv_event().await.unwrap() {
+            VncEvent::SetResolution(screen) => assert_eq!((screen.width, screen.height), (8, 6)),
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert!(!vnc.is_closed());
+
+        // noticed without waiting for an event
+        drop(server);
+        while !vnc.is_closed() {
+            tokio::task::yield_now().await;
+        }
+        vnc.close().await.unwrap();
+    }
+
+    #[test]
+    fn malformed_version_string_rejected() {
+        let (connector, _server) = VncConnector::new_duplex(4096);
+        let result = connector
+            .add_encoding(VncEncoding::Raw)
+            .set_version_string(*b"RFB 3.8\n    ")
+            .build();
+        assert!(matches!(result, Err(VncError::General(e)) if e.contains("RFB 3.8")));
+    }
+
+    #[tokio::test]
+    async fn version_string_sent_unless_downgraded() {
+        for (server_version, expected) in [
+            (b"RFB 003.008\n", b"RFB 003.889\n"),
+            // the banner would claim 3.8, which this server doesn't speak
+            (b"RFB 003.003\n", b"RFB 003.003\n"),
+        ] {
+            let (connector, mut server) = VncConnector::new_duplex(4096);
+            let state = connector
+                .add_encoding(VncEncoding::Raw)
+                .set_version_string(*b"RFB 003.889\n")
+                .build()
+                .unwrap();
+            let client = tokio::spawn(state.try_start());
+
+            server.write_all(server_version).await.unwrap();
+            let mut version = [0; 12];
+            server.read_exact(&mut version).await.unwrap();
+            assert_eq!(&version, expected);
+            drop(server);
+            assert!(client.await.unwrap().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_vnc_auth() {
+        let (connector, mut first) = VncConnector::new_duplex(4096);
+        let (second_client, mut second) = tokio::io::duplex(4096);
+        let mut second_client = Some(second_client);
+        let state = connector
+            .set_credentials(Credentials::new(None, Some("secret".to_string())))
+            .allow_security_fallback(true)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start_with_fallback(move || {
+            let stream = second_client.take().unwrap();
+            async move { Ok(stream) }
+        }));
+
+        let mut version = [0; 12];
+        // VeNCrypt is preferred, but a version nobody speaks makes it fail
+        first.write_all(b"RFB 003.008\n").await.unwrap();
+        first.read_exact(&mut version).await.unwrap();
+        first.write_all(&[2, 19, 2]).await.unwrap();
+        assert_eq!(first.read_u8().await.unwrap(), 19);
+        first.write_all(&[0, 1]).await.unwrap();
+
+        // then VncAuth on the new connection
+        second.write_all(b"RFB 003.008\n").await.unwrap();
+        second.read_exact(&mut version).await.unwrap();
+        second.write_all(&[2, 19, 2]).await.unwrap();
+        assert_eq!(second.read_u8().await.unwrap(), 2);
+        second.write_all(&[0; 16]).await.unwrap();
+        let mut response = [0; 16];
+        second.read_exact(&mut response).await.unwrap();
+        second.write_u32(0).await.unwrap();
+
+        assert_eq!(second.read_u8().await.unwrap(), 1);
+        second.write_all(&[0, 8, 0, 6]).await.unwrap();
+        second
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        second.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        vnc.close().await.unwrap();
+    }
+
+    /// The answer to an all zero VncAuth challenge with `password`
+    async fn zero_challenge_response(password: &str) -> Vec<u8> {
+        let mut response = vec![];
+        let auth = AuthHelper::read(&mut &[0; 16][..], password).await.unwrap();
+        auth.write(&mut response).await.unwrap();
+        response
+    }
+
+    /// Offer VncAuth alone on `server`, expect the response of `password` and answer with `result`
+    async fn vnc_auth_server(server: &mut DuplexStream, password: &str, result: u32) {
+        let mut version = [0; 12];
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.read_exact(&mut version).await.unwrap();
+        server.write_all(&[1, 2]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 2);
+        server.write_all(&[0; 16]).await.unwrap();
+        let mut response = [0; 16];
+        server.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..], zero_challenge_response(password).await);
+        server.write_u32(result).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_password() {
+        let (connector, mut first) = VncConnector::new_duplex(4096);
+        let (second_client, mut second) = tokio::io::duplex(4096);
+        let mut second_client = Some(second_client);
+        let state = connector
+            .set_credentials(
+                Credentials::new(None, Some("wrong".to_string()))
+                    .add_fallback_password("secret".to_string()),
+            )
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start_with_fallback(move || {
+            let stream = second_client.take().unwrap();
+            async move { Ok(stream) }
+        }));
+
+        vnc_auth_server(&mut first, "wrong", 1).await;
+        first.write_u32(21).await.unwrap();
+        first.write_all(b"Authentication failed").await.unwrap();
+
+        vnc_auth_server(&mut second, "secret", 0).await;
+        assert_eq!(second.read_u8().await.unwrap(), 1);
+        second.write_all(&[0, 8, 0, 6]).await.unwrap();
+        second
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        second.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        vnc.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fallback_stops_when_blacklisted() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(
+                Credentials::new(None, Some("wrong".to_string()))
+                    .add_fallback_password("secret".to_string()),
+            )
+            .add_encoding(VncEncoding::Raw)
+            .build()
+            .unwrap();
+        let client = tokio::spawn(
+            state.try_start_with_fallback(|| async { panic!("reconnected after the ban") }),
+        );
+
+        vnc_auth_server(&mut server, "wrong", 1).await;
+        let reason = b"Too many authentication failures";
+        server.write_u32(reason.len() as u32).await.unwrap();
+        server.write_all(reason).await.unwrap();
+
+        match client.await.unwrap() {
+            Err(VncError::WrongPassword(Some(reason))) => {
+                assert_eq!(reason, "Too many authentication failures")
+            }
+            e => panic!("unexpected result {:?}", e.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_tls_needs_no_certificate() {
+        use crate::protocol::security::vencrypt::tests::{LEAF, LEAF_KEY};
+        use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+        // no roots, no server name, the default verification
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(None, Some("secret".to_string())))
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        let mut version = [0; 12];
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.read_exact(&mut version).await.unwrap();
+        server.write_all(&[2, 19, 2]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 19);
+        server.write_all(&[0, 2]).await.unwrap();
+        assert_eq!(server.read_u16().await.unwrap(), 2);
+        server.write_u8(0).await.unwrap();
+        // TlsVnc alone
+        server.write_u8(1).await.unwrap();
+        server.write_u32(258).await.unwrap();
+        assert_eq!(server.read_u32().await.unwrap(), 258);
+        server.write_u8(1).await.unwrap();
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(LEAF.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(LEAF_KEY.as_bytes()).unwrap(),
+            )
+            .unwrap();
+        let mut server = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))
+            .accept(server)
+            .await
+            .unwrap();
+        server.write_all(&[0; 16]).await.unwrap();
+        let mut response = [0; 16];
+        server.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..], zero_challenge_response("secret").await);
+        server.write_u32(0).await.unwrap();
+
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 0]).await.unwrap();
+        server.flush().await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        vnc.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn vencrypt_subtypes_are_kept() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(
+                Some("user".to_string()),
+                Some("secret".to_string()),
+            ))
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        let mut version = [0; 12];
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.read_exact(&mut version).await.unwrap();
+        server.write_all(&[1, 19]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 19);
+        server.write_all(&[0, 2]).await.unwrap();
+        assert_eq!(server.read_u16().await.unwrap(), 2);
+        server.write_u8(0).await.unwrap();
+
+        // Plain and TlsNone, along with one unknown to us
+        server.write_u8(3).await.unwrap();
+        for subtype in [256, 9999, 257] {
+            server.write_u32(subtype).await.unwrap();
+        }
+        assert_eq!(server.read_u32().await.unwrap(), 256);
+        server.write_u8(1).await.unwrap();
+        assert_eq!(server.read_u32().await.unwrap(), 4);
+        assert_eq!(server.read_u32().await.unwrap(), 6);
+        let mut credentials = [0; 10];
+        server.read_exact(&mut credentials).await.unwrap();
+        assert_eq!(&credentials, b"usersecret");
+        server.write_u32(0).await.unwrap();
+
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        assert_eq!(
+            vnc.vencrypt_subtypes_offered().await,
+            vec![VeNCryptSubtype::Plain, VeNCryptSubtype::TlsNone]
+        );
+        vnc.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rfb33_vnc_auth() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(None, Some("secret".to_string())))
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        server.write_all(b"RFB 003.003\n").await.unwrap();
+        let mut version = [0; 12];
+        server.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.003\n");
+
+        // the server's choice, the client answers the challenge without selecting anything
+        server.write_u32(2).await.unwrap();
+        server.write_all(&[7; 16]).await.unwrap();
+        let mut response = [0; 16];
+        server.read_exact(&mut response).await.unwrap();
+        assert_ne!(response, [7; 16]);
+        server.write_u32(0).await.unwrap();
+
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        vnc.close().await.unwrap();
+    }
+
+    #[cfg(feature = "ard")]
+    #[tokio::test]
+    async fn apple_remote_desktop_auth() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(
+                Some("alice".to_string()),
+                Some("secret".to_string()),
+            ))
+            .add_encoding(VncEncoding::Raw)
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        let mut version = [0; 12];
+        server.read_exact(&mut version).await.unwrap();
+        // with a username ARD goes before VncAuth
+        server.write_all(&[2, 2, 30]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 30);
+
+        // generator 5, a 4 byte prime and the server's public key
+        server.write_all(&[0, 5, 0, 4]).await.unwrap();
+        server
+            .write_all(&0xffff_fffb_u32.to_be_bytes())
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 7]).await.unwrap();
+        let mut response = [0; 128 + 4];
+        server.read_exact(&mut response).await.unwrap();
+        assert!(u32::from_be_bytes(response[128..].try_into().unwrap()) < 0xffff_fffb);
+        server.write_u32(0).await.unwrap();
+
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        vnc.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tight_security_handshake() {
+        fn capability(code: i32, vendor: &[u8; 4], name: &[u8; 8]) -> Vec<u8> {
+            [&code.to_be_bytes()[..], vendor, name].concat()
+        }
+
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(None, Some("secret".to_string())))
+            .add_encoding(VncEncoding::Raw)
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        // laid out as a TightVNC server sends it
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        let mut version = [0; 12];
+        server.read_exact(&mut version).await.unwrap();
+        server.write_all(&[1, 16]).await.unwrap();
+        assert_eq!(server.read_u8().await.unwrap(), 16);
+
+        server.write_u32(1).await.unwrap();
+        server
+            .write_all(&capability(0, b"TGHT", b"NOTUNNEL"))
+            .await
+            .unwrap();
+        assert_eq!(server.read_u32().await.unwrap(), 0);
+        server.write_u32(1).await.unwrap();
+        server
+            .write_all(&capability(2, b"STDV", b"VNCAUTH_"))
+            .await
+            .unwrap();
+        assert_eq!(server.read_u32().await.unwrap(), 2);
+        server.write_all(&[7; 16]).await.unwrap();
+        let mut response = [0; 16];
+        server.read_exact(&mut response).await.unwrap();
+        server.write_u32(0).await.unwrap();
+
+        assert_eq!(server.read_u8().await.unwrap(), 1);
+        server.write_all(&[0, 8, 0, 6]).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_all(&[0, 0, 0, 5]).await.unwrap();
+        server.write_all(b"tight").await.unwrap();
+        // one server message, one client message and two encodings
+        server.write_all(&[0, 1, 0, 1, 0, 2, 0, 0]).await.unwrap();
+        for (code, vendor, name) in [
+            (130, b"TGHT", b"FTS_LSDT"),
+            (130, b"TGHT", b"FTC_LSRQ"),
+            (7, b"TGHT", b"TIGHT___"),
+            (1, b"STDV", b"COPYRECT"),
+        ] {
+            server
+                .write_all(&capability(code, vendor, name))
+                .await
+                .unwrap();
+        }
+        server.write_u8(2).await.unwrap();
+
+        let vnc = client.await.unwrap().unwrap().finish().unwrap();
+        assert_eq!(vnc.server_name().await, "tight");
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::Bell => break,
+                VncEvent::SecurityWarning { .. }
+                | VncEvent::SetResolution(_)
+                | VncEvent::SetPixelFormat(_) => (),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+        vnc.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rfb33_rejects_vencrypt() {
+        let (connector, mut server) = VncConnector::new_duplex(4096);
+        let state = connector
+            .set_credentials(Credentials::new(None, Some("secret".to_string())))
+            .add_encoding(VncEncoding::Raw)
+            .build()
+            .unwrap();
+        let client = tokio::spawn(state.try_start());
+
+        server.write_all(b"RFB 003.003\n").await.unwrap();
+        let mut version = [0; 12];
+        server.read_exact(&mut version).await.unwrap();
+        server.write_u32(19).await.unwrap();
+
+        assert!(matches!(
+            client.await.unwrap(),
+            Err(VncError::General(e)) if e.contains("3.3")
+        ));
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_retried_until_listening() {
+        // find a free port, then only listen on it a while later
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(
+            VncConnector::connect_with_tcp_retry(addr, 2, Duration::from_millis(10))
+                .await
+                .is_err()
+        );
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        assert!(
+            VncConnector::connect_with_tcp_retry(addr, 100, Duration::from_millis(20))
+                .await
+                .is_ok()
+        );
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn quality_preset_replaces_the_levels() {
+        let connector = VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Tight)
+            .add_encoding(VncEncoding::JpegQualityLevel9Pseudo)
+            .enable_lossy_refresh()
+            .set_quality_preset(QualityPreset::LowBandwidth)
+            .add_encoding(VncEncoding::Raw)
+            .set_quality_preset(QualityPreset::Lossless);
+        assert_eq!(
+            connector.config.encodings,
+            [
+                VncEncoding::Tight,
+                VncEncoding::Raw,
+                VncEncoding::CompressLevel1Pseudo
+            ]
+        );
+    }
+
+    #[test]
+    fn text_encodings() {
+        let connector = VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Zrle)
+            .optimize_for_text();
+        assert_eq!(
+            connector.config.encodings,
+            [
+                VncEncoding::Tight,
+                VncEncoding::CopyRect,
+                VncEncoding::Raw,
+                VncEncoding::CompressLevel9Pseudo,
+                VncEncoding::CursorPseudo,
+                VncEncoding::DesktopSizePseudo,
+                VncEncoding::LastRectPseudo,
+                VncEncoding::FencePseudo,
+                VncEncoding::ContinuousUpdatesPseudo,
+            ]
+        );
+        assert!(connector.config.auto_continuous_updates);
+    }
+}
@@ -1,18 +1,30 @@
 use futures::TryStreamExt;
 use tokio_stream::wrappers::ReceiverStream;
 
+use std::collections::{BTreeSet, HashMap};
 use std::io::{Error, ErrorKind};
-use std::{future::Future, sync::Arc, vec};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::{future::Future, sync::Arc, time::Duration};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{oneshot, watch, Mutex};
 use tokio_util::compat::*;
 use tracing::*;
 
-use crate::protocol::{ClientMsg, ServerMsg};
-use crate::{codec, PixelFormat, Rect, VncEncoding, VncError, VncEvent, X11Event};
+use super::builder::ConnectorConfig;
+use super::recorder::Recorder;
+use super::timeout::TimeoutReader;
+use crate::framebuffer::Framebuffer;
+use crate::protocol::messages::{clipboard, fence, DEFAULT_MAX_NAME_LEN, XVP_VERSION};
+use crate::protocol::security::{tight, vencrypt::VeNCryptSubtype};
+use crate::protocol::text::read_string;
+use crate::protocol::{ClientMsg, ColourMap, ServerInit, ServerMsg, XvpOperation};
+use crate::{
+    codec, PixelFormat, Rect, Screen, ScreenLayout, ScrollConfig, VncEncoding, VncError, VncEvent,
+    X11Event,
+};
 
 const CHANNEL_SIZE: usize = 4096;
 
@@ -26,9 +38,12 @@ struct ImageRect {
     encoding: VncEncoding,
 }
 
-impl From<[u8; 12]> for ImageRect {
-    fn from(buf: [u8; 12]) -> Self {
-        Self {
+impl TryFrom<[u8; 12]> for ImageRect {
+    type Error = VncError;
+
+    fn try_from(buf: [u8; 12]) -> Result<Self, Self::Error> {
+        // without knowing the encoding there is no way to find the next rectangle
+        Ok(Self {
             rect: Rect {
                 x: (buf[0] as u16) << 8 | buf[1] as u16,
                 y: (buf[2] as u16) << 8 | buf[3] as u16,
@@ -39,8 +54,8 @@ impl From<[u8; 12]> for ImageRect {
                 | (buf[9] as u32) << 16
                 | (buf[10] as u32) << 8
                 | (buf[11] as u32))
-                .into(),
-        }
+                .try_into()?,
+        })
     }
 }
 
@@ -51,53 +66,423 @@ impl ImageRect {
     {
         let mut rect_buf = [0_u8; 12];
         reader.read_exact(&mut rect_buf).await?;
-        Ok(rect_buf.into())
+        rect_buf.try_into()
+    }
+}
+
+/// What a session is made of besides the connection itself
+///
+/// Taken with [VncClient::export_state] and resumed with [VncClient::from_state],
+///
+/// along with the settings given to the [crate::VncConnector] (read timeout, rate limit, registered messages, ...)
+///
+/// With the `serde` feature it can be serialized, e.g. to hand the connection over to another process
+///
+/// A resumed session starts with a full (non-incremental) update, which repaints the framebuffer
+///
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionState {
+    pub name: String,
+    pub screen: (u16, u16),
+    pub pixel_format: PixelFormat,
+    pub encodings: Vec<VncEncoding>,
+    pub colour_map: ColourMap,
+    /// The screen as of the last [VncEvent::Frame], only kept with [crate::VncConnector::composite_frames]
+    pub framebuffer: Option<Framebuffer>,
+    /// Whether the server announced continuous updates, see [VncClient::enable_continuous_updates]
+    pub continuous_updates: bool,
+    /// Whether the server sent an ExtendedDesktopSize rectangle, see [VncClient::set_desktop_size]
+    pub desktop_resize: bool,
+    /// The xvp-extension-version agreed on, 0 if the server didn't announce xvp
+    pub xvp_version: u8,
+    /// Whether the server announced QEMU extended key events, see [VncClient::supports_extended_key]
+    pub extended_key: bool,
+    /// The flags of the server's extended clipboard caps, `None` while the Latin-1 clipboard is used
+    pub clipboard_caps: Option<u32>,
+    settings: ReadSettings,
+}
+
+/// The part of [ReadOptions] set up by the connector, which a resumed session keeps
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct ReadSettings {
+    timeout: Option<Duration>,
+    rate_limit: Option<u64>,
+    update_interval: Option<Duration>,
+    keep_encoded: bool,
+    cursor_only: bool,
+    composite_frames: bool,
+    framebuffer_capacity: Option<(u16, u16)>,
+    full_redraws: bool,
+    zrlee: bool,
+    detect_endianness: bool,
+    normalize_newlines: bool,
+    auto_continuous_updates: bool,
+    extensions: HashMap<u8, usize>,
+    max_name_len: usize,
+}
+
+impl From<ReadSettings> for ReadOptions {
+    fn from(settings: ReadSettings) -> Self {
+        Self {
+            timeout: settings.timeout,
+            rate_limit: settings.rate_limit,
+            update_interval: settings.update_interval,
+            keep_encoded: settings.keep_encoded,
+            cursor_only: settings.cursor_only,
+            composite_frames: settings.composite_frames,
+            framebuffer_capacity: settings.framebuffer_capacity,
+            full_redraws: settings.full_redraws,
+            zrlee: settings.zrlee,
+            detect_endianness: settings.detect_endianness,
+            normalize_newlines: settings.normalize_newlines,
+            auto_continuous_updates: settings.auto_continuous_updates,
+            extensions: settings.extensions,
+            max_name_len: settings.max_name_len,
+            ..Default::default()
+        }
+    }
+}
+
+/// How the incoming data is read and decoded, and where it is accounted for
+struct ReadOptions {
+    timeout: Option<Duration>,
+    /// bytes per second read from the socket by the traffic thread
+    rate_limit: Option<u64>,
+    /// see [crate::VncConnector::set_update_interval]
+    update_interval: Option<Duration>,
+    keep_encoded: bool,
+    /// skip the framebuffer, see [crate::VncConnector::cursor_only]
+    cursor_only: bool,
+    /// see [crate::VncConnector::composite_frames]
+    composite_frames: bool,
+    /// see [crate::VncConnector::preallocate_framebuffer]
+    framebuffer_capacity: Option<(u16, u16)>,
+    /// see [crate::VncConnector::report_full_redraws]
+    full_redraws: bool,
+    /// see [crate::VncConnector::zrle_stream_per_rect]
+    zrlee: bool,
+    /// see [crate::VncConnector::detect_endianness]
+    detect_endianness: bool,
+    /// see [crate::VncConnector::normalize_clipboard_newlines]
+    normalize_newlines: bool,
+    /// turn continuous updates on as soon as the server announces them,
+    /// see [crate::VncConnector::optimize_for_text]
+    auto_continuous_updates: bool,
+    extensions: HashMap<u8, usize>,
+    stats: Arc<std::sync::Mutex<DecodeStats>>,
+    /// the xvp-extension-version agreed on, 0 until the server announces xvp
+    xvp_version: Arc<AtomicU8>,
+    /// set once the server announced continuous updates
+    continuous_updates: Arc<AtomicBool>,
+    /// set once the server sent an ExtendedDesktopSize rectangle
+    desktop_resize: Arc<AtomicBool>,
+    /// set while [VncEncoding::PointerPosPseudo] is among the encodings sent
+    pointer_pos: Arc<AtomicBool>,
+    /// set once the server announced QEMU extended key events
+    extended_key: Arc<AtomicBool>,
+    /// see [VncClient::used_encodings]
+    used_encodings: Arc<std::sync::Mutex<Vec<VncEncoding>>>,
+    clipboard: Arc<std::sync::Mutex<ClipboardState>>,
+    /// for the messages answered without the frontend
+    reply_ch: Option<Sender<ClientMsg>>,
+    pool: codec::BufferPool,
+    /// the framebuffer size at the start, followed through DesktopSize rectangles
+    screen: (u16, u16),
+    /// the screen a resumed session carries on from with composite_frames, taken by the read loop
+    framebuffer: std::sync::Mutex<Option<Framebuffer>>,
+    max_name_len: usize,
+}
+
+impl ReadOptions {
+    /// Remember that the server sent something in `encoding`
+    fn note_used(&self, encoding: VncEncoding) {
+        let mut used = self.used_encodings.lock().unwrap();
+        if !used.contains(&encoding) {
+            used.push(encoding);
+        }
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            rate_limit: None,
+            update_interval: None,
+            keep_encoded: false,
+            cursor_only: false,
+            composite_frames: false,
+            framebuffer_capacity: None,
+            full_redraws: false,
+            zrlee: false,
+            detect_endianness: false,
+            normalize_newlines: false,
+            auto_continuous_updates: false,
+            extensions: HashMap::new(),
+            stats: Default::default(),
+            xvp_version: Default::default(),
+            continuous_updates: Default::default(),
+            desktop_resize: Default::default(),
+            pointer_pos: Default::default(),
+            extended_key: Default::default(),
+            used_encodings: Default::default(),
+            clipboard: Default::default(),
+            reply_ch: None,
+            pool: Default::default(),
+            screen: (0, 0),
+            framebuffer: Default::default(),
+            max_name_len: DEFAULT_MAX_NAME_LEN,
+        }
+    }
+}
+
+/// Tells if the server sends its pixels byte swapped, see [crate::VncConnector::detect_endianness]
+#[derive(Debug, Default)]
+struct EndiannessCheck {
+    /// where the byte left out of the colours is, for 32bpp formats with one
+    free_byte: Option<usize>,
+    /// the first value found there and whether another one followed
+    declared: Option<(u8, bool)>,
+    /// the same at the mirrored place, where the free byte is if the pixels are swapped
+    mirrored: Option<(u8, bool)>,
+}
+
+impl EndiannessCheck {
+    fn new(pf: &PixelFormat) -> Self {
+        let mask = (pf.red_max as u32) << pf.red_shift
+            | (pf.green_max as u32) << pf.green_shift
+            | (pf.blue_max as u32) << pf.blue_shift;
+        let free_byte = if pf.bits_per_pixel == 32 && pf.true_color_flag > 0 {
+            // counted from the least significant byte, then placed in memory
+            (0..4)
+                .find(|byte| mask & (0xff << (byte * 8)) == 0)
+                .map(|byte| {
+                    if pf.big_endian_flag > 0 {
+                        3 - byte
+                    } else {
+                        byte
+                    }
+                })
+        } else {
+            None
+        };
+        Self {
+            free_byte,
+            ..Default::default()
+        }
+    }
+
+    fn sample(&mut self, pixels: &[u8]) {
+        let Some(free_byte) = self.free_byte else {
+            return;
+        };
+        let note = |seen: &mut Option<(u8, bool)>, value: u8| match seen {
+            Some((first, varies)) => *varies |= *first != value,
+            None => *seen = Some((value, false)),
+        };
+        for pixel in pixels.chunks_exact(4) {
+            note(&mut self.declared, pixel[free_byte]);
+            note(&mut self.mirrored, pixel[3 - free_byte]);
+        }
+    }
+
+    /// The free byte varies where it should be, but keeps one value where it would be if swapped
+    fn swapped(&self) -> bool {
+        matches!(
+            (self.declared, self.mirrored),
+            (Some((_, true)), Some((_, false)))
+        )
     }
 }
 
+/// The extended clipboard as far as it got, shared by the client and its decoding thread
+#[derive(Debug, Default)]
+struct ClipboardState {
+    /// the flags of the server's caps, `None` while the Latin-1 clipboard is used
+    server_caps: Option<u32>,
+    /// our latest text, provided when the server requests it
+    local_text: Option<String>,
+}
+
+/// Running totals of the framebuffer updates received, see [VncClient::decode_stats]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeStats {
+    pub updates: u64,
+    /// bytes of all those updates, headers included
+    pub bytes: u64,
+    /// pixels covered by their rectangles, pseudo ones aside
+    pub pixels: u64,
+    /// time from the header to the end of every update, decoding included
+    ///
+    /// not measured on wasm32, where it stays zero
+    pub busy: Duration,
+}
+
 struct VncInner {
     name: String,
     screen: (u16, u16),
     input_ch: Sender<ClientMsg>,
     output_ch: Receiver<VncEvent>,
+    pixel_format: watch::Sender<PixelFormat>,
+    clip_region: watch::Sender<Option<Rect>>,
+    encodings: Vec<VncEncoding>,
+    colour_map: ColourMap,
+    /// the screen as of the last frame handed out, with composite_frames
+    framebuffer: Option<Framebuffer>,
+    settings: ReadSettings,
+    stats: Arc<std::sync::Mutex<DecodeStats>>,
+    xvp_version: Arc<AtomicU8>,
+    continuous_updates: Arc<AtomicBool>,
+    desktop_resize: Arc<AtomicBool>,
+    pointer_pos: Arc<AtomicBool>,
+    extended_key: Arc<AtomicBool>,
+    used_encodings: Arc<std::sync::Mutex<Vec<VncEncoding>>>,
+    clipboard: Arc<std::sync::Mutex<ClipboardState>>,
+    pool: codec::BufferPool,
+    cursor_only: bool,
+    /// the screen chosen by [VncClient::focus_screen]
+    focus: Option<Rect>,
+    /// see [crate::VncConnector::normalize_clipboard_newlines]
+    normalize_newlines: bool,
+    /// see [VncClient::vencrypt_subtypes_offered]
+    vencrypt_offered: Vec<VeNCryptSubtype>,
+    scroll: ScrollConfig,
+    /// the keys down on the server as far as we know, with the pointer's position and buttons
+    pressed_keys: BTreeSet<u32>,
+    pointer: (u16, u16, u8),
     decoding_stop: Option<oneshot::Sender<()>>,
     net_conn_stop: Option<oneshot::Sender<()>>,
     closed: bool,
+    /// cleared as soon as either thread stops, readable without locking the client
+    running: Arc<AtomicBool>,
 }
 
 /// The instance of a connected vnc client
 impl VncInner {
-    async fn new<S>(
-        mut stream: S,
-        shared: bool,
-        mut pixel_format: Option<PixelFormat>,
-        encodings: Vec<VncEncoding>,
-    ) -> Result<Self, VncError>
+    async fn new<S>(mut stream: S, config: ConnectorConfig) -> Result<Self, VncError>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let (conn_ch_tx, conn_ch_rx) = channel(CHANNEL_SIZE);
-        let (input_ch_tx, input_ch_rx) = channel(CHANNEL_SIZE);
+        let ConnectorConfig {
+            allow_shared: shared,
+            manual_updates,
+            keep_encoded,
+            cursor_only,
+            composite_frames,
+            framebuffer_capacity,
+            full_redraws,
+            zrlee,
+            detect_endianness,
+            normalize_newlines,
+            auto_continuous_updates,
+            extensions,
+            mut pixel_format,
+            always_send_pixel_format,
+            encodings,
+            read_timeout,
+            read_rate_limit,
+            update_interval,
+            max_name_len,
+            security_warning,
+            vencrypt_offered,
+            tight_security,
+            ..
+        } = config;
         let (output_ch_tx, output_ch_rx) = channel(CHANNEL_SIZE);
-        let (decoding_stop_tx, decoding_stop_rx) = oneshot::channel();
-        let (net_conn_stop_tx, net_conn_stop_rx) = oneshot::channel();
+
+        if let Some(reason) = security_warning {
+            warn!("Insecure connection: {}", reason);
+            output_ch_tx
+                .send(VncEvent::SecurityWarning { reason })
+                .await?;
+        }
 
         trace!("client init msg");
         send_client_init(&mut stream, shared).await?;
 
         trace!("server init msg");
-        let (name, (width, height)) =
-            read_server_init(&mut stream, &mut pixel_format, &|e| async {
+        let (name, (width, height)) = read_server_init(
+            &mut stream,
+            &mut pixel_format,
+            always_send_pixel_format,
+            max_name_len,
+            tight_security,
+            &|e| async {
                 output_ch_tx.send(e).await?;
                 Ok(())
-            })
-            .await?;
+            },
+        )
+        .await?;
 
         trace!("client encodings: {:?}", encodings);
-        send_client_encoding(&mut stream, encodings).await?;
+        send_client_encoding(&mut stream, encodings.clone()).await?;
+        // SetPixelFormat & SetEncodings must reach the server ahead of the first update request,
+        // which goes through the traffic thread
+        stream.flush().await?;
+
+        let mut inner = Self::start(
+            stream,
+            SessionState {
+                name,
+                screen: (width, height),
+                pixel_format: pixel_format.unwrap(),
+                encodings,
+                colour_map: ColourMap::new(),
+                framebuffer: None,
+                continuous_updates: false,
+                desktop_resize: false,
+                xvp_version: 0,
+                extended_key: false,
+                clipboard_caps: None,
+                settings: ReadSettings {
+                    timeout: read_timeout,
+                    rate_limit: read_rate_limit,
+                    update_interval,
+                    max_name_len,
+                    keep_encoded,
+                    cursor_only,
+                    composite_frames,
+                    framebuffer_capacity,
+                    full_redraws,
+                    zrlee,
+                    detect_endianness,
+                    normalize_newlines,
+                    auto_continuous_updates,
+                    extensions,
+                },
+            },
+            (output_ch_tx, output_ch_rx),
+        );
+        inner.vencrypt_offered = vencrypt_offered;
+
+        if !manual_updates {
+            trace!("Require the first frame");
+            inner
+                .input_ch
+                .send(ClientMsg::FramebufferUpdateRequest(inner.update_area(), 0))
+                .await?;
+        }
+        Ok(inner)
+    }
+
+    async fn from_state<S>(mut stream: S, state: SessionState) -> Result<Self, VncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // the zlib streams are gone, have the server start over from our formats
+        trace!("Resume {} with {:?}", state.name, state.encodings);
+        ClientMsg::SetPixelFormat(state.pixel_format)
+            .write(&mut stream)
+            .await?;
+        send_client_encoding(&mut stream, state.encodings.clone()).await?;
+        stream.flush().await?;
 
-        trace!("Require the first frame");
-        input_ch_tx
+        let (width, height) = state.screen;
+        let inner = Self::start(stream, state, channel(CHANNEL_SIZE));
+        inner
+            .input_ch
             .send(ClientMsg::FramebufferUpdateRequest(
                 Rect {
                     x: 0,
@@ -108,6 +493,78 @@ impl VncInner {
                 0,
             ))
             .await?;
+        Ok(inner)
+    }
+
+    /// Spawn the decoding & traffic threads over an initialized connection
+    fn start<S>(
+        stream: S,
+        state: SessionState,
+        (output_ch_tx, output_ch_rx): (Sender<VncEvent>, Receiver<VncEvent>),
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let SessionState {
+            name,
+            screen,
+            pixel_format,
+            encodings,
+            colour_map,
+            framebuffer,
+            continuous_updates,
+            desktop_resize,
+            xvp_version,
+            extended_key,
+            clipboard_caps,
+            settings,
+        } = state;
+        let (conn_ch_tx, conn_ch_rx) = channel(CHANNEL_SIZE);
+        let (input_ch_tx, input_ch_rx) = channel(CHANNEL_SIZE);
+        let normalize_newlines = settings.normalize_newlines;
+        let framebuffer = settings.composite_frames.then(|| {
+            framebuffer.unwrap_or_else(|| Framebuffer::with_size(pixel_format, screen.0, screen.1))
+        });
+        let options = ReadOptions {
+            reply_ch: Some(input_ch_tx.clone()),
+            screen,
+            framebuffer: std::sync::Mutex::new(framebuffer.clone()),
+            ..settings.clone().into()
+        };
+        // a resumed session won't hear the announcements again
+        options
+            .continuous_updates
+            .store(continuous_updates, Ordering::Release);
+        options
+            .desktop_resize
+            .store(desktop_resize, Ordering::Release);
+        options.xvp_version.store(xvp_version, Ordering::Release);
+        options.extended_key.store(extended_key, Ordering::Release);
+        options.clipboard.lock().unwrap().server_caps = clipboard_caps;
+        let (decoding_stop_tx, decoding_stop_rx) = oneshot::channel();
+        let (net_conn_stop_tx, net_conn_stop_rx) = oneshot::channel();
+        let (pf_tx, pf_rx) = watch::channel(pixel_format);
+        let (clip_tx, clip_rx) = watch::channel(None);
+        let decoding_colour_map = colour_map.clone();
+        let stats = options.stats.clone();
+        let xvp_version = options.xvp_version.clone();
+        let continuous_updates = options.continuous_updates.clone();
+        let desktop_resize = options.desktop_resize.clone();
+        let pointer_pos = options.pointer_pos.clone();
+        let extended_key = options.extended_key.clone();
+        let used_encodings = options.used_encodings.clone();
+        pointer_pos.store(
+            encodings.contains(&VncEncoding::PointerPosPseudo),
+            Ordering::Release,
+        );
+        let clipboard = options.clipboard.clone();
+        let pool = options.pool.clone();
+        let cursor_only = options.cursor_only;
+        let rate_limit = options.rate_limit;
+        let update_interval = options.update_interval;
+        let running = Arc::new(AtomicBool::new(true));
+        let decoding_running = running.clone();
+        let net_running = running.clone();
 
         // start the decoding thread
         spawn(async move {
@@ -122,15 +579,26 @@ impl VncInner {
                 Ok(())
             };
 
-            let pf = pixel_format.as_ref().unwrap();
-            if let Err(e) =
-                asycn_vnc_read_loop(&mut conn_ch_rx, pf, &output_func, decoding_stop_rx).await
+            if let Err(e) = asycn_vnc_read_loop(
+                &mut conn_ch_rx,
+                pf_rx,
+                clip_rx,
+                decoding_colour_map,
+                &options,
+                &output_func,
+                decoding_stop_rx,
+            )
+            .await
             {
                 if let VncError::IoError(e) = e {
                     if let std::io::ErrorKind::UnexpectedEof = e.kind() {
                         // this should be a normal case when the network connection disconnects
                         // and we just send an EOF over the inner bridge between the process thread and the decode thread
                         // do nothing here
+                    } else if let std::io::ErrorKind::TimedOut = e.kind() {
+                        error!("Error occurs during the decoding {:?}", e);
+                        let _ =
+                            output_func(VncEvent::Error(VncError::ReadTimeout.to_string())).await;
                     } else {
                         error!("Error occurs during the decoding {:?}", e);
                         let _ = output_func(VncEvent::Error(e.to_string())).await;
@@ -140,28 +608,76 @@ impl VncInner {
                     let _ = output_func(VncEvent::Error(e.to_string())).await;
                 }
             }
+            decoding_running.store(false, Ordering::Release);
             trace!("Decoding thread stops");
         });
 
         // start the traffic process thread
         spawn(async move {
             trace!("Net Connection thread starts");
-            let _ =
-                async_connection_process_loop(stream, input_ch_rx, conn_ch_tx, net_conn_stop_rx)
-                    .await;
+            let _ = async_connection_process_loop(
+                stream,
+                input_ch_rx,
+                conn_ch_tx,
+                rate_limit,
+                update_interval,
+                net_conn_stop_rx,
+            )
+            .await;
+            net_running.store(false, Ordering::Release);
             trace!("Net Connection thread stops");
         });
 
         info!("VNC Client {name} starts");
-        Ok(Self {
+        Self {
             name,
-            screen: (width, height),
+            screen,
             input_ch: input_ch_tx,
             output_ch: output_ch_rx,
+            pixel_format: pf_tx,
+            clip_region: clip_tx,
+            encodings,
+            colour_map,
+            framebuffer,
+            settings,
+            stats,
+            xvp_version,
+            continuous_updates,
+            desktop_resize,
+            pointer_pos,
+            extended_key,
+            used_encodings,
+            clipboard,
+            pool,
+            cursor_only,
+            focus: None,
+            normalize_newlines,
+            vencrypt_offered: Vec::new(),
+            scroll: ScrollConfig::default(),
+            pressed_keys: BTreeSet::new(),
+            pointer: (0, 0, 0),
             decoding_stop: Some(decoding_stop_tx),
             net_conn_stop: Some(net_conn_stop_tx),
             closed: false,
-        })
+            running,
+        }
+    }
+
+    /// The whole screen, the focused one, or a single pixel when only the cursor is followed
+    fn update_area(&self) -> Rect {
+        let (width, height) = if self.cursor_only {
+            (1, 1)
+        } else if let Some(focus) = self.focus {
+            return focus;
+        } else {
+            self.screen
+        };
+        Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
     }
 
     async fn input(&mut self, event: X11Event) -> Result<(), VncError> {
@@ -169,32 +685,189 @@ impl VncInner {
             Err(VncError::ClientNotRunning)
         } else {
             let msg = match event {
-                X11Event::Refresh => ClientMsg::FramebufferUpdateRequest(
-                    Rect {
-                        x: 0,
-                        y: 0,
-                        width: self.screen.0,
-                        height: self.screen.1,
-                    },
-                    1,
-                ),
-                X11Event::KeyEvent(key) => ClientMsg::KeyEvent(key.keycode, key.down),
+                X11Event::Refresh => ClientMsg::FramebufferUpdateRequest(self.update_area(), 1),
+                X11Event::KeyEvent(key) => {
+                    if key.down {
+                        self.pressed_keys.insert(key.keycode);
+                    } else {
+                        self.pressed_keys.remove(&key.keycode);
+                    }
+                    ClientMsg::KeyEvent(key.keycode, key.down)
+                }
+                X11Event::ExtendedKeyEvent {
+                    down,
+                    keysym,
+                    keycode,
+                } => {
+                    if down {
+                        self.pressed_keys.insert(keysym);
+                    } else {
+                        self.pressed_keys.remove(&keysym);
+                    }
+                    if self.extended_key.load(Ordering::Acquire) {
+                        ClientMsg::QemuExtendedKeyEvent(keysym, keycode, down)
+                    } else {
+                        ClientMsg::KeyEvent(keysym, down)
+                    }
+                }
                 X11Event::PointerEvent(mouse) => {
+                    self.pointer = (mouse.position_x, mouse.position_y, mouse.bottons);
                     ClientMsg::PointerEvent(mouse.position_x, mouse.position_y, mouse.bottons)
                 }
-                X11Event::CopyText(text) => ClientMsg::ClientCutText(text),
+                X11Event::CopyText(text) => {
+                    let mut shared = self.clipboard.lock().unwrap();
+                    match shared.server_caps {
+                        Some(caps) => {
+                            // announce the text and wait for the request, unless the server can't ask
+                            let msg = if caps & clipboard::NOTIFY != 0 {
+                                ClientMsg::ExtendedClipboard(
+                                    clipboard::NOTIFY | clipboard::TEXT,
+                                    vec![],
+                                )
+                            } else {
+                                clipboard::provide(&text)?
+                            };
+                            shared.local_text = Some(text);
+                            msg
+                        }
+                        None if self.normalize_newlines => ClientMsg::ClientCutText(
+                            text.replace("\r\n", "\n").replace('\n', "\r\n"),
+                        ),
+                        None => ClientMsg::ClientCutText(text),
+                    }
+                }
             };
             self.input_ch.send(msg).await?;
             Ok(())
         }
     }
 
+    async fn release_all_input(&mut self) -> Result<(), VncError> {
+        if self.closed {
+            return Err(VncError::ClientNotRunning);
+        }
+        for keycode in std::mem::take(&mut self.pressed_keys) {
+            trace!("Release key {:#x}", keycode);
+            self.input_ch
+                .send(ClientMsg::KeyEvent(keycode, false))
+                .await?;
+        }
+        // without a button held the pointer stays where it is, not even moved to (0, 0)
+        let (x, y, buttons) = self.pointer;
+        if buttons != 0 {
+            self.pointer = (x, y, 0);
+            self.input_ch.send(ClientMsg::PointerEvent(x, y, 0)).await?;
+        }
+        Ok(())
+    }
+
+    fn set_clip_region(&mut self, clip: Option<Rect>) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else {
+            self.clip_region.send_replace(clip);
+            Ok(())
+        }
+    }
+
+    async fn xvp_operation(&mut self, op: XvpOperation) -> Result<(), VncError> {
+        let version = self.xvp_version.load(Ordering::Acquire);
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else if version == 0 {
+            Err(VncError::XvpUnavailable)
+        } else {
+            self.input_ch.send(ClientMsg::Xvp(version, op)).await?;
+            Ok(())
+        }
+    }
+
+    async fn enable_continuous_updates(&mut self, enable: bool) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else if !self.continuous_updates.load(Ordering::Acquire) {
+            // the server would silently ignore it, leaving the screen frozen
+            Err(VncError::ContinuousUpdatesUnavailable)
+        } else {
+            self.input_ch
+                .send(ClientMsg::EnableContinuousUpdates(
+                    enable,
+                    self.update_area(),
+                ))
+                .await?;
+            Ok(())
+        }
+    }
+
+    async fn set_desktop_size(
+        &mut self,
+        width: u16,
+        height: u16,
+        screens: &[ScreenLayout],
+    ) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else if !self.desktop_resize.load(Ordering::Acquire) {
+            Err(VncError::DesktopResizeUnavailable)
+        } else {
+            self.input_ch
+                .send(ClientMsg::SetDesktopSize(width, height, screens.to_vec()))
+                .await?;
+            Ok(())
+        }
+    }
+
+    async fn request_update(&mut self, rect: Rect, incremental: bool) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else {
+            self.input_ch
+                .send(ClientMsg::FramebufferUpdateRequest(rect, incremental as u8))
+                .await?;
+            Ok(())
+        }
+    }
+
+    async fn set_pixel_format(&mut self, pf: PixelFormat) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else {
+            self.input_ch.send(ClientMsg::SetPixelFormat(pf)).await?;
+            self.pixel_format.send_replace(pf);
+            if let Some(frame) = self.framebuffer.as_mut() {
+                frame.apply(&VncEvent::SetPixelFormat(pf));
+            }
+            Ok(())
+        }
+    }
+
+    async fn set_encodings(&mut self, encodings: &[VncEncoding]) -> Result<(), VncError> {
+        if self.closed {
+            Err(VncError::ClientNotRunning)
+        } else if encodings.is_empty() {
+            Err(VncError::NoEncoding)
+        } else {
+            self.input_ch
+                .send(ClientMsg::SetEncodings(encodings.to_vec()))
+                .await?;
+            self.pointer_pos.store(
+                encodings.contains(&VncEncoding::PointerPosPseudo),
+                Ordering::Release,
+            );
+            self.encodings = encodings.to_vec();
+            Ok(())
+        }
+    }
+
     async fn recv_event(&mut self) -> Result<VncEvent, VncError> {
         if self.closed {
             Err(VncError::ClientNotRunning)
         } else {
             match self.output_ch.recv().await {
-                Some(e) => Ok(e),
+                Some(e) => {
+                    self.observe(&e);
+                    Ok(e)
+                }
                 None => {
                     self.closed = true;
                     Err(VncError::ClientNotRunning)
@@ -213,12 +886,77 @@ impl VncInner {
                     Err(VncError::ClientNotRunning)
                 }
                 Err(TryRecvError::Empty) => Ok(None),
-                Ok(e) => Ok(Some(e)),
+                Ok(e) => {
+                    self.observe(&e);
+                    Ok(Some(e))
+                }
             }
             // Ok(self.output_ch.recv().await)
         }
     }
 
+    async fn poll_event_timeout(&mut self, dur: Duration) -> Result<Option<VncEvent>, VncError> {
+        // the channel is cancel safe, a timed out receive loses nothing
+        match tokio::time::timeout(dur, self.recv_event()).await {
+            Ok(event) => event.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Keep track of the state carried by events on their way to the frontend
+    fn observe(&mut self, event: &VncEvent) {
+        if let Some(frame) = self.framebuffer.as_mut() {
+            frame.apply(event);
+        }
+        match event {
+            VncEvent::SetResolution(screen) => self.screen = (screen.width, screen.height),
+            VncEvent::SetColourMap { first, colors } => self.colour_map.update(*first, colors),
+            VncEvent::SetDesktopName(name) => self.name = name.clone(),
+            _ => (),
+        }
+    }
+
+    fn export_state(&self) -> SessionState {
+        SessionState {
+            name: self.name.clone(),
+            screen: self.screen,
+            pixel_format: *self.pixel_format.borrow(),
+            encodings: self.encodings.clone(),
+            colour_map: self.colour_map.clone(),
+            framebuffer: self.framebuffer.clone(),
+            continuous_updates: self.continuous_updates.load(Ordering::Acquire),
+            desktop_resize: self.desktop_resize.load(Ordering::Acquire),
+            xvp_version: self.xvp_version.load(Ordering::Acquire),
+            extended_key: self.extended_key.load(Ordering::Acquire),
+            clipboard_caps: self.clipboard.lock().unwrap().server_caps,
+            settings: self.settings.clone(),
+        }
+    }
+
+    /// Request the whole screen, with what is received of it starting out blank
+    async fn request_first_frame(&mut self) -> Result<(Framebuffer, Option<Rect>), VncError> {
+        if self.closed {
+            return Err(VncError::ClientNotRunning);
+        }
+        let (width, height) = self.screen;
+        self.input_ch
+            .send(ClientMsg::FramebufferUpdateRequest(
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                },
+                0,
+            ))
+            .await?;
+
+        let clip = *self.clip_region.borrow();
+        let mut frame = Framebuffer::with_size(*self.pixel_format.borrow(), width, height);
+        frame.expect_only(clip);
+        Ok((frame, clip))
+    }
+
     /// Stop the VNC engine and release resources
     ///
     fn close(&mut self) -> Result<(), VncError> {
@@ -231,6 +969,7 @@ impl VncInner {
             let _ = decoding_stop.send(());
         }
         self.closed = true;
+        self.running.store(false, Ordering::Release);
         Ok(())
     }
 }
@@ -244,23 +983,57 @@ impl Drop for VncInner {
 
 pub struct VncClient {
     inner: Arc<Mutex<VncInner>>,
+    running: Arc<AtomicBool>,
 }
 
 impl VncClient {
-    pub(super) async fn new<S>(
-        stream: S,
-        shared: bool,
-        pixel_format: Option<PixelFormat>,
-        encodings: Vec<VncEncoding>,
-    ) -> Result<Self, VncError>
+    pub(super) async fn new<S>(stream: S, config: ConnectorConfig) -> Result<Self, VncError>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        Ok(Self {
-            inner: Arc::new(Mutex::new(
-                VncInner::new(stream, shared, pixel_format, encodings).await?,
-            )),
-        })
+        Ok(Self::from_inner(VncInner::new(stream, config).await?))
+    }
+
+    fn from_inner(inner: VncInner) -> Self {
+        Self {
+            running: inner.running.clone(),
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Resume a session taken with [VncClient::export_state] over `stream`
+    ///
+    /// `stream` must be the very same connection, already past the handshake,
+    ///
+    /// e.g. one moved over to another runtime
+    ///
+    /// The settings of the connector that made the exported session are kept as well
+    ///
+    /// The zlib streams of [VncEncoding::Tight] and [VncEncoding::Zrle] can't be carried over,
+    ///
+    /// so SetPixelFormat and SetEncodings are sent again before a full update is requested,
+    ///
+    /// a server that keeps its zlib streams across SetEncodings will then produce garbage
+    ///
+    /// Use encodings without zlib state (e.g. [VncEncoding::Raw], [VncEncoding::Trle]) if unsure
+    ///
+    pub async fn from_state<S>(stream: S, state: SessionState) -> Result<Self, VncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Ok(Self::from_inner(VncInner::from_state(stream, state).await?))
+    }
+
+    /// Take what is needed to resume this session elsewhere with [VncClient::from_state]
+    ///
+    /// The state is as of the last event received from this client,
+    ///
+    /// so drain the events and hand over the connection before anything else arrives,
+    ///
+    /// e.g. with [crate::VncConnector::manual_updates] and no update requested
+    ///
+    pub async fn export_state(&self) -> SessionState {
+        self.inner.lock().await.export_state()
     }
 
     /// Input a `X11Event` from the frontend
@@ -269,73 +1042,497 @@ impl VncClient {
         self.inner.lock().await.input(event).await
     }
 
-    /// Receive a `VncEvent` from the engine
-    /// This function will block until a `VncEvent` is received
+    /// Send `text` to the server's clipboard, the same as [X11Event::CopyText]
     ///
-    pub async fn recv_event(&self) -> Result<VncEvent, VncError> {
-        self.inner.lock().await.recv_event().await
+    /// The text goes as UTF-8 once the server announced the extended clipboard,
+    ///
+    /// which needs [VncEncoding::ExtendedClipboardPseudo], and as Latin-1 otherwise
+    ///
+    pub async fn copy_text(&self, text: impl Into<String>) -> Result<(), VncError> {
+        self.input(X11Event::CopyText(text.into())).await
     }
 
-    /// polling `VncEvent` from the engine and give it to the client
+    /// Change how [VncClient::scroll] maps the deltas to buttons
     ///
-    pub async fn poll_event(&self) -> Result<Option<VncEvent>, VncError> {
-        self.inner.lock().await.poll_event().await
+    pub async fn set_scroll_config(&self, config: ScrollConfig) {
+        self.inner.lock().await.scroll = config;
     }
 
-    /// Stop the VNC engine and release resources
+    /// Scroll by `delta` ticks at `position`, positive is up and right
     ///
-    pub async fn close(&self) -> Result<(), VncError> {
-        self.inner.lock().await.close()
+    /// Sends the press & release pairs given by the [ScrollConfig], see [ScrollConfig::pointer_events],
+    ///
+    /// `buttons` is the mask of the buttons held meanwhile
+    ///
+    pub async fn scroll(
+        &self,
+        position: (u16, u16),
+        buttons: u8,
+        delta: (f32, f32),
+    ) -> Result<(), VncError> {
+        let mut inner = self.inner.lock().await;
+        let events = inner.scroll.pointer_events(position, buttons, delta);
+        for event in events {
+            inner.input(X11Event::PointerEvent(event)).await?;
+        }
+        Ok(())
     }
-}
 
-impl Clone for VncClient {
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone(),
-        }
+    /// Only report what is inside `clip` from the next rectangle on
+    ///
+    /// Images are cut down to their visible part and dropped if nothing is left,
+    ///
+    /// except the jpeg ones, which are passed as a whole if they overlap `clip`
+    ///
+    /// The sources of [VncEvent::Copy] may still lie outside `clip`
+    ///
+    /// Note that every rectangle is still decoded, as the zlib streams must be followed
+    ///
+    pub async fn set_clip_region(&self, clip: Rect) -> Result<(), VncError> {
+        self.inner.lock().await.set_clip_region(Some(clip))
     }
-}
 
-async fn send_client_init<S>(stream: &mut S, shared: bool) -> Result<(), VncError>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    trace!("Send shared flag: {}", shared);
-    stream.write_u8(shared as u8).await?;
-    Ok(())
-}
+    /// Report the whole screen again
+    ///
+    /// This also drops the screen chosen by [VncClient::focus_screen]
+    ///
+    pub async fn clear_clip_region(&self) -> Result<(), VncError> {
+        let mut inner = self.inner.lock().await;
+        inner.focus = None;
+        inner.set_clip_region(None)
+    }
 
-async fn read_server_init<S, F, Fut>(
-    stream: &mut S,
-    pf: &mut Option<PixelFormat>,
-    output_func: &F,
-) -> Result<(String, (u16, u16)), VncError>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    F: Fn(VncEvent) -> Fut,
-    Fut: Future<Output = Result<(), VncError>>,
+    /// Follow a single monitor of a multi-screen desktop, one of the [VncEvent::SetScreenLayout] entries
+    ///
+    /// Sets the clip region to `screen`, and narrows the update requests to it,
+    ///
+    /// so the server only sends what changed on that monitor
+    ///
+    /// Servers that allow resizing can instead be asked for a single-screen desktop,
+    ///
+    /// by passing just `screen` to [VncClient::set_desktop_size]
+    ///
+    pub async fn focus_screen(&self, screen: &ScreenLayout) -> Result<(), VncError> {
+        let rect = Rect {
+            x: screen.x,
+            y: screen.y,
+            width: screen.width,
+            height: screen.height,
+        };
+        let mut inner = self.inner.lock().await;
+        inner.set_clip_region(Some(rect))?;
+        inner.focus = Some(rect);
+        Ok(())
+    }
+
+    /// The desktop name from the ServerInit, or the last [VncEvent::SetDesktopName] received
+    ///
+    /// Bytes that aren't UTF-8 are replaced by U+FFFD rather than failing the connection
+    ///
+    pub async fn server_name(&self) -> String {
+        self.inner.lock().await.name.clone()
+    }
+
+    /// The pixel format the server sends in, the one set last
+    ///
+    pub async fn negotiated_pixel_format(&self) -> PixelFormat {
+        *self.inner.lock().await.pixel_format.borrow()
+    }
+
+    /// The encodings the server has sent something in so far, in the order they were first seen
+    ///
+    /// Raw aside, the server only uses the encodings sent to it, see [VncClient::encodings],
+    ///
+    /// but it is free to skip any of them
+    ///
+    pub async fn used_encodings(&self) -> Vec<VncEncoding> {
+        self.inner
+            .lock()
+            .await
+            .used_encodings
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    /// The pseudo-encodings among [VncClient::used_encodings], i.e. the extensions the server accepted
+    ///
+    pub async fn supported_pseudo_encodings(&self) -> Vec<VncEncoding> {
+        let mut used = self.used_encodings().await;
+        used.retain(|encoding| (*encoding as i32) < 0);
+        used
+    }
+
+    /// How much has been received and decoded so far
+    ///
+    /// Compare two snapshots to get the rates in between
+    ///
+    pub async fn decode_stats(&self) -> DecodeStats {
+        *self.inner.lock().await.stats.lock().unwrap()
+    }
+
+    /// The VeNCrypt subtypes the server offered during the handshake, the unknown ones aside
+    ///
+    /// Empty if VeNCrypt wasn't used, and after [VncClient::from_state]
+    ///
+    pub async fn vencrypt_subtypes_offered(&self) -> Vec<VeNCryptSubtype> {
+        self.inner.lock().await.vencrypt_offered.clone()
+    }
+
+    /// The region set by [VncClient::set_clip_region], if any
+    ///
+    pub async fn clip_region(&self) -> Option<Rect> {
+        *self.inner.lock().await.clip_region.borrow()
+    }
+
+    /// Ask the server to shut down, reboot or reset the machine it shows
+    ///
+    /// Requires [VncEncoding::XvpPseudo], and fails with [VncError::XvpUnavailable]
+    ///
+    /// until the server announced xvp with a [VncEvent::XvpReady]
+    ///
+    /// A refused operation is reported as [VncEvent::XvpFailed]
+    ///
+    pub async fn xvp_operation(&self, op: XvpOperation) -> Result<(), VncError> {
+        self.inner.lock().await.xvp_operation(op).await
+    }
+
+    /// Have the server send every change of the screen without waiting for a request
+    ///
+    /// Requires [VncEncoding::ContinuousUpdatesPseudo], and fails with [VncError::ContinuousUpdatesUnavailable]
+    ///
+    /// until the server announced them with a [VncEvent::EndOfContinuousUpdates],
+    ///
+    /// so a server without them never leaves the screen frozen; keep sending [X11Event::Refresh] then
+    ///
+    pub async fn enable_continuous_updates(&self) -> Result<(), VncError> {
+        self.inner
+            .lock()
+            .await
+            .enable_continuous_updates(true)
+            .await
+    }
+
+    /// Go back to updates on request, confirmed by a [VncEvent::EndOfContinuousUpdates]
+    ///
+    pub async fn disable_continuous_updates(&self) -> Result<(), VncError> {
+        self.inner
+            .lock()
+            .await
+            .enable_continuous_updates(false)
+            .await
+    }
+
+    /// Ask the server to resize the desktop to `width` x `height`, laid out as `screens`
+    ///
+    /// Requires [VncEncoding::ExtendedDesktopSizePseudo], and fails with [VncError::DesktopResizeUnavailable]
+    ///
+    /// until the server announced it, i.e. sent the first [VncEvent::SetScreenLayout]
+    ///
+    /// The new size arrives as a [VncEvent::SetResolution], a refusal as a [VncEvent::DesktopSizeRejected]
+    ///
+    pub async fn set_desktop_size(
+        &self,
+        width: u16,
+        height: u16,
+        screens: &[ScreenLayout],
+    ) -> Result<(), VncError> {
+        self.inner
+            .lock()
+            .await
+            .set_desktop_size(width, height, screens)
+            .await
+    }
+
+    /// Send a FramebufferUpdateRequest for `rect`
+    ///
+    /// With `incremental` the server only sends what changed since the last update,
+    ///
+    /// otherwise the whole area is sent again, e.g. to recover from a decode error
+    ///
+    /// The rect is only a hint, a server may send more than asked for or the whole screen anyway
+    ///
+    /// [X11Event::Refresh] is the incremental request for the whole screen
+    ///
+    pub async fn request_update(&self, rect: Rect, incremental: bool) -> Result<(), VncError> {
+        self.inner
+            .lock()
+            .await
+            .request_update(rect, incremental)
+            .await
+    }
+
+    /// Ask the server to send pixels in `pf` from now on
+    ///
+    /// The decoders switch to the new format at the next server message,
+    ///
+    /// so rectangles that are already on the wire may still be in the old one
+    ///
+    pub async fn set_pixel_format(&self, pf: PixelFormat) -> Result<(), VncError> {
+        self.inner.lock().await.set_pixel_format(pf).await
+    }
+
+    /// The encodings informed to the server, most preferred first
+    ///
+    pub async fn encodings(&self) -> Vec<VncEncoding> {
+        self.inner.lock().await.encodings.clone()
+    }
+
+    /// Inform the server of a new encoding preference order
+    ///
+    /// The server is free to pick any of them for the following updates,
+    ///
+    /// but it usually prefers the earlier ones
+    ///
+    pub async fn set_encoding_priority(&self, encodings: &[VncEncoding]) -> Result<(), VncError> {
+        self.inner.lock().await.set_encodings(encodings).await
+    }
+
+    /// Receive a `VncEvent` from the engine
+    /// This function will block until a `VncEvent` is received
+    ///
+    pub async fn recv_event(&self) -> Result<VncEvent, VncError> {
+        self.inner.lock().await.recv_event().await
+    }
+
+    /// Request the whole screen and wait until every pixel of it has been received
+    ///
+    /// Returns the screen size with the composited image in the current [PixelFormat]
+    ///
+    /// Only the pixels inside [VncClient::set_clip_region] are waited for, the rest stays blank,
+    ///
+    /// as are the [VncEvent::JpegImage] rectangles, which count as received all the same
+    ///
+    /// Unlike a one-shot screenshot the session stays open afterwards,
+    ///
+    /// but the events received in the meantime are consumed by this call
+    ///
+    pub async fn await_first_frame(&self) -> Result<(Screen, Vec<u8>), VncError> {
+        let (mut frame, clip) = self.inner.lock().await.request_first_frame().await?;
+        // locked per event like recv_event, so input can go out between the events
+        while !frame.is_complete() {
+            match self.recv_event().await? {
+                VncEvent::Error(e) => return Err(VncError::General(e)),
+                VncEvent::JpegImage(rect, _) => frame.cover(&rect),
+                // with composite_frames the images only come as frames, which apply just the same
+                e => {
+                    frame.apply(&e);
+                    if let VncEvent::SetResolution(_) = e {
+                        frame.expect_only(clip);
+                    }
+                }
+            }
+        }
+        Ok((frame.dimensions().into(), frame.into_pixels()))
+    }
+
+    /// polling `VncEvent` from the engine and give it to the client
+    ///
+    pub async fn poll_event(&self) -> Result<Option<VncEvent>, VncError> {
+        self.inner.lock().await.poll_event().await
+    }
+
+    /// Wait up to `dur` for a `VncEvent`, `Ok(None)` if none came in time
+    ///
+    /// Rectangles are decoded by a thread of their own and only handed over once complete,
+    ///
+    /// so a timeout never interrupts a half read update, the next call picks up where it left
+    ///
+    /// Other calls on this client wait for the lock meanwhile, as with [VncClient::recv_event]
+    ///
+    pub async fn poll_event_timeout(&self, dur: Duration) -> Result<Option<VncEvent>, VncError> {
+        self.inner.lock().await.poll_event_timeout(dur).await
+    }
+
+    /// Hand the data of a [VncEvent::RawImage] back once it has been drawn
+    ///
+    /// The decoders fill it again for one of the next rectangles,
+    ///
+    /// which spares an allocation per rectangle in the steady state
+    ///
+    pub async fn recycle(&self, data: Vec<u8>) {
+        self.inner.lock().await.pool.give(data)
+    }
+
+    /// Whether the client was closed, or the connection is gone after an EOF or an error
+    ///
+    /// Doesn't wait for the client, so it can be checked from another task
+    ///
+    /// while [VncClient::recv_event] is pending, e.g. for a "connected" indicator
+    ///
+    /// The events that arrived before may still be received
+    ///
+    pub fn is_closed(&self) -> bool {
+        !self.running.load(Ordering::Acquire)
+    }
+
+    /// Release every key and pointer button still held on the server
+    ///
+    /// Sends a key up for each key that went down through [VncClient::input] and hasn't come up yet,
+    ///
+    /// then a pointer event without any button at the last position, if a button is still down
+    ///
+    /// Meant for when the frontend loses the focus, and the key ups would never come
+    ///
+    pub async fn release_all_input(&self) -> Result<(), VncError> {
+        self.inner.lock().await.release_all_input().await
+    }
+
+    /// Whether the server announced QEMU extended key events,
+    ///
+    /// so [X11Event::ExtendedKeyEvent] reaches it with the scancode
+    ///
+    pub async fn supports_extended_key(&self) -> bool {
+        self.inner.lock().await.extended_key.load(Ordering::Acquire)
+    }
+
+    /// Stop the VNC engine and release resources
+    ///
+    /// Whatever is still held is released first, see [VncClient::release_all_input]
+    ///
+    pub async fn close(&self) -> Result<(), VncError> {
+        let mut inner = self.inner.lock().await;
+        let _ = inner.release_all_input().await;
+        inner.close()
+    }
+}
+
+impl Clone for VncClient {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            running: self.running.clone(),
+        }
+    }
+}
+
+/// Cut `event` down to the part inside `clip`, `None` if nothing is left
+fn clip_event(event: VncEvent, clip: &Rect, bpp: usize) -> Option<VncEvent> {
+    match event {
+        VncEvent::RawImage(rect, data) => {
+            let visible = rect.intersect(clip)?;
+            let stride = rect.width as usize * bpp;
+            if (visible.width, visible.height) == (rect.width, rect.height)
+                || data.len() < stride * rect.height as usize
+            {
+                return Some(VncEvent::RawImage(rect, data));
+            }
+            let skip = (visible.x - rect.x) as usize * bpp;
+            let row = visible.width as usize * bpp;
+            let mut cropped = Vec::with_capacity(row * visible.height as usize);
+            for y in (visible.y - rect.y)..(visible.y - rect.y + visible.height) {
+                let start = y as usize * stride + skip;
+                cropped.extend_from_slice(&data[start..start + row]);
+            }
+            Some(VncEvent::RawImage(visible, cropped))
+        }
+        VncEvent::Copy(dst, src) => {
+            let visible = dst.intersect(clip)?;
+            let src = Rect {
+                x: src.x + (visible.x - dst.x),
+                y: src.y + (visible.y - dst.y),
+                width: visible.width,
+                height: visible.height,
+            };
+            Some(VncEvent::Copy(visible, src))
+        }
+        // can't be cut without decoding it
+        VncEvent::JpegImage(rect, data) => {
+            rect.intersect(clip)?;
+            Some(VncEvent::JpegImage(rect, data))
+        }
+        e => Some(e),
+    }
+}
+
+/// The errors of data that can't be decoded, rather than of the connection or a misbehaving server
+#[cfg(feature = "capture")]
+fn is_decode_error(e: &VncError) -> bool {
+    match e {
+        VncError::InvalidImageData | VncError::DecompressionBomb | VncError::Unsupported { .. } => {
+            true
+        }
+        VncError::IoError(e) => e.kind() == ErrorKind::InvalidData,
+        _ => false,
+    }
+}
+
+/// Whether the union of `rects` is the whole of `screen`
+///
+/// Cuts the screen into bands at every top and bottom edge,
+///
+/// each band has to be covered from left to right by the rects spanning it
+fn covers_screen(rects: &[Rect], screen: (u16, u16)) -> bool {
+    let (width, height) = (screen.0 as u32, screen.1 as u32);
+    if width == 0 || height == 0 {
+        return false;
+    }
+    let mut edges: Vec<u32> = rects
+        .iter()
+        .flat_map(|r| [r.y as u32, r.y as u32 + r.height as u32])
+        .filter(|&y| y < height)
+        .chain([0])
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    edges.iter().all(|&top| {
+        let mut spans: Vec<(u32, u32)> = rects
+            .iter()
+            .filter(|r| r.y as u32 <= top && top < r.y as u32 + r.height as u32)
+            .map(|r| (r.x as u32, r.x as u32 + r.width as u32))
+            .collect();
+        spans.sort_unstable();
+        let mut right = 0;
+        for (left, end) in spans {
+            if left > right {
+                break;
+            }
+            right = right.max(end);
+        }
+        right >= width
+    })
+}
+
+async fn send_client_init<S>(stream: &mut S, shared: bool) -> Result<(), VncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    trace!("Send shared flag: {}", shared);
+    stream.write_u8(shared as u8).await?;
+    Ok(())
+}
+
+async fn read_server_init<S, F, Fut>(
+    stream: &mut S,
+    pf: &mut Option<PixelFormat>,
+    always_send_pf: bool,
+    max_name_len: usize,
+    tight_security: bool,
+    output_func: &F,
+) -> Result<(String, (u16, u16)), VncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(VncEvent) -> Fut,
+    Fut: Future<Output = Result<(), VncError>>,
 {
-    // +--------------+--------------+------------------------------+
-    // | No. of bytes | Type [Value] | Description                  |
-    // +--------------+--------------+------------------------------+
-    // | 2            | U16          | framebuffer-width in pixels  |
-    // | 2            | U16          | framebuffer-height in pixels |
-    // | 16           | PIXEL_FORMAT | server-pixel-format          |
-    // | 4            | U32          | name-length                  |
-    // | name-length  | U8 array     | name-string                  |
-    // +--------------+--------------+------------------------------+
-
-    let screen_width = stream.read_u16().await?;
-    let screen_height = stream.read_u16().await?;
-    let mut send_our_pf = false;
+    let ServerInit {
+        framebuffer_width: screen_width,
+        framebuffer_height: screen_height,
+        server_pixel_format: pixel_format,
+        name,
+        ..
+    } = ServerInit::read_with_limit(stream, max_name_len).await?;
+    if tight_security {
+        tight::read_interaction_capabilities(stream).await?;
+    }
+    let mut send_our_pf = always_send_pf;
 
     output_func(VncEvent::SetResolution(
         (screen_width, screen_height).into(),
     ))
     .await?;
 
-    let pixel_format = PixelFormat::read(stream).await?;
     if pf.is_none() {
         output_func(VncEvent::SetPixelFormat(pixel_format)).await?;
         let _ = pf.insert(pixel_format);
@@ -343,11 +1540,6 @@ where
         send_our_pf = true;
     }
 
-    let name_len = stream.read_u32().await?;
-    let mut name_buf = vec![0_u8; name_len as usize];
-    stream.read_exact(&mut name_buf).await?;
-    let name = String::from_utf8_lossy(&name_buf).into_owned();
-
     if send_our_pf {
         trace!("Send customized pixel format {:#?}", pf);
         ClientMsg::SetPixelFormat(*pf.as_ref().unwrap())
@@ -370,7 +1562,10 @@ where
 
 async fn asycn_vnc_read_loop<S, F, Fut>(
     stream: &mut S,
-    pf: &PixelFormat,
+    mut pf_ch: watch::Receiver<PixelFormat>,
+    clip_ch: watch::Receiver<Option<Rect>>,
+    mut colour_map: ColourMap,
+    options: &ReadOptions,
     output_func: &F,
     mut stop_ch: oneshot::Receiver<()>,
 ) -> Result<(), VncError>
@@ -379,91 +1574,496 @@ where
     F: Fn(VncEvent) -> Fut,
     Fut: Future<Output = Result<(), VncError>>,
 {
-    let mut raw_decoder = codec::RawDecoder::new();
-    let mut zrle_decoder = codec::ZrleDecoder::new();
+    let stream = &mut TimeoutReader::new(stream, options.timeout);
+    let mut pf = *pf_ch.borrow_and_update();
+    let mut screen = options.screen;
+    // std's Mutex, as the events are painted from the synchronous part of `rect_output`
+    let framebuffer = std::sync::Mutex::new(options.composite_frames.then(|| {
+        // a resumed session shows its last frame until the full update has repainted it
+        let mut frame = match options.framebuffer.lock().unwrap().take() {
+            Some(mut frame) if frame.dimensions() == screen => {
+                frame.apply(&VncEvent::SetPixelFormat(pf));
+                frame
+            }
+            _ => Framebuffer::with_size(pf, screen.0, screen.1),
+        }
+        .for_frames();
+        if let Some((max_width, max_height)) = options.framebuffer_capacity {
+            frame.reserve(max_width, max_height);
+        }
+        frame
+    }));
+
+    // owned by this loop, so a new connection never inherits the zlib streams of the last one
+    let mut raw_decoder = codec::RawDecoder::new(options.pool.clone());
+    let mut zrle_decoder = codec::ZrleDecoder::new(options.zrlee);
     let mut tight_decoder = codec::TightDecoder::new();
     let mut trle_decoder = codec::TrleDecoder::new();
+    // pending until the first full screen update, then `swap_bytes` tells the verdict
+    let endianness =
+        std::sync::Mutex::new(options.detect_endianness.then(|| EndiannessCheck::new(&pf)));
+    let mut swap_bytes = false;
+    let mut hextile_decoder = codec::HextileDecoder::new();
+    let mut rre_decoder = codec::RreDecoder::new();
     let mut cursor = codec::CursorDecoder::new();
 
     // main decoding loop
     while let Err(oneshot::error::TryRecvError::Empty) = stop_ch.try_recv() {
-        let server_msg = ServerMsg::read(stream).await?;
+        stream.idle();
+        let server_msg = ServerMsg::read(stream, &options.extensions).await?;
         trace!("Server message got: {:?}", server_msg);
+        if pf_ch.has_changed().unwrap_or(false) {
+            // every format-derived value is recomputed from `pf` per rectangle
+            pf = *pf_ch.borrow_and_update();
+            trace!("Pixel format changed to {:?}", pf);
+            if let Some(frame) = framebuffer.lock().unwrap().as_mut() {
+                frame.apply(&VncEvent::SetPixelFormat(pf));
+            }
+            if options.detect_endianness {
+                // a new format gets checked all over again
+                *endianness.lock().unwrap() = Some(EndiannessCheck::new(&pf));
+                swap_bytes = false;
+            }
+        }
         match server_msg {
             ServerMsg::FramebufferUpdate(rect_num) => {
+                #[cfg(not(target_arch = "wasm32"))]
+                let started = std::time::Instant::now();
+                // the header
+                let mut bytes = 4;
+                let mut pixels = 0;
+                // the rectangles replacing pixels, to tell a full redraw
+                let mut painted = Vec::new();
                 for _ in 0..rect_num {
                     let rect = ImageRect::read(stream).await?;
-
-                    match rect.encoding {
-                        VncEncoding::Raw => {
-                            raw_decoder
-                                .decode(pf, &rect.rect, stream, output_func)
-                                .await?;
-                        }
-                        VncEncoding::CopyRect => {
-                            let source_x = stream.read_u16().await?;
-                            let source_y = stream.read_u16().await?;
-                            let mut src_rect = rect.rect;
-                            src_rect.x = source_x;
-                            src_rect.y = source_y;
-                            output_func(VncEvent::Copy(rect.rect, src_rect)).await?;
+                    options.note_used(rect.encoding);
+                    let clip = *clip_ch.borrow();
+                    let bpp = pf.bytes_per_pixel();
+                    let rect_output = &|e| {
+                        let e = match e {
+                            VncEvent::RawImage(rect, mut data) if swap_bytes => {
+                                data.chunks_exact_mut(4).for_each(<[u8]>::reverse);
+                                VncEvent::RawImage(rect, data)
+                            }
+                            e => e,
+                        };
+                        if let (VncEvent::RawImage(_, data), Some(check)) =
+                            (&e, endianness.lock().unwrap().as_mut())
+                        {
+                            check.sample(data);
                         }
-                        VncEncoding::Tight => {
-                            tight_decoder
-                                .decode(pf, &rect.rect, stream, output_func)
-                                .await?;
+                        let e = if options.cursor_only
+                            && matches!(
+                                e,
+                                VncEvent::RawImage(..)
+                                    | VncEvent::Copy(..)
+                                    | VncEvent::JpegImage(..)
+                            ) {
+                            None
+                        } else {
+                            match &clip {
+                                Some(clip) => clip_event(e, clip, bpp),
+                                None => Some(e),
+                            }
+                        };
+                        let e = match (e, framebuffer.lock().unwrap().as_mut()) {
+                            (Some(e), Some(frame)) => frame.composite(e),
+                            (e, _) => e,
+                        };
+                        async move {
+                            match e {
+                                Some(e) => output_func(e).await,
+                                None => Ok(()),
+                            }
                         }
-                        VncEncoding::Trle => {
-                            trle_decoder
-                                .decode(pf, &rect.rect, stream, output_func)
+                    };
+                    let mut stream = Recorder::new(
+                        &mut *stream,
+                        options.keep_encoded || cfg!(feature = "capture"),
+                    );
+                    let stream = &mut stream;
+
+                    // true for the last rectangle
+                    let decoded = async {
+                        match rect.encoding {
+                            VncEncoding::Raw if options.cursor_only => {
+                                let len =
+                                    rect.rect.width as u64 * rect.rect.height as u64 * bpp as u64;
+                                let skipped = tokio::io::copy(
+                                    &mut AsyncReadExt::take(&mut *stream, len),
+                                    &mut tokio::io::sink(),
+                                )
                                 .await?;
-                        }
-                        VncEncoding::Zrle => {
-                            zrle_decoder
-                                .decode(pf, &rect.rect, stream, output_func)
+                                if skipped < len {
+                                    return Err(std::io::Error::from(
+                                        std::io::ErrorKind::UnexpectedEof,
+                                    )
+                                    .into());
+                                }
+                            }
+                            VncEncoding::Raw => {
+                                raw_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::CopyRect => {
+                                let source_x = stream.read_u16().await?;
+                                let source_y = stream.read_u16().await?;
+                                let mut src_rect = rect.rect;
+                                src_rect.x = source_x;
+                                src_rect.y = source_y;
+                                // a resize earlier in this very update may have shrunk the framebuffer
+                                if source_x as u32 + src_rect.width as u32 > screen.0 as u32
+                                    || source_y as u32 + src_rect.height as u32 > screen.1 as u32
+                                {
+                                    error!("CopyRect from {:?} outside of {:?}", src_rect, screen);
+                                    return Err(VncError::RectOutOfBounds(src_rect));
+                                }
+                                rect_output(VncEvent::Copy(rect.rect, src_rect)).await?;
+                            }
+                            VncEncoding::Tight => {
+                                tight_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::Trle => {
+                                trle_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::Rre => {
+                                rre_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::Hextile => {
+                                hextile_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::Zrle => {
+                                zrle_decoder
+                                    .decode(&pf, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::CursorPseudo => {
+                                cursor
+                                    .decode(&pf, &colour_map, &rect.rect, stream, rect_output)
+                                    .await?;
+                            }
+                            VncEncoding::CursorWithAlphaPseudo => {
+                                cursor.decode_alpha(&rect.rect, stream, rect_output).await?;
+                            }
+                            VncEncoding::DesktopSizePseudo => {
+                                screen = (rect.rect.width, rect.rect.height);
+                                rect_output(VncEvent::SetResolution(
+                                    (rect.rect.width, rect.rect.height).into(),
+                                ))
                                 .await?;
+                            }
+                            VncEncoding::ExtendedDesktopSizePseudo => {
+                                let count = stream.read_u8().await?;
+                                let mut padding = [0; 3];
+                                stream.read_exact(&mut padding).await?;
+                                let mut screens = Vec::with_capacity(count as usize);
+                                for _ in 0..count {
+                                    screens.push(ScreenLayout::read(stream).await?);
+                                }
+                                options.desktop_resize.store(true, Ordering::Release);
+                                // the position tells why it was sent and the outcome of a resize we asked for
+                                let (reason, status) = (rect.rect.x, rect.rect.y);
+                                trace!("ExtendedDesktopSize for {} with status {}", reason, status);
+                                if status == 0 {
+                                    // applied before the next rectangle, which may already be in the new size
+                                    screen = (rect.rect.width, rect.rect.height);
+                                    rect_output(VncEvent::SetResolution(
+                                        (rect.rect.width, rect.rect.height).into(),
+                                    ))
+                                    .await?;
+                                    rect_output(VncEvent::SetScreenLayout(screens)).await?;
+                                } else {
+                                    rect_output(VncEvent::DesktopSizeRejected(status)).await?;
+                                }
+                            }
+                            VncEncoding::QemuExtendedKeyEventPseudo => {
+                                trace!("The server takes QEMU extended key events");
+                                options.extended_key.store(true, Ordering::Release);
+                            }
+                            VncEncoding::PointerPosPseudo => {
+                                // the position is the x and y of the header, there is no payload
+                                if options.pointer_pos.load(Ordering::Acquire) {
+                                    rect_output(VncEvent::CursorPosition(rect.rect.x, rect.rect.y))
+                                        .await?;
+                                } else {
+                                    warn!("Pointer position sent without being asked for, ignored");
+                                }
+                            }
+                            VncEncoding::DesktopNamePseudo => {
+                                let name = read_string(stream, options.max_name_len).await?;
+                                rect_output(VncEvent::SetDesktopName(name)).await?;
+                            }
+                            VncEncoding::LastRectPseudo => {
+                                return Ok(true);
+                            }
+                            // settings we inform, never sent back as rectangles
+                            VncEncoding::FencePseudo
+                            | VncEncoding::ContinuousUpdatesPseudo
+                            | VncEncoding::XvpPseudo
+                            | VncEncoding::ExtendedClipboardPseudo
+                            | VncEncoding::JpegQualityLevel2Pseudo
+                            | VncEncoding::JpegQualityLevel6Pseudo
+                            | VncEncoding::JpegQualityLevel9Pseudo
+                            | VncEncoding::CompressLevel1Pseudo
+                            | VncEncoding::CompressLevel9Pseudo
+                            | VncEncoding::JpegFineQualityLevel30Pseudo
+                            | VncEncoding::JpegFineQualityLevel80Pseudo
+                            | VncEncoding::JpegFineQualityLevel95Pseudo
+                            | VncEncoding::JpegSubsampling1XPseudo
+                            | VncEncoding::JpegSubsampling4XPseudo
+                            | VncEncoding::JpegSubsampling2XPseudo => {
+                                return Err(VncError::WrongServerMessage);
+                            }
                         }
-                        VncEncoding::CursorPseudo => {
-                            cursor.decode(pf, &rect.rect, stream, output_func).await?;
+                        Ok(false)
+                    }
+                    // one span per rectangle, for profiling where the decoding time goes
+                    .instrument(trace_span!(
+                        "decode",
+                        encoding = ?rect.encoding,
+                        w = rect.rect.width,
+                        h = rect.rect.height
+                    ))
+                    .await;
+                    let last = match decoded {
+                        Ok(last) => last,
+                        // the bytes of the rectangle so far make a test input
+                        #[cfg(feature = "capture")]
+                        Err(e) if is_decode_error(&e) => {
+                            let payload = stream.take_recorded().unwrap_or_default();
+                            error!(
+                                "Decoding {:?} at {:?} failed after {} bytes",
+                                rect.encoding,
+                                rect.rect,
+                                payload.len()
+                            );
+                            return Err(VncError::DecodeFailed {
+                                encoding: rect.encoding,
+                                rect: rect.rect,
+                                payload,
+                                source: Box::new(e),
+                            });
                         }
-                        VncEncoding::DesktopSizePseudo => {
-                            output_func(VncEvent::SetResolution(
-                                (rect.rect.width, rect.rect.height).into(),
-                            ))
-                            .await?;
+                        Err(e) => return Err(e),
+                    };
+                    if last {
+                        break;
+                    }
+
+                    bytes += 12 + stream.count() as u64;
+                    if (rect.encoding as i32) >= 0 {
+                        pixels += rect.rect.width as u64 * rect.rect.height as u64;
+                        if (options.full_redraws || endianness.lock().unwrap().is_some())
+                            && rect.encoding != VncEncoding::CopyRect
+                        {
+                            painted.push(rect.rect);
                         }
-                        VncEncoding::LastRectPseudo => {
-                            break;
+                    }
+                    if let Some(raw_bytes) = stream.take_recorded().filter(|_| options.keep_encoded)
+                    {
+                        output_func(VncEvent::EncodedRectangle {
+                            rect: rect.rect,
+                            encoding: rect.encoding,
+                            raw_bytes,
+                        })
+                        .await?;
+                    }
+                }
+
+                if options.full_redraws && covers_screen(&painted, screen) {
+                    output_func(VncEvent::FullRedraw).await?;
+                }
+
+                let verdict = match endianness.lock().unwrap().as_ref() {
+                    Some(check) if covers_screen(&painted, screen) => Some(check.swapped()),
+                    _ => None,
+                };
+                if let Some(swapped) = verdict {
+                    *endianness.lock().unwrap() = None;
+                    if swapped {
+                        // what was shown so far is wrong, have it all sent again
+                        warn!("The server sends its pixels byte swapped, swapping them back");
+                        swap_bytes = true;
+                        if let Some(reply_ch) = &options.reply_ch {
+                            let rect = Rect {
+                                x: 0,
+                                y: 0,
+                                width: screen.0,
+                                height: screen.1,
+                            };
+                            reply_ch
+                                .send(ClientMsg::FramebufferUpdateRequest(rect, 0))
+                                .await?;
                         }
                     }
                 }
+
+                // a server may send an empty update as a keepalive, there is nothing to show
+                let frame = match rect_num {
+                    0 => None,
+                    _ => framebuffer.lock().unwrap().as_mut().map(Framebuffer::frame),
+                };
+                if let Some(frame) = frame {
+                    output_func(frame).await?;
+                }
+
+                let mut stats = options.stats.lock().unwrap();
+                stats.updates += 1;
+                stats.bytes += bytes;
+                stats.pixels += pixels;
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    stats.busy += started.elapsed();
+                }
+            }
+            ServerMsg::SetColourMapEntries(first, colors) => {
+                // takes effect from the next rectangle on
+                colour_map.update(first, &colors);
+                output_func(VncEvent::SetColourMap { first, colors }).await?;
             }
             ServerMsg::Bell => {
                 output_func(VncEvent::Bell).await?;
             }
             ServerMsg::ServerCutText(text) => {
+                let text = if options.normalize_newlines {
+                    text.replace("\r\n", "\n")
+                } else {
+                    text
+                };
                 output_func(VncEvent::Text(text)).await?;
             }
-        }
-    }
-    Ok(())
-}
-
-async fn async_connection_process_loop<S>(
-    mut stream: S,
-    mut input_ch: Receiver<ClientMsg>,
-    conn_ch: Sender<std::io::Result<Vec<u8>>>,
-    mut stop_ch: oneshot::Receiver<()>,
-) -> Result<(), VncError>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    let mut buffer = [0; 65535];
-    let mut pending = 0;
-
-    // main traffic loop
-    loop {
-        if pending > 0 {
+            ServerMsg::ExtendedClipboard(flags, payload) => {
+                trace!("Extended clipboard {:#x}", flags);
+                options.note_used(VncEncoding::ExtendedClipboardPseudo);
+                let reply = if flags & clipboard::CAPS != 0 {
+                    options.clipboard.lock().unwrap().server_caps = Some(flags);
+                    Some(clipboard::caps())
+                } else if flags & clipboard::PROVIDE != 0 {
+                    if let Some(text) = clipboard::provided_text(flags, &payload)? {
+                        output_func(VncEvent::Text(text)).await?;
+                    }
+                    None
+                } else if flags & clipboard::NOTIFY != 0 {
+                    // only ask for what we can take
+                    (flags & clipboard::TEXT != 0).then(|| {
+                        ClientMsg::ExtendedClipboard(clipboard::REQUEST | clipboard::TEXT, vec![])
+                    })
+                } else {
+                    let local_text = options.clipboard.lock().unwrap().local_text.clone();
+                    if flags & clipboard::REQUEST != 0 {
+                        match local_text {
+                            Some(text) if flags & clipboard::TEXT != 0 => {
+                                Some(clipboard::provide(&text)?)
+                            }
+                            _ => None,
+                        }
+                    } else if flags & clipboard::PEEK != 0 {
+                        let formats = if local_text.is_some() {
+                            clipboard::TEXT
+                        } else {
+                            0
+                        };
+                        Some(ClientMsg::ExtendedClipboard(
+                            clipboard::NOTIFY | formats,
+                            vec![],
+                        ))
+                    } else {
+                        None
+                    }
+                };
+                if let (Some(reply), Some(reply_ch)) = (reply, &options.reply_ch) {
+                    reply_ch.send(reply).await?;
+                }
+            }
+            ServerMsg::Fence(flags, data) => {
+                options.note_used(VncEncoding::FencePseudo);
+                if flags & fence::REQUEST != 0 {
+                    // everything before has been handled already, so just echo it
+                    trace!("Answer fence {:#x}", flags);
+                    if let Some(reply_ch) = &options.reply_ch {
+                        reply_ch
+                            .send(ClientMsg::Fence(flags & !fence::REQUEST, data))
+                            .await?;
+                    }
+                }
+            }
+            ServerMsg::EndOfContinuousUpdates => {
+                options.note_used(VncEncoding::ContinuousUpdatesPseudo);
+                if !options.continuous_updates.swap(true, Ordering::AcqRel) {
+                    trace!("Continuous updates announced");
+                    if options.auto_continuous_updates {
+                        if let Some(reply_ch) = &options.reply_ch {
+                            let (width, height) = if options.cursor_only { (1, 1) } else { screen };
+                            reply_ch
+                                .send(ClientMsg::EnableContinuousUpdates(
+                                    true,
+                                    Rect {
+                                        x: 0,
+                                        y: 0,
+                                        width,
+                                        height,
+                                    },
+                                ))
+                                .await?;
+                        }
+                    }
+                }
+                output_func(VncEvent::EndOfContinuousUpdates).await?;
+            }
+            ServerMsg::Xvp(version, 1) => {
+                options.note_used(VncEncoding::XvpPseudo);
+                // XVP_INIT, the server speaks every version up to its own
+                let version = version.min(XVP_VERSION);
+                trace!("xvp version {}", version);
+                options.xvp_version.store(version, Ordering::Release);
+                output_func(VncEvent::XvpReady(version)).await?;
+            }
+            ServerMsg::Xvp(_, 0) => {
+                // XVP_FAIL
+                output_func(VncEvent::XvpFailed).await?;
+            }
+            ServerMsg::Xvp(_, code) => {
+                error!("Unexpected xvp message code {}", code);
+                return Err(VncError::WrongServerMessage);
+            }
+            ServerMsg::Extension(msg_type, body) => {
+                output_func(VncEvent::ExtensionMessage(msg_type, body)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn async_connection_process_loop<S>(
+    mut stream: S,
+    mut input_ch: Receiver<ClientMsg>,
+    conn_ch: Sender<std::io::Result<Vec<u8>>>,
+    rate_limit: Option<u64>,
+    update_interval: Option<Duration>,
+    mut stop_ch: oneshot::Receiver<()>,
+) -> Result<(), VncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buffer = [0; 65535];
+    let mut pending = 0;
+    // with a rate limit, when the bytes read so far are paid for
+    let mut paid_until = tokio::time::Instant::now();
+    // the area of the last update request and when it went out
+    let mut last_request: Option<(Rect, tokio::time::Instant)> = None;
+
+    // main traffic loop
+    loop {
+        if pending > 0 {
             match conn_ch.try_send(Ok(buffer[0..pending].to_owned())) {
                 Err(TrySendError::Full(_message)) => (),
                 Err(TrySendError::Closed(_message)) => break,
@@ -471,11 +2071,28 @@ where
             }
         }
 
+        let heartbeat = update_interval
+            .zip(last_request)
+            .map(|(interval, (rect, sent))| (rect, sent + interval));
+        let heartbeat_at = heartbeat.map_or_else(tokio::time::Instant::now, |(_, at)| at);
+
         tokio::select! {
-            _ = &mut stop_ch => break,
-            result = stream.read(&mut buffer), if pending == 0 => {
+            _ = &mut stop_ch => {
+                // what was queued before closing still goes out, e.g. the released keys
+                while let Ok(msg) = input_ch.try_recv() {
+                    msg.write(&mut stream).await?;
+                }
+                stream.flush().await?;
+                break;
+            }
+            _ = tokio::time::sleep_until(paid_until), if paid_until > tokio::time::Instant::now() => (),
+            result = stream.read(&mut buffer), if pending == 0 && paid_until <= tokio::time::Instant::now() => {
                 match result {
                     Ok(nread) => {
+                        if let Some(rate) = rate_limit {
+                            let cost = Duration::from_secs_f64(nread as f64 / rate as f64);
+                            paid_until = paid_until.max(tokio::time::Instant::now()) + cost;
+                        }
                         if nread > 0 {
                             match conn_ch.try_send(Ok(buffer[0..nread].to_owned())) {
                                 Err(TrySendError::Full(_message)) => pending = nread,
@@ -493,8 +2110,19 @@ where
                     }
                 }
             }
+            _ = tokio::time::sleep_until(heartbeat_at), if heartbeat.is_some() => {
+                let (rect, _) = heartbeat.unwrap();
+                trace!("No update requested for a while, ask for {:?}", rect);
+                ClientMsg::FramebufferUpdateRequest(rect, 1).write(&mut stream).await?;
+                stream.flush().await?;
+                last_request = Some((rect, tokio::time::Instant::now()));
+            }
             Some(msg) = input_ch.recv() => {
+                if let ClientMsg::FramebufferUpdateRequest(rect, _) = msg {
+                    last_request = Some((rect, tokio::time::Instant::now()));
+                }
                 msg.write(&mut stream).await?;
+                stream.flush().await?;
             }
         }
     }
@@ -506,3 +2134,1478 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    fn raw_update(width: u16, height: u16, bpp: usize) -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        buf.extend_from_slice(&width.to_be_bytes());
+        buf.extend_from_slice(&height.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.resize(buf.len() + width as usize * height as usize * bpp, 0xab);
+        buf
+    }
+
+    /// The read loop in bgra over a pipe, with the server end of it and the events reported
+    fn spawn_read_loop(options: ReadOptions) -> (DuplexStream, Receiver<VncEvent>) {
+        let (server, event_rx, _) =
+            spawn_read_loop_with(watch::channel(PixelFormat::bgra()).1, options);
+        (server, event_rx)
+    }
+
+    /// [spawn_read_loop] following the formats sent to `pf_rx`, along with how the loop ended
+    fn spawn_read_loop_with(
+        pf_rx: watch::Receiver<PixelFormat>,
+        options: ReadOptions,
+    ) -> (
+        DuplexStream,
+        Receiver<VncEvent>,
+        JoinHandle<Result<(), VncError>>,
+    ) {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let (event_tx, event_rx) = channel(16);
+        let read_loop = spawn(async move {
+            // the loop stops once the sender is gone
+            let (_stop_tx, stop_rx) = oneshot::channel();
+            let output_func = |e| async {
+                event_tx.send(e).await?;
+                Ok(())
+            };
+            asycn_vnc_read_loop(
+                &mut client,
+                pf_rx,
+                watch::channel(None).1,
+                ColourMap::new(),
+                &options,
+                &output_func,
+                stop_rx,
+            )
+            .await
+        });
+        (server, event_rx, read_loop)
+    }
+
+    /// A client made from `config` connected to a 4x3 bgra server named "vnc"
+    ///
+    /// The ClientInit, SetPixelFormat and SetEncodings sent are checked and read off already
+    async fn connected_client(config: ConnectorConfig) -> (DuplexStream, VncClient) {
+        let mut expected = vec![config.allow_shared as u8];
+        let pf = config
+            .pixel_format
+            .or(config.always_send_pixel_format.then(PixelFormat::bgra));
+        if let Some(pf) = pf {
+            ClientMsg::SetPixelFormat(pf)
+                .write(&mut expected)
+                .await
+                .unwrap();
+        }
+        ClientMsg::SetEncodings(config.encodings.clone())
+            .write(&mut expected)
+            .await
+            .unwrap();
+
+        let (mut server, client) = tokio::io::duplex(1 << 16);
+        let mut server_init = vec![0, 4, 0, 3];
+        server_init.extend(Vec::<u8>::from(PixelFormat::bgra()));
+        server_init.extend_from_slice(&[0, 0, 0, 3]);
+        server_init.extend_from_slice(b"vnc");
+        server.write_all(&server_init).await.unwrap();
+        let vnc = VncClient::from_inner(VncInner::new(client, config).await.unwrap());
+
+        let mut sent = vec![0; expected.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, expected);
+        (server, vnc)
+    }
+
+    #[tokio::test]
+    async fn pixel_format_change_between_updates() {
+        let (pf_tx, pf_rx) = watch::channel(PixelFormat::bgra());
+        let (mut server, mut event_rx, _) = spawn_read_loop_with(pf_rx, ReadOptions::default());
+
+        server.write_all(&raw_update(3, 2, 4)).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::RawImage(_, data)) => assert_eq!(data.len(), 3 * 2 * 4),
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        let rgb565 = PixelFormat::rgb565();
+        pf_tx.send_replace(rgb565);
+
+        server.write_all(&raw_update(3, 2, 2)).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::RawImage(_, data)) => assert_eq!(data.len(), 3 * 2 * 2),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_rectangle_times_out() {
+        let (mut server, mut event_rx, read_loop) = spawn_read_loop_with(
+            watch::channel(PixelFormat::bgra()).1,
+            ReadOptions {
+                timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        // half of the pixels, then nothing while the connection stays open
+        let update = raw_update(4, 4, 4);
+        let started = std::time::Instant::now();
+        server.write_all(&update[..update.len() / 2]).await.unwrap();
+        match read_loop.await.unwrap() {
+            Err(VncError::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            e => panic!("unexpected result {e:?}"),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(event_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_between_messages_never_times_out() {
+        let (mut server, mut event_rx, read_loop) = spawn_read_loop_with(
+            watch::channel(PixelFormat::bgra()).1,
+            ReadOptions {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..2 {
+            server.write_all(&raw_update(2, 2, 4)).await.unwrap();
+            assert!(matches!(
+                event_rx.recv().await,
+                Some(VncEvent::RawImage(..))
+            ));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        assert!(!read_loop.is_finished());
+    }
+
+    #[tokio::test]
+    async fn read_timeout_reported_as_an_event() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .set_read_timeout(Duration::from_millis(100))
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let update = raw_update(4, 3, 4);
+        server.write_all(&update[..20]).await.unwrap();
+        loop {
+            match vnc.recv_event().await {
+                Ok(VncEvent::Error(e)) => {
+                    assert_eq!(e, VncError::ReadTimeout.to_string());
+                    break;
+                }
+                Ok(_) => (),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn colour_map_between_updates() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions::default());
+
+        let mut stream = raw_update(2, 2, 4);
+        stream.extend_from_slice(&[1, 0, 0, 3, 0, 2]);
+        stream.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        stream.extend(raw_update(2, 2, 4));
+        server.write_all(&stream).await.unwrap();
+
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(VncEvent::RawImage(..))
+        ));
+        match event_rx.recv().await {
+            Some(VncEvent::SetColourMap { first, colors }) => {
+                assert_eq!(first, 3);
+                assert_eq!(colors, vec![[0xffff, 0, 0], [0, 0xffff, 0]]);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(VncEvent::RawImage(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn init_messages_in_order() {
+        let rgb565 = PixelFormat::rgb565();
+        let encodings = vec![VncEncoding::Tight, VncEncoding::Raw];
+        let config = ConnectorConfig {
+            credentials: Default::default(),
+            rfb_version: crate::VncVersion::RFB38,
+            version_string: None,
+            allow_shared: true,
+            manual_updates: false,
+            keep_encoded: false,
+            cursor_only: false,
+            composite_frames: false,
+            framebuffer_capacity: None,
+            full_redraws: false,
+            zrlee: false,
+            detect_endianness: false,
+            normalize_newlines: false,
+            auto_continuous_updates: false,
+            lenient_security_result: None,
+            security_fallback: false,
+            excluded_security: Vec::new(),
+            security_warning: None,
+            vencrypt_offered: Vec::new(),
+            tight_security: false,
+            extensions: HashMap::new(),
+            pixel_format: Some(rgb565),
+            always_send_pixel_format: false,
+            encodings: encodings.clone(),
+            read_timeout: None,
+            update_interval: None,
+            read_rate_limit: None,
+            max_name_len: DEFAULT_MAX_NAME_LEN,
+            vencrypt_subtype_selector: None,
+            tls_verification: Default::default(),
+            tls_roots: Vec::new(),
+            tls_server_name: None,
+        };
+        let (mut server, client) = tokio::io::duplex(4096);
+
+        let mut server_init = vec![0, 4, 0, 3];
+        server_init.extend(Vec::<u8>::from(PixelFormat::bgra()));
+        server_init.extend_from_slice(&[0, 0, 0, 4]);
+        server_init.extend_from_slice(b"test");
+        server.write_all(&server_init).await.unwrap();
+
+        let _inner = VncInner::new(client, config).await.unwrap();
+
+        let mut expected = vec![1];
+        ClientMsg::SetPixelFormat(rgb565)
+            .write(&mut expected)
+            .await
+            .unwrap();
+        ClientMsg::SetEncodings(encodings)
+            .write(&mut expected)
+            .await
+            .unwrap();
+        ClientMsg::FramebufferUpdateRequest(
+            Rect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 3,
+            },
+            0,
+        )
+        .write(&mut expected)
+        .await
+        .unwrap();
+
+        let mut sent = vec![0; expected.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[tokio::test]
+    async fn server_format_sent_back() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .always_send_pixel_format(true)
+            .add_encoding(VncEncoding::Raw)
+            .manual_updates(true)
+            .config;
+        // no format was set, so the server's own bgra is expected back
+        connected_client(config).await;
+    }
+
+    #[tokio::test]
+    async fn poll_timeout_resumes() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        let wait = Duration::from_millis(20);
+        // the resolution and the pixel format from the init
+        while vnc.poll_event_timeout(wait).await.unwrap().is_some() {}
+
+        // half an update, then the rest once the poll timed out
+        let update = raw_update(2, 2, 4);
+        let (head, tail) = update.split_at(update.len() / 2);
+        server.write_all(head).await.unwrap();
+        assert!(vnc.poll_event_timeout(wait).await.unwrap().is_none());
+        server.write_all(tail).await.unwrap();
+        match vnc.poll_event_timeout(Duration::from_secs(5)).await {
+            Ok(Some(VncEvent::RawImage(rect, pixels))) => {
+                assert_eq!((rect.width, rect.height), (2, 2));
+                assert_eq!(pixels.len(), 16);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn encoding_priority_changed() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Tight)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let reordered = [
+            VncEncoding::Raw,
+            VncEncoding::Tight,
+            VncEncoding::PointerPosPseudo,
+        ];
+        vnc.set_encoding_priority(&reordered).await.unwrap();
+        let mut expected = Vec::new();
+        ClientMsg::SetEncodings(reordered.to_vec())
+            .write(&mut expected)
+            .await
+            .unwrap();
+        let mut sent = vec![0; expected.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, expected);
+        assert_eq!(vnc.encodings().await, reordered);
+
+        // nothing goes out, and the last list stays
+        assert!(matches!(
+            vnc.set_encoding_priority(&[]).await,
+            Err(VncError::NoEncoding)
+        ));
+        assert_eq!(vnc.encodings().await, reordered);
+        vnc.close().await.unwrap();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        // nothing held, nothing to release on close
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn continuous_updates_need_the_server() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::ContinuousUpdatesPseudo)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        assert!(matches!(
+            vnc.enable_continuous_updates().await,
+            Err(VncError::ContinuousUpdatesUnavailable)
+        ));
+
+        server.write_all(&[150]).await.unwrap();
+        loop {
+            if let VncEvent::EndOfContinuousUpdates = vnc.recv_event().await.unwrap() {
+                break;
+            }
+        }
+        vnc.enable_continuous_updates().await.unwrap();
+
+        let mut sent = [0; 10];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, [150, 1, 0, 0, 0, 0, 0, 4, 0, 3]);
+    }
+
+    #[tokio::test]
+    async fn text_sessions_turn_continuous_updates_on() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .optimize_for_text()
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        // announced twice, turned on once
+        server.write_all(&[150, 150]).await.unwrap();
+        let mut announced = 0;
+        while announced < 2 {
+            if let VncEvent::EndOfContinuousUpdates = vnc.recv_event().await.unwrap() {
+                announced += 1;
+            }
+        }
+        let mut sent = [0; 10];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, [150, 1, 0, 0, 0, 0, 0, 4, 0, 3]);
+
+        vnc.close().await.unwrap();
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn held_input_released_on_close() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        for event in [
+            X11Event::KeyEvent((0xffe3, true).into()),
+            X11Event::KeyEvent((0x61, true).into()),
+            X11Event::KeyEvent((0x61, false).into()),
+            X11Event::PointerEvent((3, 4, 1).into()),
+        ] {
+            vnc.input(event).await.unwrap();
+        }
+        vnc.close().await.unwrap();
+
+        let mut expected = Vec::new();
+        for msg in [
+            ClientMsg::KeyEvent(0xffe3, true),
+            ClientMsg::KeyEvent(0x61, true),
+            ClientMsg::KeyEvent(0x61, false),
+            ClientMsg::PointerEvent(3, 4, 1),
+            ClientMsg::KeyEvent(0xffe3, false),
+            ClientMsg::PointerEvent(3, 4, 0),
+        ] {
+            msg.write(&mut expected).await.unwrap();
+        }
+
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[tokio::test]
+    async fn mixed_messages_in_order() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions::default());
+
+        // an update of two rectangles, bell, cut text and update again, all in a single write
+        let mut stream = raw_update(2, 1, 4);
+        stream[3] = 2;
+        stream.extend_from_slice(&raw_update(1, 1, 4)[4..]);
+        stream.push(2);
+        stream.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 3]);
+        stream.extend_from_slice(b"r\xe9b");
+        stream.extend(raw_update(1, 2, 4));
+        server.write_all(&stream).await.unwrap();
+
+        for size in [(2, 1), (1, 1)] {
+            match event_rx.recv().await {
+                Some(VncEvent::RawImage(rect, data)) => {
+                    assert_eq!((rect.width, rect.height), size);
+                    assert_eq!(data, vec![0xab; size.0 as usize * size.1 as usize * 4]);
+                }
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::Bell)));
+        match event_rx.recv().await {
+            Some(VncEvent::Text(text)) => assert_eq!(text, "r\u{e9}b"),
+            e => panic!("unexpected event {e:?}"),
+        }
+        match event_rx.recv().await {
+            Some(VncEvent::RawImage(rect, _)) => assert_eq!((rect.width, rect.height), (1, 2)),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_rectangle_follows_decoded() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions {
+            keep_encoded: true,
+            ..Default::default()
+        });
+
+        server.write_all(&raw_update(2, 1, 4)).await.unwrap();
+
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(VncEvent::RawImage(..))
+        ));
+        match event_rx.recv().await {
+            Some(VncEvent::EncodedRectangle {
+                rect,
+                encoding,
+                raw_bytes,
+            }) => {
+                assert_eq!((rect.width, rect.height), (2, 1));
+                assert_eq!(encoding, VncEncoding::Raw);
+                assert_eq!(raw_bytes, vec![0xab; 8]);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[test]
+    fn full_screen_coverage() {
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        let tiles = [
+            rect(0, 0, 64, 16),
+            rect(0, 16, 40, 16),
+            rect(32, 16, 32, 20),
+            rect(0, 30, 32, 10),
+        ];
+        assert!(covers_screen(&tiles, (64, 36)));
+        assert!(!covers_screen(&tiles, (64, 40)));
+        assert!(!covers_screen(&tiles[1..], (64, 36)));
+        assert!(covers_screen(&[rect(0, 0, 800, 600)], (800, 600)));
+        assert!(!covers_screen(&[], (800, 600)));
+    }
+
+    #[test]
+    fn clip_raw_image() {
+        let rect = Rect {
+            x: 10,
+            y: 10,
+            width: 3,
+            height: 2,
+        };
+        let clip = Rect {
+            x: 11,
+            y: 11,
+            width: 100,
+            height: 100,
+        };
+        let event = clip_event(VncEvent::RawImage(rect, (0..6).collect()), &clip, 1);
+        match event {
+            Some(VncEvent::RawImage(rect, data)) => {
+                assert_eq!((rect.x, rect.y, rect.width, rect.height), (11, 11, 2, 1));
+                assert_eq!(data, vec![4, 5]);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        let outside = Rect {
+            x: 200,
+            y: 200,
+            width: 1,
+            height: 1,
+        };
+        let event = clip_event(VncEvent::RawImage(outside, vec![0]), &clip, 1);
+        assert!(event.is_none());
+    }
+
+    #[tokio::test]
+    async fn fence_is_echoed() {
+        let (reply_tx, mut reply_rx) = channel(16);
+        let (mut server, _event_rx) = spawn_read_loop(ReadOptions {
+            reply_ch: Some(reply_tx),
+            ..Default::default()
+        });
+
+        let mut msg = vec![248, 0, 0, 0];
+        msg.extend_from_slice(&(fence::REQUEST | fence::BLOCK_BEFORE).to_be_bytes());
+        msg.extend_from_slice(&[2, 0xbe, 0xef]);
+        server.write_all(&msg).await.unwrap();
+
+        match reply_rx.recv().await {
+            Some(ClientMsg::Fence(flags, data)) => {
+                assert_eq!(flags, fence::BLOCK_BEFORE);
+                assert_eq!(data, vec![0xbe, 0xef]);
+            }
+            m => panic!("unexpected reply {m:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn swapped_pixels_are_detected() {
+        let (reply_tx, mut reply_rx) = channel(16);
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions {
+            detect_endianness: true,
+            reply_ch: Some(reply_tx),
+            screen: (2, 1),
+            ..Default::default()
+        });
+
+        // bgra keeps its free byte last, this server puts it first
+        let mut update = raw_update(2, 1, 4);
+        update.truncate(update.len() - 8);
+        update.extend_from_slice(&[0, 10, 20, 30, 0, 40, 50, 60]);
+        server.write_all(&update).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::RawImage(_, data)) => assert_eq!(data, [0, 10, 20, 30, 0, 40, 50, 60]),
+            e => panic!("unexpected event {e:?}"),
+        }
+        match reply_rx.recv().await {
+            Some(ClientMsg::FramebufferUpdateRequest(rect, 0)) => {
+                assert_eq!((rect.x, rect.y, rect.width, rect.height), (0, 0, 2, 1))
+            }
+            m => panic!("unexpected reply {m:?}"),
+        }
+
+        server.write_all(&update).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::RawImage(_, data)) => assert_eq!(data, [30, 20, 10, 0, 60, 50, 40, 0]),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[test]
+    fn plausible_pixels_are_kept() {
+        let mut check = EndiannessCheck::new(&PixelFormat::bgra());
+        check.sample(&[10, 20, 30, 0, 40, 50, 60, 0]);
+        assert!(!check.swapped());
+        // a single colour tells nothing
+        let mut check = EndiannessCheck::new(&PixelFormat::bgra());
+        check.sample(&[0, 10, 20, 0, 0, 10, 20, 0]);
+        assert!(!check.swapped());
+    }
+
+    #[tokio::test]
+    async fn resize_applies_before_next_update() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .add_encoding(VncEncoding::ExtendedDesktopSizePseudo)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .composite_frames(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let layout = |reason: u16, width: u16, height: u16| {
+            let mut update = vec![0, 0, 0, 1];
+            for n in [reason, 0, width, height] {
+                update.extend_from_slice(&n.to_be_bytes());
+            }
+            update.extend_from_slice(&(-308_i32).to_be_bytes());
+            update.extend_from_slice(&[1, 0, 0, 0]);
+            update.extend_from_slice(
+                &ScreenLayout {
+                    id: 7,
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                    flags: 0,
+                }
+                .to_bytes(),
+            );
+            update
+        };
+
+        // the server announces its layout
+        server.write_all(&layout(0, 4, 3)).await.unwrap();
+        let mut screen = loop {
+            if let VncEvent::SetScreenLayout(screens) = vnc.recv_event().await.unwrap() {
+                break screens[0];
+            }
+        };
+        screen.width = 1920;
+        screen.height = 1080;
+        vnc.set_desktop_size(1920, 1080, &[screen]).await.unwrap();
+        let mut request = Vec::new();
+        ClientMsg::SetDesktopSize(1920, 1080, vec![screen])
+            .write(&mut request)
+            .await
+            .unwrap();
+        let mut sent = vec![0; request.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, request);
+
+        // accepted, then a full screen update at the new size right after
+        let mut updates = layout(1, 1920, 1080);
+        updates.extend(raw_update(1920, 1080, 4));
+        let writer = spawn(async move {
+            server.write_all(&updates).await.unwrap();
+            server
+        });
+        let mut resized = false;
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::SetResolution(screen) => {
+                    assert_eq!((screen.width, screen.height), (1920, 1080));
+                    resized = true;
+                }
+                VncEvent::Frame {
+                    framebuffer_slice, ..
+                } if resized && framebuffer_slice.iter().all(|&b| b == 0xab) => {
+                    assert_eq!(framebuffer_slice.len(), 1920 * 1080 * 4);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        drop(writer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejected_resize() {
+        let options = ReadOptions {
+            screen: (4, 3),
+            ..Default::default()
+        };
+        let desktop_resize = options.desktop_resize.clone();
+        let (mut server, mut event_rx) = spawn_read_loop(options);
+
+        // asked by this client, an invalid layout, no screens
+        let mut update = vec![0, 0, 0, 1, 0, 1, 0, 3, 0, 4, 0, 3];
+        update.extend_from_slice(&(-308_i32).to_be_bytes());
+        update.extend_from_slice(&[0, 0, 0, 0]);
+        server.write_all(&update).await.unwrap();
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(VncEvent::DesktopSizeRejected(3))
+        ));
+        assert!(desktop_resize.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn extended_clipboard_flow() {
+        let (reply_tx, mut reply_rx) = channel(16);
+        let options = ReadOptions {
+            reply_ch: Some(reply_tx),
+            ..Default::default()
+        };
+        let shared = options.clipboard.clone();
+        let (mut server, mut event_rx) = spawn_read_loop(options);
+        let extended = |flags: u32, payload: &[u8]| {
+            let mut msg = vec![3, 0, 0, 0];
+            msg.extend_from_slice(&(-(payload.len() as i32 + 4)).to_be_bytes());
+            msg.extend_from_slice(&flags.to_be_bytes());
+            msg.extend_from_slice(payload);
+            msg
+        };
+
+        // the caps are answered with ours
+        let caps = clipboard::CAPS | clipboard::TEXT | clipboard::REQUEST | clipboard::NOTIFY;
+        server
+            .write_all(&extended(caps, &1024_u32.to_be_bytes()))
+            .await
+            .unwrap();
+        match reply_rx.recv().await {
+            Some(ClientMsg::ExtendedClipboard(flags, _)) => {
+                assert_eq!(
+                    flags & (clipboard::CAPS | clipboard::TEXT),
+                    clipboard::CAPS | clipboard::TEXT
+                )
+            }
+            m => panic!("unexpected reply {m:?}"),
+        }
+        assert_eq!(shared.lock().unwrap().server_caps, Some(caps));
+
+        // notified text is requested, then reported once provided
+        server
+            .write_all(&extended(clipboard::NOTIFY | clipboard::TEXT, &[]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            reply_rx.recv().await,
+            Some(ClientMsg::ExtendedClipboard(flags, _))
+                if flags == clipboard::REQUEST | clipboard::TEXT
+        ));
+        let ClientMsg::ExtendedClipboard(flags, payload) =
+            clipboard::provide("h\u{e9}llo\n\u{4e16}\u{754c}").unwrap()
+        else {
+            unreachable!()
+        };
+        server.write_all(&extended(flags, &payload)).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::Text(text)) => assert_eq!(text, "h\u{e9}llo\n\u{4e16}\u{754c}"),
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        // our text is provided on request
+        shared.lock().unwrap().local_text = Some("caf\u{e9}".to_string());
+        server
+            .write_all(&extended(clipboard::REQUEST | clipboard::TEXT, &[]))
+            .await
+            .unwrap();
+        match reply_rx.recv().await {
+            Some(ClientMsg::ExtendedClipboard(flags, payload)) => assert_eq!(
+                clipboard::provided_text(flags, &payload).unwrap(),
+                Some("caf\u{e9}".to_string())
+            ),
+            m => panic!("unexpected reply {m:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn xvp_handshake() {
+        let options = ReadOptions::default();
+        let xvp_version = options.xvp_version.clone();
+        let (mut server, mut event_rx) = spawn_read_loop(options);
+
+        assert_eq!(xvp_version.load(Ordering::Acquire), 0);
+        // a newer server settles for our version
+        server
+            .write_all(&[250, 0, 2, 1, 250, 0, 1, 0])
+            .await
+            .unwrap();
+
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::XvpReady(1))));
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::XvpFailed)));
+        assert_eq!(xvp_version.load(Ordering::Acquire), 1);
+    }
+
+    #[cfg(feature = "capture")]
+    #[tokio::test]
+    async fn failed_rectangle_is_captured() {
+        let (mut server, _event_rx, read_loop) = spawn_read_loop_with(
+            watch::channel(PixelFormat::bgra()).1,
+            ReadOptions::default(),
+        );
+
+        // a 4x4 RRE rectangle with a subrect reaching beyond it
+        let mut rre = vec![0, 0, 0, 1, 1, 2, 3, 4];
+        rre.extend_from_slice(&[5, 6, 7, 8, 0, 2, 0, 0, 0, 4, 0, 1]);
+        let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 4, 0, 4, 0, 0, 0, 2];
+        msg.extend_from_slice(&rre);
+        server.write_all(&msg).await.unwrap();
+
+        match read_loop.await.unwrap() {
+            Err(VncError::DecodeFailed {
+                encoding,
+                rect,
+                payload,
+                source,
+            }) => {
+                assert_eq!(encoding, VncEncoding::Rre);
+                assert_eq!((rect.width, rect.height), (4, 4));
+                assert_eq!(payload, rre);
+                assert!(matches!(*source, VncError::InvalidImageData));
+            }
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_source_outside_resized_screen() {
+        let (mut server, mut event_rx, read_loop) = spawn_read_loop_with(
+            watch::channel(PixelFormat::bgra()).1,
+            ReadOptions {
+                screen: (100, 100),
+                ..Default::default()
+            },
+        );
+
+        let copy = |src: u16| {
+            let mut rect = vec![0, 0, 0, 0, 0, 10, 0, 10, 0, 0, 0, 1];
+            rect.extend_from_slice(&src.to_be_bytes());
+            rect.extend_from_slice(&src.to_be_bytes());
+            rect
+        };
+        // fine within the initial screen
+        let mut msg = vec![0, 0, 0, 1];
+        msg.extend(copy(80));
+        // then shrink it to 50x50 and copy from where it used to be
+        msg.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 50, 0, 50, 0xff, 0xff, 0xff, 0x21]);
+        msg.extend(copy(80));
+        server.write_all(&msg).await.unwrap();
+
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::Copy(..))));
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(VncEvent::SetResolution(_))
+        ));
+        assert!(matches!(
+            read_loop.await.unwrap(),
+            Err(VncError::RectOutOfBounds(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cursor_only_skips_pixels() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions {
+            cursor_only: true,
+            pointer_pos: Arc::new(AtomicBool::new(true)),
+            ..Default::default()
+        });
+
+        let mut msg = raw_update(4, 4, 4);
+        msg[3] = 2;
+        msg.extend_from_slice(&[0, 7, 0, 9, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x18]);
+        server.write_all(&msg).await.unwrap();
+
+        match event_rx.recv().await {
+            Some(VncEvent::CursorPosition(7, 9)) => (),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn desktop_name_is_capped() {
+        let (mut server, mut event_rx, read_loop) = spawn_read_loop_with(
+            watch::channel(PixelFormat::bgra()).1,
+            ReadOptions {
+                max_name_len: 8,
+                ..Default::default()
+            },
+        );
+
+        let name = |name: &[u8]| {
+            let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xfe, 0xcd];
+            msg.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            msg.extend_from_slice(name);
+            msg
+        };
+        server.write_all(&name(b"desktop")).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::SetDesktopName(name)) => assert_eq!(name, "desktop"),
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        // only the length is sent, nothing is allocated for it
+        server.write_all(&name(b"")[..16]).await.unwrap();
+        server.write_u32(u32::MAX).await.unwrap();
+        assert!(matches!(
+            read_loop.await.unwrap(),
+            Err(VncError::OversizedMessage(len)) if len == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn one_frame_per_update() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions {
+            composite_frames: true,
+            screen: (2, 2),
+            ..Default::default()
+        });
+
+        // the top row in raw, then copied to the bottom one
+        let mut msg = vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0];
+        msg.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        msg.extend_from_slice(&[0, 0, 0, 1, 0, 2, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0]);
+        server.write_all(&msg).await.unwrap();
+
+        match event_rx.recv().await {
+            Some(VncEvent::Frame {
+                dirty_rects,
+                framebuffer_slice,
+            }) => {
+                assert_eq!(dirty_rects.len(), 2);
+                assert_eq!(
+                    *framebuffer_slice,
+                    vec![1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8]
+                );
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_update_is_a_keepalive() {
+        let options = ReadOptions {
+            composite_frames: true,
+            screen: (2, 1),
+            ..Default::default()
+        };
+        let stats = options.stats.clone();
+        let (mut server, mut event_rx) = spawn_read_loop(options);
+
+        let mut msg = vec![0, 0, 0, 0];
+        msg.extend(raw_update(2, 1, 4));
+        // the stats are in once the next message is handled
+        msg.push(2);
+        server.write_all(&msg).await.unwrap();
+
+        match event_rx.recv().await {
+            Some(VncEvent::Frame { dirty_rects, .. }) => assert_eq!(dirty_rects.len(), 1),
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::Bell)));
+        let stats = *stats.lock().unwrap();
+        assert_eq!(stats.updates, 2);
+        assert_eq!(stats.bytes, 4 + 4 + 12 + 8);
+    }
+
+    #[tokio::test]
+    async fn reads_are_paced() {
+        let (mut server, client) = tokio::io::duplex(1024);
+        let (_input_tx, input_rx) = channel(1);
+        let (conn_tx, mut conn_rx) = channel(64);
+        let (_stop_tx, stop_rx) = oneshot::channel();
+        spawn(async_connection_process_loop(
+            client,
+            input_rx,
+            conn_tx,
+            Some(100_000),
+            None,
+            stop_rx,
+        ));
+
+        let started = std::time::Instant::now();
+        spawn(async move { server.write_all(&[0; 20_000]).await });
+        let mut received = 0;
+        while received < 20_000 {
+            received += conn_rx.recv().await.unwrap().unwrap().len();
+        }
+        // 0.2s at that rate, minus the last read which is paid for afterwards
+        assert!(started.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[tokio::test]
+    async fn focused_screen_narrows_requests() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let right = Rect {
+            x: 2,
+            y: 0,
+            width: 2,
+            height: 3,
+        };
+        vnc.focus_screen(&ScreenLayout {
+            id: 1,
+            x: 2,
+            y: 0,
+            width: 2,
+            height: 3,
+            flags: 0,
+        })
+        .await
+        .unwrap();
+        vnc.input(X11Event::Refresh).await.unwrap();
+        vnc.clear_clip_region().await.unwrap();
+        vnc.input(X11Event::Refresh).await.unwrap();
+
+        let mut expected = Vec::new();
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 3,
+        };
+        for rect in [right, whole] {
+            ClientMsg::FramebufferUpdateRequest(rect, 1)
+                .write(&mut expected)
+                .await
+                .unwrap();
+        }
+        let mut requests = vec![0; expected.len()];
+        server.read_exact(&mut requests).await.unwrap();
+        assert_eq!(requests, expected);
+    }
+
+    #[tokio::test]
+    async fn zrle_stream_restarts_per_session() {
+        use flate2::{Compress, Compression, FlushCompress};
+
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Zrle)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+
+        // the same config connects twice, as a reconnect would
+        for color in [[10, 20, 30], [40, 50, 60]] {
+            let (mut server, vnc) = connected_client(config.clone()).await;
+
+            // a new zlib stream for this session, carried over both rectangles
+            let mut zlib = Compress::new(Compression::default(), true);
+            let mut update = vec![0, 0, 0, 2];
+            for y in 0..2_u16 {
+                for n in [0, y, 4, 1] {
+                    update.extend_from_slice(&n.to_be_bytes());
+                }
+                update.extend_from_slice(&16_i32.to_be_bytes());
+                // a single solid tile
+                let mut compressed = Vec::with_capacity(64);
+                zlib.compress_vec(
+                    &[&[1][..], &color].concat(),
+                    &mut compressed,
+                    FlushCompress::Sync,
+                )
+                .unwrap();
+                update.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+                update.extend_from_slice(&compressed);
+            }
+            server.write_all(&update).await.unwrap();
+
+            let mut images = 0;
+            while images < 2 {
+                match vnc.recv_event().await.unwrap() {
+                    VncEvent::RawImage(_, data) => {
+                        for pixel in data.chunks_exact(4) {
+                            assert_eq!(pixel[..3], color);
+                        }
+                        images += 1;
+                    }
+                    VncEvent::SetResolution(_) | VncEvent::SetPixelFormat(_) => {}
+                    e => panic!("unexpected event {e:?}"),
+                }
+            }
+            vnc.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn clipboard_newlines_normalized() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .normalize_clipboard_newlines(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        server
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, 4, b'a', b'\r', b'\n', b'b'])
+            .await
+            .unwrap();
+        loop {
+            if let VncEvent::Text(text) = vnc.recv_event().await.unwrap() {
+                assert_eq!(text, "a\nb");
+                break;
+            }
+        }
+
+        // an existing CRLF isn't doubled
+        vnc.copy_text("c\nd\r\ne").await.unwrap();
+        let mut expected = Vec::new();
+        ClientMsg::ClientCutText("c\r\nd\r\ne".to_string())
+            .write(&mut expected)
+            .await
+            .unwrap();
+        let mut cut_text = vec![0; expected.len()];
+        server.read_exact(&mut cut_text).await.unwrap();
+        assert_eq!(cut_text, expected);
+    }
+
+    #[tokio::test]
+    async fn idle_update_requests() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .set_update_interval(Some(Duration::from_millis(100)))
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let mut request = Vec::new();
+        ClientMsg::FramebufferUpdateRequest(
+            Rect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 3,
+            },
+            1,
+        )
+        .write(&mut request)
+        .await
+        .unwrap();
+        let mut received = vec![0; request.len()];
+
+        // nothing goes out before the first request
+        let idle = tokio::time::timeout(Duration::from_millis(250), server.read_u8()).await;
+        assert!(idle.is_err());
+
+        let started = tokio::time::Instant::now();
+        vnc.input(X11Event::Refresh).await.unwrap();
+        server.read_exact(&mut received).await.unwrap();
+        // a refresh halfway through restarts the wait
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        vnc.input(X11Event::Refresh).await.unwrap();
+        server.read_exact(&mut received).await.unwrap();
+
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, request);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        server.read_exact(&mut received).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn first_frame_within_clip_region() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        let left = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 3,
+        };
+        vnc.set_clip_region(left).await.unwrap();
+        let first_frame = spawn({
+            let vnc = vnc.clone();
+            async move { vnc.await_first_frame().await }
+        });
+        let mut request = [0; 10];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, [3, 0, 0, 0, 0, 0, 0, 4, 0, 3]);
+
+        // only the left half is sent, the rest would be clipped away anyway
+        let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 3, 0, 0, 0, 0];
+        update.extend_from_slice(&[7; 2 * 3 * 4]);
+        server.write_all(&update).await.unwrap();
+        let (screen, pixels) = first_frame.await.unwrap().unwrap();
+        assert_eq!((screen.width, screen.height), (4, 3));
+        for (y, row) in pixels.chunks_exact(4 * 4).enumerate() {
+            assert_eq!(row[..8], [7; 8], "row {y}");
+            assert_eq!(row[8..], [0; 8], "row {y}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_hotspot_from_header() {
+        let (mut server, mut event_rx) = spawn_read_loop(ReadOptions::default());
+
+        // a 2x1 cursor with its hotspot at (1, 0), then an alpha one at (5, 7)
+        let mut update = vec![0, 0, 0, 2];
+        for n in [1_u16, 0, 2, 1] {
+            update.extend_from_slice(&n.to_be_bytes());
+        }
+        update.extend_from_slice(&(-239_i32).to_be_bytes());
+        update.extend_from_slice(&[1, 2, 3, 0, 4, 5, 6, 0, 0b0100_0000]);
+        for n in [5_u16, 7, 1, 1] {
+            update.extend_from_slice(&n.to_be_bytes());
+        }
+        update.extend_from_slice(&(-314_i32).to_be_bytes());
+        update.extend_from_slice(&[0, 0, 0, 0, 10, 20, 30, 255]);
+        server.write_all(&update).await.unwrap();
+
+        match event_rx.recv().await {
+            Some(VncEvent::SetCursor(rect, image, hotspot)) => {
+                assert_eq!((rect.width, rect.height, hotspot), (2, 1, (1, 0)));
+                assert_eq!(image, [3, 2, 1, 0, 6, 5, 4, 255]);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+        match event_rx.recv().await {
+            Some(VncEvent::SetCursor(_, image, hotspot)) => {
+                assert_eq!(hotspot, (5, 7));
+                assert_eq!(image, [10, 20, 30, 255]);
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn pointer_position_needs_the_encoding() {
+        let options = ReadOptions::default();
+        let pointer_pos = options.pointer_pos.clone();
+        let (mut server, mut event_rx) = spawn_read_loop(options);
+
+        let position = |x: u8, y: u8| {
+            let mut update = vec![0, 0, 0, 1, 0, x, 0, y, 0, 0, 0, 0];
+            update.extend_from_slice(&(-232_i32).to_be_bytes());
+            update
+        };
+        // not advertised, so only the bell comes through
+        server.write_all(&position(3, 4)).await.unwrap();
+        server.write_all(&[2]).await.unwrap();
+        assert!(matches!(event_rx.recv().await, Some(VncEvent::Bell)));
+
+        pointer_pos.store(true, Ordering::Release);
+        server.write_all(&position(5, 6)).await.unwrap();
+        match event_rx.recv().await {
+            Some(VncEvent::CursorPosition(5, 6)) => (),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn extended_keys_need_the_server() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .add_encoding(VncEncoding::QemuExtendedKeyEventPseudo)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+
+        // right control
+        let key = X11Event::ExtendedKeyEvent {
+            down: true,
+            keysym: 0xffe4,
+            keycode: 0x9d,
+        };
+        assert!(!vnc.supports_extended_key().await);
+        vnc.input(key.clone()).await.unwrap();
+        let mut expected = Vec::new();
+        ClientMsg::KeyEvent(0xffe4, true)
+            .write(&mut expected)
+            .await
+            .unwrap();
+        let mut received = vec![0; expected.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        update.extend_from_slice(&(-258_i32).to_be_bytes());
+        server.write_all(&update).await.unwrap();
+        while !vnc.supports_extended_key().await {
+            tokio::task::yield_now().await;
+        }
+        vnc.input(key).await.unwrap();
+        let mut received = [0; 12];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [255, 0, 0, 1, 0, 0, 0xff, 0xe4, 0, 0, 0, 0x9d]);
+    }
+
+    #[tokio::test]
+    async fn capabilities_after_connect() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Zrle)
+            .add_encoding(VncEncoding::Raw)
+            .add_encoding(VncEncoding::DesktopSizePseudo)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        assert_eq!(vnc.server_name().await, "vnc");
+        assert_eq!(
+            Vec::<u8>::from(vnc.negotiated_pixel_format().await),
+            Vec::<u8>::from(PixelFormat::bgra())
+        );
+
+        // a resize to 1x1, then a raw pixel
+        let mut update = vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 1, 0, 1];
+        update.extend_from_slice(&(-223_i32).to_be_bytes());
+        update.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+        update.extend_from_slice(&[1, 2, 3, 0]);
+        server.write_all(&update).await.unwrap();
+        while !matches!(vnc.recv_event().await.unwrap(), VncEvent::RawImage(..)) {}
+
+        assert_eq!(
+            vnc.used_encodings().await,
+            [VncEncoding::DesktopSizePseudo, VncEncoding::Raw]
+        );
+        assert_eq!(
+            vnc.supported_pseudo_encodings().await,
+            [VncEncoding::DesktopSizePseudo]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_init_name_is_kept() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, client) = tokio::io::duplex(1 << 16);
+
+        // a Latin-1 name, which isn't valid UTF-8
+        let name = b"b\xfcro";
+        let mut server_init = vec![0, 4, 0, 3];
+        server_init.extend(Vec::<u8>::from(PixelFormat::bgra()));
+        server_init.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        server_init.extend_from_slice(name);
+        server.write_all(&server_init).await.unwrap();
+        let vnc = VncClient::from_inner(VncInner::new(client, config).await.unwrap());
+        assert_eq!(vnc.server_name().await, "b\u{fffd}ro");
+        assert_eq!(vnc.export_state().await.name, "b\u{fffd}ro");
+    }
+
+    #[tokio::test]
+    async fn resumed_session_keeps_the_settings() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .register_server_message(200, 2)
+            .config;
+        let (_server, vnc) = connected_client(config).await;
+        let state = vnc.export_state().await;
+        vnc.close().await.unwrap();
+
+        let (mut server, client) = tokio::io::duplex(1 << 16);
+        let vnc = VncClient::from_state(client, state).await.unwrap();
+        // SetPixelFormat, SetEncodings with Raw, then a full update request
+        let mut resume = [0; 20 + 8 + 10];
+        server.read_exact(&mut resume).await.unwrap();
+        assert_eq!(resume[20..28], [2, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(resume[28..], [3, 0, 0, 0, 0, 0, 0, 4, 0, 3]);
+
+        // only known through register_server_message
+        server.write_all(&[200, 1, 2]).await.unwrap();
+        match vnc.recv_event().await.unwrap() {
+            VncEvent::ExtensionMessage(msg_type, body) => {
+                assert_eq!((msg_type, body), (200, vec![1, 2]))
+            }
+            e => panic!("unexpected {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn resumed_session_keeps_what_the_server_announced() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .add_encoding(VncEncoding::ContinuousUpdatesPseudo)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        server.write_all(&[150]).await.unwrap();
+        loop {
+            if let VncEvent::EndOfContinuousUpdates = vnc.recv_event().await.unwrap() {
+                break;
+            }
+        }
+        let state = vnc.export_state().await;
+        assert!(state.continuous_updates);
+        assert!(!state.desktop_resize);
+        vnc.close().await.unwrap();
+
+        let (mut server, client) = tokio::io::duplex(1 << 16);
+        let vnc = VncClient::from_state(client, state).await.unwrap();
+        let mut resume = [0; 20 + 12 + 10];
+        server.read_exact(&mut resume).await.unwrap();
+
+        // not announced again, still known
+        vnc.enable_continuous_updates().await.unwrap();
+        let mut sent = [0; 10];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, [150, 1, 0, 0, 0, 0, 0, 4, 0, 3]);
+    }
+
+    #[tokio::test]
+    async fn resumed_session_keeps_the_frame() {
+        let config = crate::VncConnector::new(tokio::io::duplex(1).0)
+            .add_encoding(VncEncoding::Raw)
+            .set_pixel_format(PixelFormat::bgra())
+            .manual_updates(true)
+            .composite_frames(true)
+            .config;
+        let (mut server, vnc) = connected_client(config).await;
+        server.write_all(&raw_update(4, 3, 4)).await.unwrap();
+        loop {
+            if let VncEvent::Frame { .. } = vnc.recv_event().await.unwrap() {
+                break;
+            }
+        }
+        let state = vnc.export_state().await;
+        vnc.close().await.unwrap();
+        let pixels = state.framebuffer.as_ref().unwrap().pixels();
+        assert_eq!(pixels, [0xab; 4 * 3 * 4]);
+
+        #[cfg(feature = "serde")]
+        let state: SessionState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let (mut server, client) = tokio::io::duplex(1 << 16);
+        let vnc = VncClient::from_state(client, state).await.unwrap();
+        let mut resume = [0; 20 + 8 + 10];
+        server.read_exact(&mut resume).await.unwrap();
+
+        // a single pixel painted over the last frame
+        let mut update = raw_update(1, 1, 4);
+        update[16..].copy_from_slice(&[1, 2, 3, 0]);
+        server.write_all(&update).await.unwrap();
+        match vnc.recv_event().await.unwrap() {
+            VncEvent::Frame {
+                framebuffer_slice, ..
+            } => {
+                assert_eq!(framebuffer_slice[..4], [1, 2, 3, 0]);
+                assert_eq!(framebuffer_slice[4..], [0xab; 4 * 3 * 4 - 4]);
+            }
+            e => panic!("unexpected {e:?}"),
+        }
+    }
+}
@@ -1,7 +1,11 @@
+pub mod adaptive;
 pub mod auth;
 pub mod builder;
 pub mod connection;
+mod recorder;
+mod security;
+mod timeout;
 
 pub use auth::Credentials;
 pub use builder::VncConnector;
-pub use connection::VncClient;
+pub use connection::{DecodeStats, SessionState, VncClient};
@@ -0,0 +1,50 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Counts the bytes read through it and keeps a copy of them, if asked to
+pub(super) struct Recorder<S> {
+    inner: S,
+    count: usize,
+    recorded: Option<Vec<u8>>,
+}
+
+impl<S> Recorder<S> {
+    pub(super) fn new(inner: S, record: bool) -> Self {
+        Self {
+            inner,
+            count: 0,
+            recorded: record.then(Vec::new),
+        }
+    }
+
+    /// How many bytes were read so far
+    pub(super) fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The bytes read so far, `None` if not recording
+    pub(super) fn take_recorded(&mut self) -> Option<Vec<u8>> {
+        self.recorded.take()
+    }
+}
+
+impl<S> AsyncRead for Recorder<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.count += buf.filled().len() - filled;
+        if let (Poll::Ready(Ok(())), Some(recorded)) = (&result, &mut this.recorded) {
+            recorded.extend_from_slice(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
@@ -0,0 +1,260 @@
+use crate::protocol::security::types::AuthResult;
+use crate::protocol::text::read_reason;
+use crate::VncError;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use crypto_bigint::modular::{BoxedMontyForm, BoxedMontyParams};
+use crypto_bigint::{BoxedUint, Odd};
+use md5::{Digest, Md5};
+use rustls::ClientConfig;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::error;
+
+/// Longest Diffie-Hellman key accepted, macOS sends 128 bytes
+const MAX_KEY_LEN: usize = 1024;
+
+/// Bytes of the username and of the password once encrypted, each NUL terminated
+const CREDENTIAL_LEN: usize = 64;
+
+/// Apple Remote Desktop authentication, security type 30
+///
+/// The server sends its Diffie-Hellman parameters, the client answers with the username
+///
+/// and password encrypted by AES-128 under the MD5 of the shared secret, then its own public key
+///
+pub(crate) struct ArdAuth {
+    generator: u16,
+    prime: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ArdAuth {
+    pub(crate) async fn read<S>(reader: &mut S) -> Result<Self, VncError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +--------------+--------------+-------------------+
+        // | No. of bytes | Type [Value] | Description       |
+        // +--------------+--------------+-------------------+
+        // | 2            | U16          | generator         |
+        // | 2            | U16          | key-length        |
+        // | key-length   | U8 array     | prime modulus     |
+        // | key-length   | U8 array     | server public key |
+        // +--------------+--------------+-------------------+
+        let generator = reader.read_u16().await?;
+        let key_len = reader.read_u16().await? as usize;
+        if key_len > MAX_KEY_LEN {
+            return Err(VncError::OversizedMessage(key_len));
+        }
+        let mut prime = vec![0; key_len];
+        reader.read_exact(&mut prime).await?;
+        let mut server_key = vec![0; key_len];
+        reader.read_exact(&mut server_key).await?;
+        Ok(Self {
+            generator,
+            prime,
+            server_key,
+        })
+    }
+
+    pub(crate) async fn write<S>(
+        &self,
+        writer: &mut S,
+        username: &str,
+        password: &str,
+    ) -> Result<(), VncError>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut private_key = vec![0; self.prime.len()];
+        let mut filler = [0; 2 * CREDENTIAL_LEN];
+        let random = ClientConfig::builder().crypto_provider().secure_random;
+        random
+            .fill(&mut private_key)
+            .and_then(|_| random.fill(&mut filler))
+            .map_err(|_| VncError::General("No random numbers for the key exchange".to_string()))?;
+        let response = self.response(&private_key, &filler, username, password)?;
+        writer.write_all(&response).await?;
+        Ok(())
+    }
+
+    /// Our public key and the shared secret, as many bytes as the prime
+    ///
+    /// The exponentiations run in constant time, in the private key as well as the server key
+    fn exchange(&self, private_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), VncError> {
+        let key_len = self.prime.len();
+        let bits = key_len as u32 * 8;
+        let unusable = |what: &str| VncError::General(format!("Unusable Diffie-Hellman {what}"));
+        let prime = BoxedUint::from_be_slice(&self.prime, bits).map_err(|_| unusable("modulus"))?;
+        let prime: Option<Odd<BoxedUint>> = Odd::new(prime).into();
+        let prime = prime.ok_or_else(|| unusable("modulus"))?;
+        let params = BoxedMontyParams::new(prime);
+        let precision = params.bits_precision();
+        let prime = params.modulus().as_ref();
+
+        let one = BoxedUint::one_with_precision(precision);
+        let generator = BoxedUint::from_be_slice(&self.generator.to_be_bytes(), precision)
+            .map_err(|_| unusable("generator"))?;
+        let server_key =
+            BoxedUint::from_be_slice(&self.server_key, precision).map_err(|_| unusable("key"))?;
+        // 0, 1 and p - 1 would give away the shared secret
+        let max = prime.wrapping_sub(&one);
+        if generator <= one || generator >= max {
+            error!("ARD generator {} out of range", self.generator);
+            return Err(unusable("generator"));
+        }
+        if server_key <= one || server_key >= max {
+            error!("ARD server key out of range");
+            return Err(unusable("key"));
+        }
+
+        let private_key = BoxedUint::from_be_slice(private_key, private_key.len() as u32 * 8)
+            .map_err(|_| unusable("private key"))?;
+        let pow = |base: BoxedUint| {
+            let bytes = BoxedMontyForm::new(base, params.clone())
+                .pow(&private_key)
+                .retrieve()
+                .to_be_bytes();
+            bytes[bytes.len() - key_len..].to_vec()
+        };
+        Ok((pow(generator), pow(server_key)))
+    }
+
+    /// The encrypted credentials followed by our public key
+    ///
+    /// `filler` pads the credentials after their NUL terminators
+    fn response(
+        &self,
+        private_key: &[u8],
+        filler: &[u8; 2 * CREDENTIAL_LEN],
+        username: &str,
+        password: &str,
+    ) -> Result<Vec<u8>, VncError> {
+        let (public_key, shared) = self.exchange(private_key)?;
+        let cipher = Aes128::new(&Md5::digest(shared));
+
+        let mut credentials = *filler;
+        for (field, value) in credentials
+            .chunks_exact_mut(CREDENTIAL_LEN)
+            .zip([username, password])
+        {
+            // longer ones are cut, to keep the terminator
+            let len = value.len().min(CREDENTIAL_LEN - 1);
+            field[..len].copy_from_slice(&value.as_bytes()[..len]);
+            field[len] = 0;
+        }
+        for block in credentials.chunks_exact_mut(16) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        let mut response = credentials.to_vec();
+        response.extend_from_slice(&public_key);
+        Ok(response)
+    }
+}
+
+/// Go through the whole of ARD once the security type is chosen, SecurityResult included
+pub(crate) async fn authenticate<S>(
+    stream: &mut S,
+    username: &str,
+    password: &str,
+    lenient: Option<Duration>,
+) -> Result<(), VncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let auth = ArdAuth::read(stream).await?;
+    auth.write(stream, username, password).await?;
+    if let AuthResult::Failed = AuthResult::read(stream, lenient).await? {
+        // only 3.8 has security types to choose from, so there is always a reason
+        let reason = read_reason(stream).await?;
+        error!("Apple Remote Desktop auth failed: {}", reason);
+        return Err(VncError::WrongPassword(Some(reason)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn key_exchange() {
+        // the 1024 bits MODP group of RFC2409, with fixed keys
+        let auth = ArdAuth {
+            generator: 2,
+            prime: hex(concat!(
+                "ffffffffffffffffc90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74",
+                "020bbea63b139b22514a08798e3404ddef9519b3cd3a431b302b0a6df25f1437",
+                "4fe1356d6d51c245e485b576625e7ec6f44c42e9a637ed6b0bff5cb6f406b7ed",
+                "ee386bfb5a899fa5ae9f24117c4b1fe649286651ece65381ffffffffffffffff",
+            )),
+            server_key: hex(concat!(
+                "457120764f3a1e6fd58103e41a4093a6c8bc1d97cb8759de41c21afdd2d3048a",
+                "5ef3d88ce24aa6ba4fe30bcfb0b0f75abf1a8aeaff3723f1bf53740c902005e1",
+                "199fabad7c538e94a7034fd585339a02f3634893f748929d2a7257643e398130",
+                "541ae64124c17d4507a97f1cbebeb7b933642b8df479eb59e36cfeffbf1671dd",
+            )),
+        };
+        let filler: [u8; 128] = std::array::from_fn(|i| i as u8);
+        let response = auth
+            .response(&[0x5a; 32], &filler, "alice", "secret")
+            .unwrap();
+
+        let (_, shared) = auth.exchange(&[0x5a; 32]).unwrap();
+        assert_eq!(
+            Md5::digest(shared)[..],
+            hex("7ecf1cf23fa96f524a56082ef162c22c")[..]
+        );
+        let expected = hex(concat!(
+            "cd22eac6587c84b60a0196d7b78f87c0ebcb434d1334f1f5379d69d3a2881613",
+            "3da6eaa3292d816b84c06494158f137aecb1172228d0465a5ed8b9e4c0246681",
+            "d33310628c1d112f90df39e30175b2cd2f86c6f49e30c8817deb49e34117d990",
+            "6bdd2c50b41557caac4c21330e91f406a7c782c88cfde3f0e745f92cf4009fca",
+            // our public key
+            "b1a3992ff238b7fbf85c2680a90ef15240878ba66c7d7019e0d92fd144b96e2a",
+            "346cdd746285ccf0bc811f769f62589e535a979650ec9f4a74f1215d0149b3a6",
+            "2d2db9a4defea782ca2b5669fbc86e0b74ba7b97b853f4c8dd6166a805dbc06f",
+            "b1219ba8f634defceec34a4ea3090b314c3095a926d4b7d66d9c166b6d7828b2",
+        ));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn server_key_out_of_range() {
+        let prime = 0xffff_fffb_u32;
+        for server_key in [0, 1, prime - 1, prime, u32::MAX] {
+            let auth = ArdAuth {
+                generator: 5,
+                prime: prime.to_be_bytes().to_vec(),
+                server_key: server_key.to_be_bytes().to_vec(),
+            };
+            assert!(
+                auth.exchange(&[0x5a; 4]).is_err(),
+                "server key {server_key:#x}"
+            );
+        }
+        let auth = ArdAuth {
+            generator: 5,
+            prime: prime.to_be_bytes().to_vec(),
+            server_key: 2_u32.to_be_bytes().to_vec(),
+        };
+        assert!(auth.exchange(&[0x5a; 4]).is_ok());
+        // an even modulus is no prime
+        let auth = ArdAuth {
+            generator: 5,
+            prime: 0xffff_fffa_u32.to_be_bytes().to_vec(),
+            server_key: 2_u32.to_be_bytes().to_vec(),
+        };
+        assert!(auth.exchange(&[0x5a; 4]).is_err());
+    }
+}
@@ -0,0 +1,2 @@
+#[cfg(feature = "ard")]
+pub(crate) mod ard;
@@ -0,0 +1,70 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+
+/// Fails a read that makes no progress within the timeout
+///
+/// The clock only runs once a message has started to arrive,
+///
+/// call [TimeoutReader::idle] at message boundaries to stop it again
+pub(super) struct TimeoutReader<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    in_message: bool,
+}
+
+impl<S> TimeoutReader<S> {
+    pub(super) fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: None,
+            in_message: false,
+        }
+    }
+
+    pub(super) fn idle(&mut self) {
+        self.in_message = false;
+        self.sleep = None;
+    }
+}
+
+impl<S> AsyncRead for TimeoutReader<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.sleep = None;
+                if buf.filled().len() > filled {
+                    this.in_message = true;
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if let (true, Some(timeout)) = (this.in_message, this.timeout) {
+                    let sleep = this
+                        .sleep
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        this.sleep = None;
+                        return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use crate::{PixelFormat, Rect, VncError, VncEvent};
+use crate::protocol::{convert::PixelConverter, ColourMap};
+use crate::{PixelFormat, Rect, VncEncoding, VncError, VncEvent};
 use std::future::Future;
 use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::error;
 
-use super::uninit_vec;
+use super::zeroed_vec;
 
 pub struct Decoder {}
 
@@ -14,6 +16,7 @@ impl Decoder {
     pub async fn decode<S, F, Fut>(
         &mut self,
         format: &PixelFormat,
+        colour_map: &ColourMap,
         rect: &Rect,
         input: &mut S,
         output_func: &F,
@@ -23,58 +26,209 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let _hotx = rect.x;
-        let _hoty = rect.y;
-        let w = rect.width;
-        let h = rect.height;
-
-        let pixels_length = w as usize * h as usize * format.bits_per_pixel as usize / 8;
-        let mask_length = (w as usize).div_ceil(8) * h as usize;
-
-        let _bytes = pixels_length + mask_length;
+        // +----------------------------+--------------+-------------+
+        // | No. of bytes               | Type [Value] | Description |
+        // +----------------------------+--------------+-------------+
+        // | width*height*bytesPerPixel | PIXEL array  | cursor      |
+        // | div(width+7,8)*height      | U8 array     | bitmask     |
+        // +----------------------------+--------------+-------------+
+        let w = rect.width as usize;
+        let h = rect.height as usize;
+        if w == 0 || h == 0 {
+            // an empty cursor hides the pointer, with neither pixels nor mask sent
+            output_func(VncEvent::SetCursor(*rect, Vec::new(), (rect.x, rect.y))).await?;
+            return Ok(());
+        }
+        // every row of the mask starts at a new byte
+        let mask_stride = w.div_ceil(8);
 
-        let mut pixels = uninit_vec(pixels_length);
+        let mut pixels = zeroed_vec(w * h * format.bytes_per_pixel());
         input.read_exact(&mut pixels).await?;
-        let mut mask = uninit_vec(mask_length);
+        let mut mask = zeroed_vec(mask_stride * h);
         input.read_exact(&mut mask).await?;
-        let mut image = uninit_vec(pixels_length);
-        let mut pix_idx = 0;
-
-        let pixel_mask = (format.red_max as u32) << format.red_shift
-            | (format.green_max as u32) << format.green_shift
-            | (format.blue_max as u32) << format.blue_shift;
-
-        let mut alpha_idx = match pixel_mask {
-            0xff_ff_ff_00 => 3,
-            0xff_ff_00_ff => 2,
-            0xff_00_ff_ff => 1,
-            0x00_ff_ff_ff => 0,
-            _ => unreachable!(),
+
+        let mut image = if format.true_color_flag == 0 {
+            colour_map.to_rgba(format, &pixels)
+        } else {
+            PixelConverter::new(format, &PixelFormat::rgba())?.convert(&pixels)
         };
-        if format.big_endian_flag == 0 {
-            alpha_idx = 3 - alpha_idx;
+        for (y, row) in image.chunks_exact_mut(w * 4).enumerate() {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let opaque = mask[y * mask_stride + x / 8] & (0x80 >> (x % 8)) > 0;
+                pixel[3] = if opaque { 255 } else { 0 };
+            }
+        }
+
+        output_func(VncEvent::SetCursor(*rect, image, (rect.x, rect.y))).await?;
+
+        Ok(())
+    }
+
+    /// The cursor of [crate::VncEncoding::CursorWithAlphaPseudo]
+    pub async fn decode_alpha<S, F, Fut>(
+        &mut self,
+        rect: &Rect,
+        input: &mut S,
+        output_func: &F,
+    ) -> Result<(), VncError>
+    where
+        S: AsyncRead + Unpin,
+        F: Fn(VncEvent) -> Fut,
+        Fut: Future<Output = Result<(), VncError>>,
+    {
+        // +----------------------------+--------------+-------------+
+        // | No. of bytes               | Type [Value] | Description |
+        // +----------------------------+--------------+-------------+
+        // | 4                          | S32          | encoding    |
+        // | width*height*4             | RGBA array   | cursor      |
+        // +----------------------------+--------------+-------------+
+        // always 32bpp RGBA with premultiplied alpha, whatever the pixel format
+        let encoding = input.read_i32().await?;
+        if encoding != VncEncoding::Raw as i32 {
+            error!("Alpha cursor in encoding {}", encoding);
+            return Err(VncError::Unsupported {
+                encoding: VncEncoding::CursorWithAlphaPseudo,
+                reason: "only Raw cursor data is decoded",
+            });
         }
-        for y in 0..h as usize {
-            for x in 0..w as usize {
-                let mask_idx = y * (w as usize).div_ceil(8) + (x / 8);
-                let alpha = if (mask[mask_idx] << (x % 8)) & 0x80 > 0 {
-                    255
-                } else {
-                    0
-                };
-                image[pix_idx] = pixels[pix_idx];
-                image[pix_idx + 1] = pixels[pix_idx + 1];
-                image[pix_idx + 2] = pixels[pix_idx + 2];
-                image[pix_idx + 3] = pixels[pix_idx + 3];
-
-                // use alpha from the bitmask to cover it.
-                image[pix_idx + alpha_idx] = alpha;
-                pix_idx += 4;
+        let mut image = zeroed_vec(rect.width as usize * rect.height as usize * 4);
+        input.read_exact(&mut image).await?;
+        for pixel in image.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            for c in &mut pixel[..3] {
+                if let Some(straight) = (*c as u32 * 255).checked_div(alpha) {
+                    *c = straight.min(255) as u8;
+                }
             }
         }
 
-        output_func(VncEvent::SetCursor(*rect, image)).await?;
+        output_func(VncEvent::SetCursor(*rect, image, (rect.x, rect.y))).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    async fn decode(format: &PixelFormat, width: u16, data: &[u8]) -> Vec<u8> {
+        let cursor = Mutex::new(Vec::new());
+        let output = |e| {
+            let VncEvent::SetCursor(_, image, _) = e else {
+                panic!("unexpected event {e:?}")
+            };
+            *cursor.lock().unwrap() = image;
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width,
+            height: 2,
+        };
+        let mut input = data;
+        Decoder::new()
+            .decode(format, &ColourMap::new(), &rect, &mut input, &output)
+            .await
+            .unwrap();
+        assert!(input.is_empty(), "{} bytes left", input.len());
+        cursor.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn mask_rows_are_padded() {
+        // 10 pixels wide, so each row of the mask takes 2 bytes
+        let mut data = vec![0x40; 10 * 2 * 4];
+        data.extend_from_slice(&[0b1010_0000, 0b0100_0000, 0b0000_0001, 0b1000_0000]);
+
+        let image = decode(&PixelFormat::bgra(), 10, &data).await;
+        let alpha: Vec<_> = image.chunks_exact(4).map(|p| p[3]).collect();
+        let opaque = [0, 2, 9, 17, 18];
+        for (n, alpha) in alpha.into_iter().enumerate() {
+            assert_eq!(alpha, if opaque.contains(&n) { 255 } else { 0 }, "{n}");
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_cursor_hides_the_pointer() {
+        let cursor = Mutex::new(None);
+        let output = |e| {
+            *cursor.lock().unwrap() = Some(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+        // the next message follows right away
+        let mut input: &[u8] = &[2];
+        Decoder::new()
+            .decode(
+                &PixelFormat::bgra(),
+                &ColourMap::new(),
+                &rect,
+                &mut input,
+                &output,
+            )
+            .await
+            .unwrap();
+        assert_eq!(input, [2]);
+        match cursor.into_inner().unwrap() {
+            Some(VncEvent::SetCursor(rect, image, _)) => {
+                assert_eq!((rect.width, rect.height), (0, 0));
+                assert!(image.is_empty());
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rgb565_to_rgba() {
+        let rgb565 = PixelFormat::rgb565();
+        // pure red, then pure blue in the second row
+        let mut data = [0xf800_u16.to_le_bytes(), 0x001f_u16.to_le_bytes()].concat();
+        data.extend_from_slice(&[0x80, 0x00]);
+
+        let image = decode(&rgb565, 1, &data).await;
+        assert_eq!(image, vec![255, 0, 0, 255, 0, 0, 255, 0]);
+    }
+
+    #[tokio::test]
+    async fn alpha_cursor_is_unpremultiplied() {
+        let cursor = Mutex::new(None);
+        let output = |e| {
+            *cursor.lock().unwrap() = Some(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 3,
+            y: 4,
+            width: 2,
+            height: 1,
+        };
+        // Raw, then a half transparent red and a transparent pixel
+        let data = [0, 0, 0, 0, 128, 0, 0, 128, 0, 0, 0, 0];
+        Decoder::new()
+            .decode_alpha(&rect, &mut data.as_slice(), &output)
+            .await
+            .unwrap();
+        match cursor.into_inner().unwrap() {
+            Some(VncEvent::SetCursor(_, image, hotspot)) => {
+                assert_eq!(image, [255, 0, 0, 128, 0, 0, 0, 0]);
+                assert_eq!(hotspot, (3, 4));
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        // anything but Raw can't be followed
+        let data = [0, 0, 0, 7];
+        let result = Decoder::new()
+            .decode_alpha(&rect, &mut data.as_slice(), &|_| async { Ok(()) })
+            .await;
+        assert!(matches!(result, Err(VncError::Unsupported { .. })));
+    }
+}
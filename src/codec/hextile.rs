@@ -0,0 +1,256 @@
+use crate::{PixelFormat, Rect, VncError, VncEvent};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::error;
+
+// the bits of the subencoding mask
+const RAW: u8 = 1;
+const BACKGROUND_SPECIFIED: u8 = 1 << 1;
+const FOREGROUND_SPECIFIED: u8 = 1 << 2;
+const ANY_SUBRECTS: u8 = 1 << 3;
+const SUBRECTS_COLOURED: u8 = 1 << 4;
+
+/// The tiles are 16x16, the ones at the right and bottom edges may be smaller
+const TILE_SIZE: u16 = 16;
+
+async fn read_pixel<S>(reader: &mut S, bpp: usize) -> Result<[u8; 4], VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut pixel = [0; 4];
+    reader.read_exact(&mut pixel[..bpp]).await?;
+    Ok(pixel)
+}
+
+pub struct Decoder {}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub async fn decode<S, F, Fut>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output_func: &F,
+    ) -> Result<(), VncError>
+    where
+        S: AsyncRead + Unpin,
+        F: Fn(VncEvent) -> Fut,
+        Fut: Future<Output = Result<(), VncError>>,
+    {
+        let bpp = format.bytes_per_pixel();
+        // both carried over to the next tiles unless specified again
+        let mut background = None;
+        let mut foreground = None;
+
+        let mut y = 0;
+        while y < rect.height {
+            let height = (rect.height - y).min(TILE_SIZE);
+            let mut x = 0;
+            while x < rect.width {
+                let width = (rect.width - x).min(TILE_SIZE);
+                let pixel_count = height as usize * width as usize;
+
+                let subencoding = input.read_u8().await?;
+                let pixels = if subencoding & RAW > 0 {
+                    // the other bits are irrelevant
+                    let mut pixels = vec![0; pixel_count * bpp];
+                    input.read_exact(&mut pixels).await?;
+                    pixels
+                } else {
+                    if subencoding & BACKGROUND_SPECIFIED > 0 {
+                        background = Some(read_pixel(input, bpp).await?);
+                    }
+                    if subencoding & FOREGROUND_SPECIFIED > 0 {
+                        foreground = Some(read_pixel(input, bpp).await?);
+                    }
+                    let Some(background) = background else {
+                        error!("Hextile background used before any was sent");
+                        return Err(VncError::InvalidImageData);
+                    };
+                    let mut pixels = background[..bpp].repeat(pixel_count);
+
+                    let subrects = if subencoding & ANY_SUBRECTS > 0 {
+                        input.read_u8().await?
+                    } else {
+                        0
+                    };
+                    for _ in 0..subrects {
+                        let colour = if subencoding & SUBRECTS_COLOURED > 0 {
+                            read_pixel(input, bpp).await?
+                        } else {
+                            match foreground {
+                                Some(foreground) => foreground,
+                                None => {
+                                    error!("Hextile foreground used before any was sent");
+                                    return Err(VncError::InvalidImageData);
+                                }
+                            }
+                        };
+                        // x, y and width - 1, height - 1 in nibbles
+                        let position = input.read_u8().await?;
+                        let size = input.read_u8().await?;
+                        let (sx, sy) = ((position >> 4) as usize, (position & 0xf) as usize);
+                        let (sw, sh) = ((size >> 4) as usize + 1, (size & 0xf) as usize + 1);
+                        if sx + sw > width as usize || sy + sh > height as usize {
+                            error!(
+                                "Hextile subrect {:?} beyond the tile at {:?}",
+                                (sx, sy, sw, sh),
+                                (x, y)
+                            );
+                            return Err(VncError::InvalidImageData);
+                        }
+                        for row in sy..sy + sh {
+                            let start = (row * width as usize + sx) * bpp;
+                            for pixel in pixels[start..start + sw * bpp].chunks_exact_mut(bpp) {
+                                pixel.copy_from_slice(&colour[..bpp]);
+                            }
+                        }
+                    }
+                    pixels
+                };
+
+                output_func(VncEvent::RawImage(
+                    Rect {
+                        x: rect.x + x,
+                        y: rect.y + y,
+                        width,
+                        height,
+                    },
+                    pixels,
+                ))
+                .await?;
+                x += width;
+            }
+            y += height;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Decode `data` and paint the tiles into one image of `width` x `height` 16bpp pixels
+    async fn decode(width: u16, height: u16, data: &[u8]) -> Result<Vec<u16>, VncError> {
+        let tiles = Mutex::new(Vec::new());
+        let output = |e| {
+            tiles.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut input = data;
+        Decoder::new()
+            .decode(&PixelFormat::rgb565(), &rect, &mut input, &output)
+            .await?;
+        assert!(input.is_empty(), "{} bytes left", input.len());
+
+        let mut image = vec![0; width as usize * height as usize];
+        for e in tiles.into_inner().unwrap() {
+            let VncEvent::RawImage(tile, pixels) = e else {
+                panic!("unexpected event {e:?}")
+            };
+            assert_eq!(pixels.len(), tile.width as usize * tile.height as usize * 2);
+            for (n, pixel) in pixels.chunks_exact(2).enumerate() {
+                let x = tile.x as usize + n % tile.width as usize;
+                let y = tile.y as usize + n / tile.width as usize;
+                image[y * width as usize + x] = u16::from_le_bytes([pixel[0], pixel[1]]);
+            }
+        }
+        Ok(image)
+    }
+
+    #[tokio::test]
+    async fn raw_edge_tiles() {
+        // 20x18 makes tiles of 16x16, 4x16, 16x2 and 4x2, all of them raw
+        let (width, height) = (20_u16, 18_u16);
+        let pixel = |x: u16, y: u16| y * 100 + x;
+        let mut data = Vec::new();
+        for ty in (0..height).step_by(16) {
+            for tx in (0..width).step_by(16) {
+                data.push(RAW);
+                for y in ty..height.min(ty + 16) {
+                    for x in tx..width.min(tx + 16) {
+                        data.extend_from_slice(&pixel(x, y).to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        let image = decode(width, height, &data).await.unwrap();
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(
+                    image[y as usize * width as usize + x as usize],
+                    pixel(x, y),
+                    "at {:?}",
+                    (x, y)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subrects_and_carried_colours() {
+        let mut data = Vec::new();
+        // background 1 and foreground 2, with a 2x2 subrect at (14, 0)
+        data.push(BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED | ANY_SUBRECTS);
+        data.extend_from_slice(&1_u16.to_le_bytes());
+        data.extend_from_slice(&2_u16.to_le_bytes());
+        data.extend_from_slice(&[1, 0xe0, 0x11]);
+        // both carried over: a 1x1 subrect at (0, 0) in the foreground
+        data.extend_from_slice(&[ANY_SUBRECTS, 1, 0x00, 0x00]);
+        // the background carried over again, with a coloured subrect over the 3x2 edge tile
+        data.extend_from_slice(&[ANY_SUBRECTS | SUBRECTS_COLOURED, 1]);
+        data.extend_from_slice(&3_u16.to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x21]);
+
+        let image = decode(35, 2, &data).await.unwrap();
+        for y in 0..2 {
+            for x in 0..35 {
+                let expected = match x {
+                    14..=15 => 2,
+                    16 if y == 0 => 2,
+                    32.. => 3,
+                    _ => 1,
+                };
+                assert_eq!(image[y * 35 + x], expected, "at {:?}", (x, y));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_tiles_are_rejected() {
+        // no background yet
+        assert!(decode(1, 1, &[0]).await.is_err());
+        // no foreground for a plain subrect
+        assert!(
+            decode(1, 1, &[BACKGROUND_SPECIFIED | ANY_SUBRECTS, 0, 0, 1, 0, 0])
+                .await
+                .is_err()
+        );
+        // a 2x1 subrect in a 1x1 tile
+        let data = [
+            BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED | ANY_SUBRECTS,
+            0,
+            0,
+            0,
+            0,
+            1,
+            0x00,
+            0x10,
+        ];
+        assert!(decode(1, 1, &data).await.is_err());
+    }
+}
@@ -1,21 +1,27 @@
-use std::mem::MaybeUninit;
-
 mod cursor;
+mod hextile;
+mod pool;
 mod raw;
+mod rre;
 mod tight;
 mod trle;
 mod zlib;
 mod zrle;
 
 pub(crate) use cursor::Decoder as CursorDecoder;
+pub(crate) use hextile::Decoder as HextileDecoder;
+pub(crate) use pool::BufferPool;
 pub(crate) use raw::Decoder as RawDecoder;
+pub(crate) use rre::Decoder as RreDecoder;
 pub(crate) use tight::Decoder as TightDecoder;
 pub(crate) use trle::Decoder as TrleDecoder;
 pub(crate) use zrle::Decoder as ZrleDecoder;
 
-fn uninit_vec(len: usize) -> Vec<u8> {
-    let mut vec = Vec::with_capacity(len);
-    vec.spare_capacity_mut().fill(MaybeUninit::new(0));
-    unsafe { vec.set_len(vec.capacity()) };
-    vec
+/// A buffer of `len` bytes for a decoder to read into
+///
+/// The decoders overwrite every byte before the buffer is passed on, so the zeros are never seen;
+///
+/// they only keep a short read from exposing old memory, and large buffers come zeroed from the allocator anyway
+fn zeroed_vec(len: usize) -> Vec<u8> {
+    vec![0; len]
 }
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+
+use super::zeroed_vec;
+
+/// Most buffers kept around, older frames are rarely handed back in bulk
+const MAX_POOLED: usize = 8;
+
+/// Image buffers handed back by the frontend, to be filled again by the decoders
+///
+/// Once the frontend recycles what it got, no rectangle needs a fresh allocation
+#[derive(Clone, Default)]
+pub(crate) struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    /// A buffer of `len` bytes, reused if one is available
+    ///
+    /// Its content is unspecified
+    pub(crate) fn take(&self, len: usize) -> Vec<u8> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(mut buffer) => {
+                buffer.resize(len, 0);
+                buffer
+            }
+            None => zeroed_vec(len),
+        }
+    }
+
+    pub(crate) fn give(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED && buffer.capacity() > 0 {
+            buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::RawDecoder;
+    use crate::{PixelFormat, Rect, VncEvent};
+
+    #[tokio::test]
+    async fn recycled_buffers_are_overwritten() {
+        let pool = BufferPool::default();
+        pool.give(vec![0xff; 16]);
+
+        let images = std::sync::Mutex::new(Vec::new());
+        let output = |e| {
+            images.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let pixels: Vec<u8> = (1..=8).collect();
+        RawDecoder::new(pool.clone())
+            .decode(&PixelFormat::bgra(), &rect, &mut pixels.as_slice(), &output)
+            .await
+            .unwrap();
+        // the stale bytes neither show through nor make the image longer
+        match images.into_inner().unwrap().pop() {
+            Some(VncEvent::RawImage(_, image)) => assert_eq!(image, pixels),
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert_eq!(pool.take(5), [0; 5]);
+    }
+}
@@ -2,13 +2,15 @@ use crate::{PixelFormat, Rect, VncError, VncEvent};
 use std::future::Future;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use super::uninit_vec;
+use super::BufferPool;
 
-pub struct Decoder {}
+pub struct Decoder {
+    pool: BufferPool,
+}
 
 impl Decoder {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(pool: BufferPool) -> Self {
+        Self { pool }
     }
 
     pub async fn decode<S, F, Fut>(
@@ -28,11 +30,52 @@ impl Decoder {
         // +----------------------------+--------------+-------------+
         // | width*height*bytesPerPixel | PIXEL array  | pixels      |
         // +----------------------------+--------------+-------------+
-        let bpp = format.bits_per_pixel / 8;
-        let buffer_size = bpp as usize * rect.height as usize * rect.width as usize;
-        let mut pixels = uninit_vec(buffer_size);
+        let bpp = format.bytes_per_pixel();
+        let buffer_size = bpp * rect.height as usize * rect.width as usize;
+        let mut pixels = self.pool.take(buffer_size);
         input.read_exact(&mut pixels).await?;
         output_func(VncEvent::RawImage(*rect, pixels)).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn rgb565_rows() {
+        let events = Mutex::new(Vec::new());
+        let output = |e| {
+            events.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 2,
+        };
+        let pixels: Vec<u8> = [0xf800_u16, 0x07e0, 0x001f, 0xffff, 0x0000, 0x8410]
+            .iter()
+            .flat_map(|p| p.to_le_bytes())
+            .collect();
+        // the next message starts right after the 12 pixel bytes
+        let data = [pixels.as_slice(), &[0xaa]].concat();
+        let mut input = data.as_slice();
+        Decoder::new(BufferPool::default())
+            .decode(&PixelFormat::rgb565(), &rect, &mut input, &output)
+            .await
+            .unwrap();
+
+        assert_eq!(input, [0xaa]);
+        match events.into_inner().unwrap().as_slice() {
+            [VncEvent::RawImage(r, data)] => {
+                assert_eq!((r.x, r.y, r.width, r.height), (1, 2, 3, 2));
+                assert_eq!(data, &pixels);
+            }
+            e => panic!("unexpected events {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{PixelFormat, Rect, VncError, VncEvent};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::error;
+
+pub struct Decoder {}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub async fn decode<S, F, Fut>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output_func: &F,
+    ) -> Result<(), VncError>
+    where
+        S: AsyncRead + Unpin,
+        F: Fn(VncEvent) -> Fut,
+        Fut: Future<Output = Result<(), VncError>>,
+    {
+        // +--------------+--------------+-------------------------+
+        // | No. of bytes | Type [Value] | Description             |
+        // +--------------+--------------+-------------------------+
+        // | 4            | U32          | number-of-subrectangles |
+        // | bytesPerPixel| PIXEL        | background-pixel-value  |
+        // +--------------+--------------+-------------------------+
+        let bpp = format.bytes_per_pixel();
+        let subrects = input.read_u32().await?;
+        let mut background = [0; 4];
+        input.read_exact(&mut background[..bpp]).await?;
+
+        let stride = rect.width as usize;
+        let mut pixels = background[..bpp].repeat(stride * rect.height as usize);
+
+        for _ in 0..subrects {
+            // +--------------+--------------+---------------------+
+            // | No. of bytes | Type [Value] | Description         |
+            // +--------------+--------------+---------------------+
+            // | bytesPerPixel| PIXEL        | subrect-pixel-value |
+            // | 2            | U16          | x-position          |
+            // | 2            | U16          | y-position          |
+            // | 2            | U16          | width               |
+            // | 2            | U16          | height              |
+            // +--------------+--------------+---------------------+
+            let mut colour = [0; 4];
+            input.read_exact(&mut colour[..bpp]).await?;
+            let x = input.read_u16().await? as usize;
+            let y = input.read_u16().await? as usize;
+            let width = input.read_u16().await? as usize;
+            let height = input.read_u16().await? as usize;
+            if x + width > stride || y + height > rect.height as usize {
+                error!("RRE subrect {:?} beyond {:?}", (x, y, width, height), rect);
+                return Err(VncError::InvalidImageData);
+            }
+            for row in y..y + height {
+                let start = (row * stride + x) * bpp;
+                for pixel in pixels[start..start + width * bpp].chunks_exact_mut(bpp) {
+                    pixel.copy_from_slice(&colour[..bpp]);
+                }
+            }
+        }
+
+        output_func(VncEvent::RawImage(*rect, pixels)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Encode `subrects` (colour, x, y, width, height) over `background`
+    fn encode(background: u16, subrects: &[(u16, u16, u16, u16, u16)]) -> Vec<u8> {
+        let mut data = (subrects.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&background.to_le_bytes());
+        for &(colour, x, y, width, height) in subrects {
+            data.extend_from_slice(&colour.to_le_bytes());
+            for n in [x, y, width, height] {
+                data.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+        data
+    }
+
+    async fn decode(rect: Rect, data: &[u8]) -> Result<Vec<u16>, VncError> {
+        let image = Mutex::new(None);
+        let output = |e| {
+            let VncEvent::RawImage(painted, pixels) = e else {
+                panic!("unexpected event {e:?}")
+            };
+            assert_eq!(
+                (painted.x, painted.y, painted.width, painted.height),
+                (rect.x, rect.y, rect.width, rect.height)
+            );
+            *image.lock().unwrap() = Some(pixels);
+            async { Ok(()) }
+        };
+        let mut input = data;
+        Decoder::new()
+            .decode(&PixelFormat::rgb565(), &rect, &mut input, &output)
+            .await?;
+        assert!(input.is_empty(), "{} bytes left", input.len());
+        let pixels = image.into_inner().unwrap().unwrap();
+        Ok(pixels
+            .chunks_exact(2)
+            .map(|p| u16::from_le_bytes([p[0], p[1]]))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn overlapping_subrects() {
+        let rect = Rect {
+            x: 30,
+            y: 40,
+            width: 5,
+            height: 3,
+        };
+        // the later subrect is painted over the earlier one
+        let data = encode(7, &[(1, 0, 0, 3, 2), (2, 2, 1, 3, 2)]);
+        let image = decode(rect, &data).await.unwrap();
+        #[rustfmt::skip]
+        assert_eq!(image, vec![
+            1, 1, 1, 7, 7,
+            1, 1, 2, 2, 2,
+            7, 7, 2, 2, 2,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn background_only() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        let image = decode(rect, &encode(9, &[])).await.unwrap();
+        assert_eq!(image, vec![9; 8]);
+    }
+
+    #[tokio::test]
+    async fn subrect_outside_is_rejected() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        assert!(decode(rect, &encode(9, &[(1, 3, 0, 2, 1)])).await.is_err());
+    }
+}
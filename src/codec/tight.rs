@@ -4,7 +4,7 @@ use std::io::Read;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::error;
 
-use super::{uninit_vec, zlib::ZlibReader};
+use super::{zeroed_vec, zlib::ZlibReader};
 
 const MAX_PALETTE: usize = 256;
 
@@ -13,8 +13,25 @@ pub struct Decoder {
     zlibs: [Option<flate2::Decompress>; 4],
     ctrl: u8,
     filter: u8,
+    /// the palette already converted to pixels
     palette: Vec<u8>,
-    alpha_shift: u32,
+    /// bytes of a TPIXEL, see [tpixel_size]
+    tpixel: usize,
+}
+
+/// A TPIXEL is just the r, g and b bytes for 32bpp pixels with 8 bits colors,
+///
+/// a regular pixel for every other format, e.g. 2 bytes at 16bpp
+fn tpixel_size(format: &PixelFormat) -> usize {
+    if format.true_color_flag != 0
+        && format.bits_per_pixel == 32
+        && format.depth == 24
+        && [format.red_max, format.green_max, format.blue_max] == [255; 3]
+    {
+        3
+    } else {
+        format.bytes_per_pixel()
+    }
 }
 
 impl Decoder {
@@ -42,17 +59,7 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let pixel_mask = (format.red_max as u32) << format.red_shift
-            | (format.green_max as u32) << format.green_shift
-            | (format.blue_max as u32) << format.blue_shift;
-
-        self.alpha_shift = match pixel_mask {
-            0xff_ff_ff_00 => 0,
-            0xff_ff_00_ff => 8,
-            0xff_00_ff_ff => 16,
-            0x00_ff_ff_ff => 24,
-            _ => unreachable!(),
-        };
+        self.tpixel = tpixel_size(format);
 
         let ctrl = input.read_u8().await?;
         for i in 0..4 {
@@ -108,7 +115,7 @@ impl Decoder {
             }
             len
         };
-        let mut data = uninit_vec(len);
+        let mut data = zeroed_vec(len);
         input.read_exact(&mut data).await?;
         Ok(data)
     }
@@ -125,22 +132,16 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let mut color = [0; 3];
-        input.read_exact(&mut color).await?;
-        let bpp = format.bits_per_pixel as usize / 8;
-        let mut image = Vec::with_capacity(rect.width as usize * rect.height as usize * bpp);
-
-        let true_color = self.to_true_color(format, &color);
-
-        for _ in 0..rect.width {
-            for _ in 0..rect.height {
-                image.extend_from_slice(&true_color);
-            }
-        }
+        let mut color = [0; 4];
+        input.read_exact(&mut color[..self.tpixel]).await?;
+        let mut pixel = Vec::with_capacity(4);
+        self.to_pixel(format, &color[..self.tpixel], &mut pixel);
+        let image = pixel.repeat(rect.width as usize * rect.height as usize);
         output_func(VncEvent::RawImage(*rect, image)).await?;
         Ok(())
     }
 
+    #[cfg(not(feature = "jpeg"))]
     async fn jpeg_rect<S, F, Fut>(
         &mut self,
         _format: &PixelFormat,
@@ -158,6 +159,76 @@ impl Decoder {
         Ok(())
     }
 
+    /// Decode the JPEG into pixels of `format`, which has to be true colour
+    #[cfg(feature = "jpeg")]
+    async fn jpeg_rect<S, F, Fut>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output_func: &F,
+    ) -> Result<(), VncError>
+    where
+        S: AsyncRead + Unpin,
+        F: Fn(VncEvent) -> Fut,
+        Fut: Future<Output = Result<(), VncError>>,
+    {
+        use jpeg_decoder::PixelFormat as JpegFormat;
+
+        let data = self.read_data(input).await?;
+        if format.true_color_flag == 0 {
+            return Err(VncError::Unsupported {
+                encoding: crate::VncEncoding::Tight,
+                reason: "JPEG needs a true colour pixel format, not a colour map",
+            });
+        }
+        let jpeg_error = |e| {
+            error!("Tight JPEG can't be decoded: {}", e);
+            VncError::InvalidImageData
+        };
+        let mut decoder = jpeg_decoder::Decoder::new(data.as_slice());
+        // only the header, so a JPEG claiming a huge size is refused before anything is allocated
+        decoder.read_info().map_err(jpeg_error)?;
+        let info = decoder.info().ok_or(VncError::InvalidImageData)?;
+        if (info.width, info.height) != (rect.width, rect.height) {
+            error!(
+                "Tight JPEG of {}x{} for {:?}",
+                info.width, info.height, rect
+            );
+            return Err(VncError::InvalidImageData);
+        }
+        if !matches!(info.pixel_format, JpegFormat::RGB24 | JpegFormat::L8) {
+            error!("Tight JPEG in {:?}", info.pixel_format);
+            return Err(VncError::Unsupported {
+                encoding: crate::VncEncoding::Tight,
+                reason: "only RGB and greyscale JPEGs are decoded",
+            });
+        }
+        decoder.set_max_decoding_buffer_size(rect.width as usize * rect.height as usize * 3);
+        let decoded = decoder.decode().map_err(jpeg_error)?;
+        let rgb: Vec<[u8; 3]> = match info.pixel_format {
+            JpegFormat::L8 => decoded.iter().map(|&l| [l; 3]).collect(),
+            _ => decoded
+                .chunks_exact(3)
+                .map(|c| [c[0], c[1], c[2]])
+                .collect(),
+        };
+
+        // the 8 bits channels are scaled down to the maximums of the format
+        let scale = |c: u8, max: u16| (c as u32 * max as u32 + 127) / 255;
+        let mut image = Vec::with_capacity(rgb.len() * format.bytes_per_pixel());
+        for [r, g, b] in rgb {
+            let components = [
+                scale(r, format.red_max),
+                scale(g, format.green_max),
+                scale(b, format.blue_max),
+            ];
+            self.pack(format, components, &mut image);
+        }
+        output_func(VncEvent::RawImage(*rect, image)).await?;
+        Ok(())
+    }
+
     async fn basic_rect<S, F, Fut>(
         &mut self,
         format: &PixelFormat,
@@ -215,7 +286,7 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let uncompressed_size = rect.width as usize * rect.height as usize * 3;
+        let uncompressed_size = rect.width as usize * rect.height as usize * self.tpixel;
         if uncompressed_size == 0 {
             return Ok(());
         };
@@ -223,12 +294,16 @@ impl Decoder {
         let data = self
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
-        let mut image = Vec::with_capacity(uncompressed_size / 3 * 4);
-        let mut j = 0;
-        while j < uncompressed_size {
-            image.extend_from_slice(&self.to_true_color(format, &data[j..j + 3]));
-            j += 3;
-        }
+        let image = if self.tpixel == format.bytes_per_pixel() {
+            data
+        } else {
+            let mut image =
+                Vec::with_capacity(uncompressed_size / self.tpixel * format.bytes_per_pixel());
+            for color in data.chunks_exact(self.tpixel) {
+                self.to_pixel(format, color, &mut image);
+            }
+            image
+        };
 
         output_func(VncEvent::RawImage(*rect, image)).await?;
 
@@ -249,13 +324,17 @@ impl Decoder {
         Fut: Future<Output = Result<(), VncError>>,
     {
         let num_colors = input.read_u8().await? as usize + 1;
-        let palette_size = num_colors * 3;
-
-        self.palette = uninit_vec(palette_size);
-        input.read_exact(&mut self.palette).await?;
+        let mut colors = zeroed_vec(num_colors * self.tpixel);
+        input.read_exact(&mut colors).await?;
+        self.palette.truncate(0);
+        for color in colors.chunks_exact(self.tpixel) {
+            let mut pixel = Vec::with_capacity(4);
+            self.to_pixel(format, color, &mut pixel);
+            self.palette.extend_from_slice(&pixel);
+        }
 
-        let bpp = if num_colors <= 2 { 1 } else { 8 };
-        let row_size = (rect.width as usize * bpp).div_ceil(8);
+        let bits = if num_colors <= 2 { 1 } else { 8 };
+        let row_size = (rect.width as usize * bits).div_ceil(8);
         let uncompressed_size = rect.height as usize * row_size;
 
         if uncompressed_size == 0 {
@@ -266,75 +345,26 @@ impl Decoder {
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
 
-        if num_colors == 2 {
-            self.mono_rect(data, rect, format, output_func).await?
-        } else {
-            self.palette_rect(data, rect, format, output_func).await?
-        }
-
-        Ok(())
-    }
-
-    async fn mono_rect<F, Fut>(
-        &mut self,
-        data: Vec<u8>,
-        rect: &Rect,
-        format: &PixelFormat,
-        output_func: &F,
-    ) -> Result<(), VncError>
-    where
-        F: Fn(VncEvent) -> Fut,
-        Fut: Future<Output = Result<(), VncError>>,
-    {
-        // Convert indexed (palette based) image data to RGB
-        let total = rect.width as usize * rect.height as usize;
-        let mut image = uninit_vec(total * 4);
-        let mut offset = 8_usize;
-        let mut index = -1_isize;
-        let mut dp = 0;
-        for i in 0..total {
-            if offset == 0 || i % rect.width as usize == 0 {
-                offset = 8;
-                index += 1;
-            }
-            offset -= 1;
-            let sp = ((data[index as usize] >> offset) & 0x01) as usize * 3;
-            let true_color = self.to_true_color(format, &self.palette[sp..sp + 3]);
-            unsafe {
-                std::ptr::copy_nonoverlapping(true_color.as_ptr(), image.as_mut_ptr().add(dp), 4)
+        let bpp = format.bytes_per_pixel();
+        let mut image = Vec::with_capacity(rect.width as usize * rect.height as usize * bpp);
+        for row in data.chunks_exact(row_size) {
+            for x in 0..rect.width as usize {
+                let index = if bits == 1 {
+                    (row[x / 8] >> (7 - x % 8)) & 1
+                } else {
+                    row[x]
+                } as usize;
+                match self.palette.get(index * bpp..(index + 1) * bpp) {
+                    Some(pixel) => image.extend_from_slice(pixel),
+                    None => {
+                        error!("Tight palette index {} out of {}", index, num_colors);
+                        return Err(VncError::InvalidImageData);
+                    }
+                }
             }
-            dp += 4;
         }
         output_func(VncEvent::RawImage(*rect, image)).await?;
-        Ok(())
-    }
 
-    async fn palette_rect<F, Fut>(
-        &mut self,
-        data: Vec<u8>,
-        rect: &Rect,
-        format: &PixelFormat,
-        output_func: &F,
-    ) -> Result<(), VncError>
-    where
-        F: Fn(VncEvent) -> Fut,
-        Fut: Future<Output = Result<(), VncError>>,
-    {
-        // Convert indexed (palette based) image data to RGB
-        let total = rect.width as usize * rect.height as usize;
-        let mut image = uninit_vec(total * 4);
-        let mut i = 0;
-        let mut dp = 0;
-        while i < total {
-            let sp = data[i] as usize * 3;
-            let true_color = self.to_true_color(format, &self.palette[sp..sp + 3]);
-            unsafe {
-                std::ptr::copy_nonoverlapping(true_color.as_ptr(), image.as_mut_ptr().add(dp), 4)
-            }
-            dp += 4;
-            i += 1;
-        }
-        output_func(VncEvent::RawImage(*rect, image)).await?;
         Ok(())
     }
 
@@ -351,57 +381,41 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let uncompressed_size = rect.width as usize * rect.height as usize * 3;
+        let uncompressed_size = rect.width as usize * rect.height as usize * self.tpixel;
         if uncompressed_size == 0 {
             return Ok(());
         };
         let data = self
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
-        let mut image = uninit_vec(rect.width as usize * rect.height as usize * 4);
-
-        let row_len = rect.width as usize * 3 + 3;
-        let mut row_0 = vec![0_u16; row_len];
-        let mut row_1 = vec![0_u16; row_len];
-        let max = [format.red_max, format.green_max, format.blue_max];
-        let shift = [format.red_shift, format.green_shift, format.blue_shift];
-        let mut sp = 0;
-        let mut dp = 0;
-
-        for y in 0..rect.height as usize {
-            let (this_row, prev_row) = match y & 1 {
-                0 => (&mut row_0, &mut row_1),
-                1 => (&mut row_1, &mut row_0),
-                _ => unreachable!(),
-            };
-            let mut x = 3;
-            while x < row_len {
-                let rgb = &data[sp..sp + 3];
-                let mut color = 0;
-                for index in 0..3 {
-                    let d = prev_row[index + x] as i32 + this_row[index + x - 3] as i32
-                        - prev_row[index + x - 3] as i32;
-                    let converted = if d < 0 {
-                        0
-                    } else if d > max[index] as i32 {
-                        max[index]
-                    } else {
-                        d as u16
-                    };
-                    this_row[index + x] = (converted + rgb[index] as u16) & max[index];
-                    color |= (this_row[x + index] as u32 & max[index] as u32) << shift[index];
-                }
-                unsafe {
-                    std::ptr::copy_nonoverlapping(
-                        color.to_le_bytes().as_ptr(),
-                        image.as_mut_ptr().add(dp),
-                        4,
-                    )
+        let mut image = Vec::with_capacity(
+            rect.width as usize * rect.height as usize * format.bytes_per_pixel(),
+        );
+
+        // the colors of the previous row, with a black pixel left of the first one
+        let row_len = rect.width as usize + 1;
+        let mut prev_row = vec![[0_u32; 3]; row_len];
+        let mut this_row = vec![[0_u32; 3]; row_len];
+        let max = [
+            format.red_max as u32,
+            format.green_max as u32,
+            format.blue_max as u32,
+        ];
+
+        for row in data.chunks_exact(rect.width as usize * self.tpixel) {
+            for (x, diff) in row.chunks_exact(self.tpixel).enumerate() {
+                let diff = self.components(format, diff);
+                let mut color = [0; 3];
+                for c in 0..3 {
+                    let predicted = (prev_row[x + 1][c] as i32 + this_row[x][c] as i32
+                        - prev_row[x][c] as i32)
+                        .clamp(0, max[c] as i32) as u32;
+                    color[c] = (predicted + diff[c]) & max[c];
                 }
-                dp += 4;
-                sp += 3;
-                x += 3;
+                this_row[x + 1] = color;
+                self.pack(format, color, &mut image);
             }
+            std::mem::swap(&mut prev_row, &mut this_row);
         }
 
         output_func(VncEvent::RawImage(*rect, image)).await?;
@@ -419,25 +433,322 @@ impl Decoder {
     {
         let mut data;
         if uncompressed_size < 12 {
-            data = uninit_vec(uncompressed_size);
+            data = zeroed_vec(uncompressed_size);
             input.read_exact(&mut data).await?;
         } else {
             let d = self.read_data(input).await?;
             let mut reader = ZlibReader::new(self.zlibs[stream as usize].take().unwrap(), &d);
-            data = uninit_vec(uncompressed_size);
+            data = zeroed_vec(uncompressed_size);
             reader.read_exact(&mut data)?;
             self.zlibs[stream as usize] = Some(reader.into_inner()?);
         };
         Ok(data)
     }
 
-    fn to_true_color(&self, format: &PixelFormat, color: &[u8]) -> [u8; 4] {
-        let alpha = 255;
-        // always rgb
-        (((color[0] as u32 & format.red_max as u32) << format.red_shift)
-            | ((color[1] as u32 & format.green_max as u32) << format.green_shift)
-            | ((color[2] as u32 & format.blue_max as u32) << format.blue_shift)
-            | ((alpha as u32) << self.alpha_shift))
-            .to_le_bytes()
+    /// The red, green and blue values of a TPIXEL
+    fn components(&self, format: &PixelFormat, tpixel: &[u8]) -> [u32; 3] {
+        if self.tpixel == 3 {
+            return [tpixel[0] as u32, tpixel[1] as u32, tpixel[2] as u32];
+        }
+        let mut value = [0; 4];
+        let value = if format.big_endian_flag != 0 {
+            value[4 - tpixel.len()..].copy_from_slice(tpixel);
+            u32::from_be_bytes(value)
+        } else {
+            value[..tpixel.len()].copy_from_slice(tpixel);
+            u32::from_le_bytes(value)
+        };
+        [
+            (value >> format.red_shift) & format.red_max as u32,
+            (value >> format.green_shift) & format.green_max as u32,
+            (value >> format.blue_shift) & format.blue_max as u32,
+        ]
+    }
+
+    /// Append the pixel of the given colors, with all the unused bits (e.g. alpha) set
+    fn pack(&self, format: &PixelFormat, [r, g, b]: [u32; 3], out: &mut Vec<u8>) {
+        let bpp = format.bytes_per_pixel();
+        let mask = (format.red_max as u32) << format.red_shift
+            | (format.green_max as u32) << format.green_shift
+            | (format.blue_max as u32) << format.blue_shift;
+        let value = r << format.red_shift | g << format.green_shift | b << format.blue_shift;
+        let value = if self.tpixel == 3 {
+            value | !mask
+        } else {
+            value
+        };
+        if format.big_endian_flag != 0 {
+            out.extend_from_slice(&value.to_be_bytes()[4 - bpp..]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes()[..bpp]);
+        }
+    }
+
+    /// Append the pixel for a TPIXEL
+    fn to_pixel(&self, format: &PixelFormat, tpixel: &[u8], out: &mut Vec<u8>) {
+        if self.tpixel == format.bytes_per_pixel() {
+            out.extend_from_slice(tpixel);
+        } else {
+            self.pack(format, self.components(format, tpixel), out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+    use std::sync::Mutex;
+
+    async fn decode(format: &PixelFormat, width: u16, height: u16, data: &[u8]) -> Vec<u8> {
+        let images = Mutex::new(Vec::new());
+        let output = |e| {
+            images.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut input = data;
+        Decoder::new()
+            .decode(format, &rect, &mut input, &output)
+            .await
+            .unwrap();
+        assert!(input.is_empty(), "{} bytes left", input.len());
+        match images.into_inner().unwrap().pop() {
+            Some(VncEvent::RawImage(_, image)) => image,
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[tokio::test]
+    async fn jpeg_to_pixels() {
+        // an 8x4 tile of (200, 40, 80)
+        let jpeg = include_bytes!("testdata/solid_8x4.jpg");
+        let mut data = vec![
+            0x90,
+            0x80 | (jpeg.len() & 0x7f) as u8,
+            (jpeg.len() >> 7) as u8,
+        ];
+        data.extend_from_slice(jpeg);
+
+        let close = |a: u8, b: u8| a.abs_diff(b) <= 2;
+        let image = decode(&PixelFormat::bgra(), 8, 4, &data).await;
+        for pixel in image.chunks_exact(4) {
+            assert!(close(pixel[2], 200) && close(pixel[1], 40) && close(pixel[0], 80));
+            assert_eq!(pixel[3], 255);
+        }
+
+        let image = decode(&PixelFormat::rgb565(), 8, 4, &data).await;
+        for pixel in image.chunks_exact(2) {
+            let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+            assert_eq!((value >> 11, (value >> 5) & 63, value & 31), (24, 10, 10));
+        }
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[tokio::test]
+    async fn jpeg_header_checked_before_decoding() {
+        let mut jpeg = include_bytes!("testdata/solid_8x4.jpg").to_vec();
+        // the SOF0 header now claims 65535x65535
+        assert_eq!(jpeg[20..23], [0xff, 0xc0, 0x00]);
+        jpeg[25..29].copy_from_slice(&[0xff; 4]);
+        let mut data = vec![
+            0x90,
+            0x80 | (jpeg.len() & 0x7f) as u8,
+            (jpeg.len() >> 7) as u8,
+        ];
+        data.extend_from_slice(&jpeg);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 4,
+        };
+        let result = Decoder::new()
+            .decode(
+                &PixelFormat::bgra(),
+                &rect,
+                &mut data.as_slice(),
+                &|_| async { Ok(()) },
+            )
+            .await;
+        assert!(matches!(result, Err(VncError::InvalidImageData)));
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[tokio::test]
+    async fn jpeg_needs_true_colour() {
+        let jpeg = include_bytes!("testdata/solid_8x4.jpg");
+        let mut data = vec![
+            0x90,
+            0x80 | (jpeg.len() & 0x7f) as u8,
+            (jpeg.len() >> 7) as u8,
+        ];
+        data.extend_from_slice(jpeg);
+        let colour_map =
+            PixelFormat::try_from([8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 4,
+        };
+        let output = |_| async { Ok(()) };
+        let result = Decoder::new()
+            .decode(&colour_map, &rect, &mut data.as_slice(), &output)
+            .await;
+        assert!(matches!(
+            result,
+            Err(VncError::Unsupported {
+                encoding: crate::VncEncoding::Tight,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn fill_16bpp() {
+        // the TPIXEL of a 16bpp format is just a 2 bytes pixel
+        let image = decode(&PixelFormat::rgb565(), 3, 2, &[0x80, 0x34, 0x12]).await;
+        assert_eq!(image, [0x34, 0x12].repeat(6));
+    }
+
+    #[tokio::test]
+    async fn copy_16bpp() {
+        let pixels: Vec<u8> = (0..4 * 4 * 2).collect();
+        // small enough to be sent uncompressed
+        let mut data = vec![0x00];
+        data.extend_from_slice(&pixels[..2 * 2 * 2]);
+        assert_eq!(
+            decode(&PixelFormat::rgb565(), 2, 2, &data).await,
+            &pixels[..8]
+        );
+
+        let mut compressed = Vec::with_capacity(128);
+        Compress::new(Compression::default(), true)
+            .compress_vec(&pixels, &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        let mut data = vec![0x00, compressed.len() as u8];
+        data.extend_from_slice(&compressed);
+        assert_eq!(decode(&PixelFormat::rgb565(), 4, 4, &data).await, pixels);
+    }
+
+    #[tokio::test]
+    async fn mono_palette_16bpp() {
+        // explicit filter, the palette of 2 colors, then 1 bit per pixel rows
+        let data = [
+            0x40,
+            0x01,
+            0x01,
+            0x11,
+            0x11,
+            0x22,
+            0x22,
+            0b1000_0000,
+            0b0100_0000,
+        ];
+        let image = decode(&PixelFormat::rgb565(), 3, 2, &data).await;
+        let (a, b) = ([0x11, 0x11], [0x22, 0x22]);
+        assert_eq!(image, [b, a, a, a, b, a].concat());
+    }
+
+    #[tokio::test]
+    async fn mono_palette_padded_rows() {
+        // 9 pixels take 2 bytes a row, the 7 padding bits must not shift the next row
+        let data = [
+            0x40,
+            0x01,
+            0x01,
+            0x11,
+            0x11,
+            0x22,
+            0x22,
+            0b0000_0000,
+            0b1000_0000,
+            0b1000_0000,
+            0b1111_1111,
+        ];
+        let image = decode(&PixelFormat::rgb565(), 9, 2, &data).await;
+        let (a, b) = ([0x11, 0x11], [0x22, 0x22]);
+        let mut expected = vec![a; 8];
+        expected.push(b);
+        expected.push(b);
+        expected.extend_from_slice(&[a; 7]);
+        expected.push(b);
+        assert_eq!(image, expected.concat());
+    }
+
+    #[tokio::test]
+    async fn fill_32bpp_sets_alpha() {
+        let image = decode(&PixelFormat::bgra(), 1, 1, &[0x80, 1, 2, 3]).await;
+        assert_eq!(image, [3, 2, 1, 255]);
+    }
+
+    fn gradient_rect(diffs: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(128);
+        Compress::new(Compression::default(), true)
+            .compress_vec(diffs, &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        // explicit filter on stream 0, then the gradient one
+        let mut data = vec![0x40, 0x02, compressed.len() as u8];
+        data.extend_from_slice(&compressed);
+        data
+    }
+
+    #[tokio::test]
+    async fn gradient_24bit() {
+        // worked by hand, the last pixel's prediction is clamped on red (260) and blue (-200)
+        let diffs = [200, 100, 200, 10, 0, 56, 50, 0, 56, 1, 0, 7];
+        let image = decode(&PixelFormat::bgra(), 2, 2, &gradient_rect(&diffs)).await;
+        let expected = [
+            [200, 100, 200, 255],
+            [0, 100, 210, 255],
+            [0, 100, 250, 255],
+            [7, 100, 0, 255],
+        ];
+        assert_eq!(image, expected.concat());
+    }
+
+    #[tokio::test]
+    async fn gradient_16bpp() {
+        let (width, height) = (5, 4);
+        let max = [31_i32, 63, 31];
+        let color = |x: i32, y: i32| [(x * 9 + y) % 32, (y * 21 + x * 3) % 64, (x * y * 7) % 32];
+        let at = |x: i32, y: i32| {
+            if x < 0 || y < 0 {
+                [0; 3]
+            } else {
+                color(x, y)
+            }
+        };
+
+        let (mut diffs, mut expected) = (Vec::new(), Vec::new());
+        for y in 0..height {
+            for x in 0..width {
+                let (c, left, up, corner) =
+                    (at(x, y), at(x - 1, y), at(x, y - 1), at(x - 1, y - 1));
+                let mut diff = [0; 3];
+                for i in 0..3 {
+                    let predicted = (left[i] + up[i] - corner[i]).clamp(0, max[i]);
+                    diff[i] = (c[i] - predicted) & max[i];
+                }
+                let pack = |[r, g, b]: [i32; 3]| ((r << 11 | g << 5 | b) as u16).to_le_bytes();
+                diffs.extend_from_slice(&pack(diff));
+                expected.extend_from_slice(&pack(c));
+            }
+        }
+        let image = decode(
+            &PixelFormat::rgb565(),
+            width as u16,
+            height as u16,
+            &gradient_rect(&diffs),
+        )
+        .await;
+        assert_eq!(image, expected);
     }
 }
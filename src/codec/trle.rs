@@ -3,8 +3,6 @@ use std::future::Future;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::error;
 
-use super::uninit_vec;
-
 async fn read_run_length<S>(reader: &mut S) -> Result<usize, VncError>
 where
     S: AsyncRead + Unpin,
@@ -39,11 +37,32 @@ where
     Ok(())
 }
 
-fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
+fn copy_indexed(
+    palette: &[u8],
+    pixels: &mut Vec<u8>,
+    bpp: usize,
+    index: u8,
+) -> Result<(), VncError> {
     let start = index as usize * bpp;
-    pixels.extend_from_slice(&palette[start..start + bpp])
+    match palette.get(start..start + bpp) {
+        Some(color) => {
+            pixels.extend_from_slice(color);
+            Ok(())
+        }
+        None => {
+            error!(
+                "TRLE palette index {} out of {}",
+                index,
+                palette.len() / bpp
+            );
+            Err(VncError::InvalidImageData)
+        }
+    }
 }
 
+/// The tiles are 16x16, the ones at the right and bottom edges may be smaller
+const TILE_SIZE: u16 = 16;
+
 pub struct Decoder {}
 
 impl Decoder {
@@ -63,11 +82,7 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
-        let data_len = input.read_u32().await? as usize;
-        let mut zlib_data = uninit_vec(data_len);
-        input.read_exact(&mut zlib_data).await?;
-
-        let bpp = format.bits_per_pixel as usize / 8;
+        let bpp = format.bytes_per_pixel();
         let pixel_mask = (format.red_max as u32) << format.red_shift
             | (format.green_max as u32) << format.green_shift
             | (format.blue_max as u32) << format.blue_shift;
@@ -92,33 +107,46 @@ impl Decoder {
             } else {
                 (bpp, false)
             };
+        // kept from tile to tile, as the subencodings 127 and 129 reuse it
         let mut palette = Vec::with_capacity(128 * bpp);
 
         let mut y = 0;
         while y < rect.height {
-            let height = if y + 64 > rect.height {
-                rect.height - y
-            } else {
-                64
-            };
+            let height = (rect.height - y).min(TILE_SIZE);
             let mut x = 0;
             while x < rect.width {
-                let width = if x + 64 > rect.width {
-                    rect.width - x
-                } else {
-                    64
-                };
+                let width = (rect.width - x).min(TILE_SIZE);
                 let pixel_count = height as usize * width as usize;
 
-                let control = input.read_u8().await?;
-                let is_rle = control & 0x80 > 0;
-                let palette_size = control & 0x7f;
-                palette.truncate(0);
-
-                for _ in 0..palette_size {
-                    copy_true_color(input, &mut palette, alpha_at_first, compressed_bpp, bpp)
-                        .await?
-                }
+                let subencoding = input.read_u8().await?;
+                let is_rle = subencoding & 0x80 > 0;
+                let palette_size = match subencoding {
+                    // reuse the palette of the previous tile
+                    127 | 129 => {
+                        if palette.is_empty() {
+                            error!("TRLE palette reused before any was sent");
+                            return Err(VncError::InvalidImageData);
+                        }
+                        palette.len() / bpp
+                    }
+                    _ => {
+                        let palette_size = (subencoding & 0x7f) as usize;
+                        if palette_size > 0 {
+                            palette.truncate(0);
+                            for _ in 0..palette_size {
+                                copy_true_color(
+                                    input,
+                                    &mut palette,
+                                    alpha_at_first,
+                                    compressed_bpp,
+                                    bpp,
+                                )
+                                .await?
+                            }
+                        }
+                        palette_size
+                    }
+                };
 
                 let mut pixels = Vec::with_capacity(pixel_count * bpp);
                 match (is_rle, palette_size) {
@@ -132,34 +160,28 @@ impl Decoder {
                     (false, 1) => {
                         // Color fill
                         for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
+                            copy_indexed(&palette, &mut pixels, bpp, 0)?
                         }
                     }
                     (false, 2..=16) => {
-                        // Indexed pixels
+                        // Packed palette indices, every row starts at a new byte
                         let bits_per_index = match palette_size {
                             2 => 1,
                             3..=4 => 2,
-                            5..=16 => 4,
-                            _ => unreachable!(),
+                            _ => 4,
                         };
-                        let mut encoded = input.read_u8().await?;
                         let mask = (1 << bits_per_index) - 1;
-
-                        for y in 0..height {
-                            let mut shift = 8 - bits_per_index;
+                        for _ in 0..height {
+                            let mut encoded = 0;
+                            let mut bits_left = 0;
                             for _ in 0..width {
-                                if shift < 0 {
-                                    shift = 8 - bits_per_index;
+                                if bits_left == 0 {
                                     encoded = input.read_u8().await?;
+                                    bits_left = 8;
                                 }
-                                let idx = (encoded >> shift) & mask;
-
-                                copy_indexed(&palette, &mut pixels, bpp, idx);
-                                shift -= bits_per_index;
-                            }
-                            if shift < 8 - bits_per_index && y < height - 1 {
-                                encoded = input.read_u8().await?;
+                                bits_left -= bits_per_index;
+                                let index = (encoded >> bits_left) & mask;
+                                copy_indexed(&palette, &mut pixels, bpp, index)?;
                             }
                         }
                     }
@@ -172,14 +194,18 @@ impl Decoder {
                             copy_true_color(input, &mut pixel, alpha_at_first, compressed_bpp, bpp)
                                 .await?;
                             let run_length = read_run_length(input).await?;
+                            count += run_length;
+                            if count > pixel_count {
+                                error!("TRLE run beyond the tile at {:?}", (x, y));
+                                return Err(VncError::InvalidImageData);
+                            }
                             for _ in 0..run_length {
                                 pixels.extend(&pixel)
                             }
-                            count += run_length;
                         }
                     }
-                    (true, 2..=127) => {
-                        // Indexed RLE
+                    (true, 1..=127) => {
+                        // Palette RLE
                         let mut count = 0;
                         while count < pixel_count {
                             let control = input.read_u8().await?;
@@ -190,14 +216,18 @@ impl Decoder {
                             } else {
                                 1
                             };
+                            count += run_length;
+                            if count > pixel_count {
+                                error!("TRLE run beyond the tile at {:?}", (x, y));
+                                return Err(VncError::InvalidImageData);
+                            }
                             for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
+                                copy_indexed(&palette, &mut pixels, bpp, index)?;
                             }
-                            count += run_length;
                         }
                     }
                     (x, y) => {
-                        error!("TLRE subencoding error {:?}", (x, y));
+                        error!("TRLE subencoding error {:?}", (x, y));
                         return Err(VncError::InvalidImageData);
                     }
                 }
@@ -219,3 +249,162 @@ impl Decoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Decode `data` and paint the tiles into one image of `width` x `height` 16bpp pixels
+    async fn decode(width: u16, height: u16, data: &[u8]) -> Result<Vec<u16>, VncError> {
+        let tiles = Mutex::new(Vec::new());
+        let output = |e| {
+            tiles.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut input = data;
+        Decoder::new()
+            .decode(&PixelFormat::rgb565(), &rect, &mut input, &output)
+            .await?;
+        assert!(input.is_empty(), "{} bytes left", input.len());
+
+        let mut image = vec![0; width as usize * height as usize];
+        for e in tiles.into_inner().unwrap() {
+            let VncEvent::RawImage(tile, pixels) = e else {
+                panic!("unexpected event {e:?}")
+            };
+            assert_eq!(pixels.len(), tile.width as usize * tile.height as usize * 2);
+            for (n, pixel) in pixels.chunks_exact(2).enumerate() {
+                let x = tile.x as usize + n % tile.width as usize;
+                let y = tile.y as usize + n / tile.width as usize;
+                image[y * width as usize + x] = u16::from_le_bytes([pixel[0], pixel[1]]);
+            }
+        }
+        Ok(image)
+    }
+
+    fn colour(index: usize) -> u16 {
+        0x1000 + index as u16 * 0x0101
+    }
+
+    #[tokio::test]
+    async fn packed_palette_edge_tiles() {
+        // 20x18 makes tiles of 16x16, 4x16, 16x2 and 4x2
+        let (width, height) = (20_u16, 18_u16);
+        for (palette_size, bits) in [(2_usize, 1), (4, 2), (16, 4)] {
+            let index = |x: u16, y: u16| (x as usize * 7 + y as usize * 3) % palette_size;
+            let mut data = Vec::new();
+            for ty in (0..height).step_by(16) {
+                for tx in (0..width).step_by(16) {
+                    if tx == 0 && ty == 0 {
+                        data.push(palette_size as u8);
+                        for n in 0..palette_size {
+                            data.extend_from_slice(&colour(n).to_le_bytes());
+                        }
+                    } else {
+                        data.push(127);
+                    }
+                    for y in ty..height.min(ty + 16) {
+                        let (mut byte, mut used) = (0_u8, 0);
+                        for x in tx..width.min(tx + 16) {
+                            byte |= (index(x, y) as u8) << (8 - used - bits);
+                            used += bits;
+                            if used == 8 {
+                                data.push(byte);
+                                (byte, used) = (0, 0);
+                            }
+                        }
+                        if used > 0 {
+                            data.push(byte);
+                        }
+                    }
+                }
+            }
+
+            let image = decode(width, height, &data).await.unwrap();
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(
+                        image[y as usize * width as usize + x as usize],
+                        colour(index(x, y)),
+                        "{bits} bits at {:?}",
+                        (x, y)
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_with_continuation_bytes() {
+        let mut data = Vec::new();
+        // palette RLE: 1 pixel of colour 0, then 255 of colour 1 (1 + 254)
+        data.extend_from_slice(&[130]);
+        data.extend_from_slice(&colour(0).to_le_bytes());
+        data.extend_from_slice(&colour(1).to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x81, 254]);
+        // plain RLE: all 256 pixels in one run (1 + 255 + 0)
+        data.push(128);
+        data.extend_from_slice(&colour(2).to_le_bytes());
+        data.extend_from_slice(&[255, 0]);
+        // palette RLE reusing the palette: the 8x16 edge tile in colour 1
+        data.extend_from_slice(&[129, 0x81, 127]);
+
+        let image = decode(40, 16, &data).await.unwrap();
+        for y in 0..16 {
+            for x in 0..40 {
+                let expected = match x {
+                    0 if y == 0 => colour(0),
+                    0..=15 => colour(1),
+                    16..=31 => colour(2),
+                    _ => colour(1),
+                };
+                assert_eq!(image[y * 40 + x], expected, "at {:?}", (x, y));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_and_solid_edge_tiles() {
+        let mut data = vec![0];
+        for n in 0..16 * 3 {
+            data.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        data.push(1);
+        data.extend_from_slice(&colour(3).to_le_bytes());
+
+        let image = decode(17, 3, &data).await.unwrap();
+        for y in 0..3 {
+            assert_eq!(
+                &image[y * 17..y * 17 + 16],
+                (y as u16 * 16..y as u16 * 16 + 16).collect::<Vec<_>>()
+            );
+            assert_eq!(image[y * 17 + 16], colour(3));
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_tiles_are_rejected() {
+        let solid = |index: u8| {
+            let mut data = vec![130];
+            data.extend_from_slice(&colour(0).to_le_bytes());
+            data.extend_from_slice(&colour(1).to_le_bytes());
+            data.push(index);
+            data
+        };
+        // a run past the end of the 1x1 tile
+        let mut data = solid(0x81);
+        data.push(1);
+        assert!(decode(1, 1, &data).await.is_err());
+        // an index past the palette
+        assert!(decode(1, 1, &solid(5)).await.is_err());
+        // a palette reused before any was sent
+        assert!(decode(1, 1, &[127, 0]).await.is_err());
+    }
+}
@@ -23,7 +23,8 @@ IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 DEALINGS IN THE SOFTWARE.
 */
 
-use std::io::{Read, Result};
+use crate::VncError;
+use std::io::Read;
 
 pub struct ZlibReader<'a> {
     decompressor: flate2::Decompress,
@@ -38,15 +39,27 @@ impl<'a> ZlibReader<'a> {
         }
     }
 
-    pub fn into_inner(self) -> Result<flate2::Decompress> {
-        if self.input.is_empty() {
-            Ok(self.decompressor)
-        } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "leftover zlib byte data",
-            ))
+    /// Give back the decompressor once the rectangle has been read
+    ///
+    /// The input left over may only hold the end of a flush, if it would inflate to more data
+    ///
+    /// than the rectangle needed the server is trying to make us decompress a bomb
+    pub fn into_inner(mut self) -> Result<flate2::Decompress, VncError> {
+        let mut scratch = [0; 64];
+        while !self.input.is_empty() {
+            let left = self.input.len();
+            if self.read(&mut scratch)? > 0 {
+                return Err(VncError::DecompressionBomb);
+            }
+            if self.input.len() == left {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "leftover zlib byte data",
+                )
+                .into());
+            }
         }
+        Ok(self.decompressor)
     }
 
     pub fn read_u8(&mut self) -> std::io::Result<u8> {
@@ -71,10 +84,44 @@ impl<'a> Read for ZlibReader<'a> {
             Ok(flate2::Status::Ok) => Ok(produced),
             Ok(flate2::Status::BufError) => Ok(0),
             Err(error) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error)),
-            Ok(flate2::Status::StreamEnd) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "zlib stream end",
-            )),
+            // a finished stream (as with ZRLEE) yields nothing more, reading on is an EOF
+            Ok(flate2::Status::StreamEnd) => Ok(produced),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, Decompress, FlushCompress};
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compressor
+            .compress_vec(data, &mut out, FlushCompress::Sync)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn exact_output_is_accepted() {
+        let compressed = deflate(&[7; 1000]);
+        let mut reader = ZlibReader::new(Decompress::new(true), &compressed);
+        let mut out = vec![0; 1000];
+        reader.read_exact(&mut out).unwrap();
+        assert!(reader.into_inner().is_ok());
+    }
+
+    #[test]
+    fn extra_output_is_a_bomb() {
+        let compressed = deflate(&[7; 1_000_000]);
+        let mut reader = ZlibReader::new(Decompress::new(true), &compressed);
+        let mut out = vec![0; 1000];
+        reader.read_exact(&mut out).unwrap();
+        assert!(matches!(
+            reader.into_inner(),
+            Err(VncError::DecompressionBomb)
+        ));
+    }
+}
@@ -3,7 +3,7 @@ use std::future::Future;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::error;
 
-use super::{uninit_vec, zlib::ZlibReader};
+use super::{zeroed_vec, zlib::ZlibReader};
 
 fn read_run_length(reader: &mut ZlibReader) -> Result<usize, VncError> {
     let mut run_length_part;
@@ -34,19 +34,40 @@ fn copy_true_color(
     Ok(())
 }
 
-fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
+fn copy_indexed(
+    palette: &[u8],
+    pixels: &mut Vec<u8>,
+    bpp: usize,
+    index: u8,
+) -> Result<(), VncError> {
     let start = index as usize * bpp;
-    pixels.extend_from_slice(&palette[start..start + bpp])
+    match palette.get(start..start + bpp) {
+        Some(color) => {
+            pixels.extend_from_slice(color);
+            Ok(())
+        }
+        None => {
+            error!(
+                "ZRLE palette index {} out of {}",
+                index,
+                palette.len() / bpp
+            );
+            Err(VncError::InvalidImageData)
+        }
+    }
 }
 
 pub struct Decoder {
     decompressor: Option<flate2::Decompress>,
+    /// ZRLEE, where each rectangle is a zlib stream of its own
+    stream_per_rect: bool,
 }
 
 impl Decoder {
-    pub fn new() -> Self {
+    pub fn new(stream_per_rect: bool) -> Self {
         Self {
             decompressor: Some(flate2::Decompress::new(true)),
+            stream_per_rect,
         }
     }
 
@@ -62,13 +83,23 @@ impl Decoder {
         F: Fn(VncEvent) -> Fut,
         Fut: Future<Output = Result<(), VncError>>,
     {
+        let bpp = format.bytes_per_pixel();
         let data_len = input.read_u32().await? as usize;
-        let mut zlib_data = uninit_vec(data_len);
+        // even raw tiles with a run length byte per pixel stay far below this,
+        // so refuse to buffer anything larger before inflating it
+        let max_len = 2 * rect.width as usize * rect.height as usize * (bpp + 1) + 1024;
+        if data_len > max_len {
+            error!("ZRLE data of {} bytes for a {:?} rectangle", data_len, rect);
+            return Err(VncError::DecompressionBomb);
+        }
+        let mut zlib_data = zeroed_vec(data_len);
         input.read_exact(&mut zlib_data).await?;
+        if self.stream_per_rect {
+            self.decompressor = Some(flate2::Decompress::new(true));
+        }
         let decompressor = self.decompressor.take().unwrap();
         let mut reader = ZlibReader::new(decompressor, &zlib_data);
 
-        let bpp = format.bits_per_pixel as usize / 8;
         let pixel_mask = (format.red_max as u32) << format.red_shift
             | (format.green_max as u32) << format.green_shift
             | (format.blue_max as u32) << format.blue_shift;
@@ -143,7 +174,7 @@ impl Decoder {
                     (false, 1) => {
                         // Color fill
                         for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
+                            copy_indexed(&palette, &mut pixels, bpp, 0)?
                         }
                     }
                     (false, 2..=16) => {
@@ -166,7 +197,7 @@ impl Decoder {
                                 }
                                 let idx = (encoded >> shift) & mask;
 
-                                copy_indexed(&palette, &mut pixels, bpp, idx);
+                                copy_indexed(&palette, &mut pixels, bpp, idx)?;
                                 shift -= bits_per_index;
                             }
                             if shift < 8 - bits_per_index && y < height - 1 {
@@ -188,10 +219,14 @@ impl Decoder {
                                 bpp,
                             )?;
                             let run_length = read_run_length(&mut reader)?;
+                            count += run_length;
+                            if count > pixel_count {
+                                error!("ZRLE run beyond the tile at {:?}", (x, y));
+                                return Err(VncError::InvalidImageData);
+                            }
                             for _ in 0..run_length {
                                 pixels.extend(&pixel)
                             }
-                            count += run_length;
                         }
                     }
                     (true, 2..=127) => {
@@ -206,10 +241,14 @@ impl Decoder {
                             } else {
                                 1
                             };
+                            count += run_length;
+                            if count > pixel_count {
+                                error!("ZRLE run beyond the tile at {:?}", (x, y));
+                                return Err(VncError::InvalidImageData);
+                            }
                             for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
+                                copy_indexed(&palette, &mut pixels, bpp, index)?;
                             }
-                            count += run_length;
                         }
                     }
                     (x, y) => {
@@ -237,3 +276,194 @@ impl Decoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn cpixels_at_16bpp() {
+        let rgb565 = PixelFormat::rgb565();
+        let (a, b) = ([0x34, 0x12], [0x78, 0x56]);
+        // a 66x2 rectangle: a 64x2 palette RLE tile and a 2x2 raw one, CPIXELs are 2 bytes
+        let mut tiles = vec![0x82];
+        tiles.extend_from_slice(&[a, b].concat());
+        // 100 of a (1 + 99), then 28 of b (1 + 27)
+        tiles.extend_from_slice(&[0x80, 99, 0x81, 27]);
+        tiles.push(0);
+        tiles.extend_from_slice(&[a, b, b, a].concat());
+
+        let mut compressed = Vec::with_capacity(256);
+        Compress::new(Compression::default(), true)
+            .compress_vec(&tiles, &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&compressed);
+
+        let images = Mutex::new(Vec::new());
+        let output = |e| {
+            images.lock().unwrap().push(e);
+            async { Ok(()) }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 66,
+            height: 2,
+        };
+        Decoder::new(false)
+            .decode(&rgb565, &rect, &mut data.as_slice(), &output)
+            .await
+            .unwrap();
+
+        let images: Vec<_> = images
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|e| match e {
+                VncEvent::RawImage(rect, image) => (rect.width, image),
+                e => panic!("unexpected event {e:?}"),
+            })
+            .collect();
+        assert_eq!(images[0].0, 64);
+        assert_eq!(images[0].1, [a.repeat(100), b.repeat(28)].concat());
+        assert_eq!(images[1], (2, [a, b, b, a].concat()));
+    }
+
+    #[tokio::test]
+    async fn raw_tiles_per_format() {
+        let colour_mapped =
+            PixelFormat::try_from([8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        // the CPIXELs on the wire, then the pixels expected in the RawImage
+        let cases: [(PixelFormat, &[u8], &[u8]); 3] = [
+            (colour_mapped, &[7, 9], &[7, 9]),
+            (
+                PixelFormat::rgb565(),
+                &[0x34, 0x12, 0x78, 0x56],
+                &[0x34, 0x12, 0x78, 0x56],
+            ),
+            // the unused byte of a depth 24 pixel isn't sent
+            (
+                PixelFormat::bgra(),
+                &[1, 2, 3, 4, 5, 6],
+                &[1, 2, 3, 255, 4, 5, 6, 255],
+            ),
+        ];
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        for (format, cpixels, expected) in cases {
+            let mut tile = vec![0];
+            tile.extend_from_slice(cpixels);
+            let mut compressed = Vec::with_capacity(64);
+            Compress::new(Compression::default(), true)
+                .compress_vec(&tile, &mut compressed, FlushCompress::Sync)
+                .unwrap();
+            let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(&compressed);
+
+            let images = Mutex::new(Vec::new());
+            let output = |e| {
+                images.lock().unwrap().push(e);
+                async { Ok(()) }
+            };
+            Decoder::new(true)
+                .decode(&format, &rect, &mut data.as_slice(), &output)
+                .await
+                .unwrap();
+            match images.into_inner().unwrap().as_slice() {
+                [VncEvent::RawImage(_, image)] => assert_eq!(image, expected),
+                e => panic!("unexpected events {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_beyond_the_tile_are_rejected() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        // plain RLE of one CPIXEL for 1 + 255 * 40 + 0 pixels, then a palette RLE run as long
+        let mut plain = vec![128, 0x34, 0x12];
+        plain.extend_from_slice(&[255; 40]);
+        plain.push(0);
+        let mut palette = vec![130, 0x34, 0x12, 0x78, 0x56, 0x81];
+        palette.extend_from_slice(&[255; 40]);
+        palette.push(0);
+
+        for tile in [plain, palette] {
+            let mut compressed = Vec::with_capacity(128);
+            Compress::new(Compression::default(), true)
+                .compress_vec(&tile, &mut compressed, FlushCompress::Sync)
+                .unwrap();
+            let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(&compressed);
+            let result = Decoder::new(true)
+                .decode(
+                    &PixelFormat::rgb565(),
+                    &rect,
+                    &mut data.as_slice(),
+                    &|_| async { Ok(()) },
+                )
+                .await;
+            assert!(matches!(result, Err(VncError::InvalidImageData)));
+        }
+    }
+
+    #[tokio::test]
+    async fn one_stream_or_one_per_rect() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        // a solid tile, then the data of two rectangles compressed the ZRLE or the ZRLEE way
+        let tile = [1, 0x11, 0x22, 0x33];
+        let zrle = {
+            let mut compress = Compress::new(Compression::default(), true);
+            [0, 1].map(|_| {
+                let mut compressed = Vec::with_capacity(64);
+                compress
+                    .compress_vec(&tile, &mut compressed, FlushCompress::Sync)
+                    .unwrap();
+                compressed
+            })
+        };
+        let zrlee = [0, 1].map(|_| {
+            let mut compressed = Vec::with_capacity(64);
+            Compress::new(Compression::default(), true)
+                .compress_vec(&tile, &mut compressed, FlushCompress::Finish)
+                .unwrap();
+            compressed
+        });
+
+        let decode = |mut decoder: Decoder, rects: [Vec<u8>; 2]| async move {
+            let output = |e| {
+                assert!(matches!(e, VncEvent::RawImage(..)));
+                async { Ok(()) }
+            };
+            for compressed in rects {
+                let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+                data.extend_from_slice(&compressed);
+                decoder
+                    .decode(&PixelFormat::bgra(), &rect, &mut data.as_slice(), &output)
+                    .await?;
+            }
+            Ok::<_, VncError>(())
+        };
+        assert!(decode(Decoder::new(false), zrle.clone()).await.is_ok());
+        assert!(decode(Decoder::new(true), zrlee.clone()).await.is_ok());
+        // the second header or the missing one breaks the stream
+        assert!(decode(Decoder::new(false), zrlee).await.is_err());
+        assert!(decode(Decoder::new(true), zrle).await.is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::protocol::security::SecurityType;
 use thiserror::Error;
 
 #[non_exhaustive]
@@ -9,18 +10,62 @@ pub enum VncError {
     NoEncoding,
     #[error("Unknow VNC security type: {0}")]
     InvalidSecurityType(u8),
-    #[error("Wrong password")]
-    WrongPassword,
+    #[error("None of the security types {0:?} offered by the server is supported")]
+    NoSupportedSecurityType(Vec<SecurityType>),
+    #[error("Connection rejected by the server: {0}")]
+    ServerRejected(String),
+    #[error("{0:?} failed, another security type may still work: {1}")]
+    SecurityFallback(SecurityType, Box<VncError>),
+    /// The password was rejected, along with the reason given by RFB 3.8 servers
+    #[error("Wrong password{}", .0.as_ref().map(|reason| format!(": {reason}")).unwrap_or_default())]
+    WrongPassword(Option<String>),
     #[error("Connect error with unknown reason")]
     ConnectError,
     #[error("Unknown pixel format")]
     WrongPixelFormat,
     #[error("Unkonw server message")]
     WrongServerMessage,
+    #[error("Unknown server message type {0}")]
+    UnknownMessage(u8),
+    #[error("Unknown encoding {0}")]
+    UnknownEncoding(i32),
+    #[error("Invalid SecurityResult {0}")]
+    InvalidAuthResult(u32),
+    /// A rectangle couldn't be decoded, with the bytes of it read until then
+    ///
+    /// Only with the `capture` feature, which keeps a copy of every rectangle while it is decoded
+    #[cfg(feature = "capture")]
+    #[error("Decoding {encoding:?} at {rect:?} failed: {source}")]
+    DecodeFailed {
+        encoding: crate::VncEncoding,
+        rect: crate::Rect,
+        payload: Vec<u8>,
+        source: Box<VncError>,
+    },
     #[error("Image data cannot be decoded correctly")]
     InvalidImageData,
+    /// Valid data this build can't turn into pixels, `reason` tells what is missing
+    #[error("{encoding:?} rectangle is not supported: {reason}")]
+    Unsupported {
+        encoding: crate::VncEncoding,
+        reason: &'static str,
+    },
+    #[error("Server message of {0} bytes exceeds the configured limit")]
+    OversizedMessage(usize),
+    #[error("Rectangle {0:?} lies outside of the framebuffer")]
+    RectOutOfBounds(crate::Rect),
+    #[error("Compressed data inflates to more than the rectangle needs")]
+    DecompressionBomb,
+    #[error("The server hasn't announced xvp")]
+    XvpUnavailable,
+    #[error("The server hasn't announced continuous updates")]
+    ContinuousUpdatesUnavailable,
+    #[error("The server hasn't announced ExtendedDesktopSize")]
+    DesktopResizeUnavailable,
     #[error("The VNC client isn't started. Or it is already closed")]
     ClientNotRunning,
+    #[error("Timed out reading from the VNC server")]
+    ReadTimeout,
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error("VNC Error with message: {0}")]
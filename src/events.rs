@@ -1,4 +1,5 @@
-use crate::protocol::{PixelFormat, Rect, Screen};
+use crate::protocol::{PixelFormat, Rect, Screen, VncEncoding};
+use std::sync::Arc;
 
 type ImageData = Vec<u8>;
 type SrcRect = Rect;
@@ -27,30 +28,135 @@ pub enum VncEvent {
     /// The engine will generate a [VncEvent::SetPixelFormat] to let the window know how to render image
     ///
     SetPixelFormat(PixelFormat),
+    /// Update the palette used when the PixelFormat isn't true colour
+    ///
+    /// Only the entries from `first` on are replaced, see [crate::protocol::ColourMap]
+    ///
+    /// According to [RFC6143, section-7.6.2](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6.2)
+    ///
+    SetColourMap { first: u16, colors: Vec<[u16; 3]> },
     /// Raw image data in the order followed by informed PixelFormat
     ///
+    /// Rows are packed without padding, each pixel taking `bits_per_pixel / 8` bytes:
+    /// a colour map index at 8 bits, a 16 or 32 bit value in the format's byte order otherwise
+    ///
     RawImage(Rect, ImageData),
     /// Copy image data from the second rect to the first
     ///
     Copy(DstRect, SrcRect),
+    /// The whole screen at the end of a framebuffer update, with all its rectangles composited
+    ///
+    /// Replaces [VncEvent::RawImage] and [VncEvent::Copy] if [crate::VncConnector::composite_frames] is set
+    ///
+    /// `framebuffer_slice` is row by row in the current [PixelFormat], as wide as the last [VncEvent::SetResolution]
+    ///
+    /// Drop it before the next update arrives, the buffer is copied instead of reused as long as it is held
+    ///
+    Frame {
+        dirty_rects: Vec<Rect>,
+        framebuffer_slice: Arc<Vec<u8>>,
+    },
+    /// The rectangles of the update just received covered the whole screen
+    ///
+    /// Follows the events of those rectangles, and precedes the [VncEvent::Frame] of the update,
+    ///
+    /// only generated if [crate::VncConnector::report_full_redraws] is set
+    ///
+    /// Anything drawn on top of the image, like overlays, should be reset
+    ///
+    FullRedraw,
+    /// The rectangle as it came over the wire, without the 12 bytes header
+    ///
+    /// Follows the decoded events of the same rectangle,
+    ///
+    /// only generated if [crate::VncConnector::keep_encoded_rectangles] is set
+    ///
+    /// Note that the zlib based encodings can't be decoded out of their stream
+    ///
+    EncodedRectangle {
+        rect: Rect,
+        encoding: VncEncoding,
+        raw_bytes: Vec<u8>,
+    },
+    /// A server message registered with [crate::VncConnector::register_server_message]
+    ///
+    /// Carries the message-type and the body following it
+    ///
+    ExtensionMessage(u8, Vec<u8>),
     /// A jpeg image if using Tight encoding,
     ///
     /// Encoding the bytes with base64 and render it with "<img src=data:image/jpeg;base64,.../>",
     ///
+    /// With the `jpeg` feature these are decoded and reported as [VncEvent::RawImage] instead
+    ///
     JpegImage(Rect, ImageData),
 
     // PngImage(Rect, ImageData),
-    /// Will be generated if [crate::VncEncoding::CursorPseudo] is set
+    /// Will be generated if [crate::VncEncoding::CursorPseudo] or [crate::VncEncoding::CursorWithAlphaPseudo] is set
+    ///
+    /// The size of the cursor, its image as [PixelFormat::rgba] whatever the format, and the hotspot,
+    ///
+    /// which the x and y of the rect are as well
+    ///
+    /// The bitmask of the classic cursor makes the alpha 0 or 255, the alpha cursor brings its own
     ///
     /// According to [RFC6143, section-7.8.1](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.8.1)
     ///
-    SetCursor(Rect, ImageData),
+    SetCursor(Rect, ImageData, (u16, u16)),
+    /// Will be generated if [crate::VncEncoding::PointerPosPseudo] is set
+    ///
+    /// The position of the pointer on the server, which also moves without our pointer events
+    ///
+    /// It is the x and y of the rectangle header, and only reported while the encoding is in use
+    ///
+    CursorPosition(u16, u16),
+    /// Will be generated if [crate::VncEncoding::DesktopNamePseudo] is set
+    ///
+    /// The name is limited as set by [crate::VncConnector::set_max_name_length]
+    ///
+    SetDesktopName(String),
+    /// Will be generated if [crate::VncEncoding::ExtendedDesktopSizePseudo] is set,
+    ///
+    /// right after the [VncEvent::SetResolution] of every accepted resize and once the server announced it
+    ///
+    /// Hand the screens back to [crate::VncClient::set_desktop_size], the server knows them by their ids
+    ///
+    SetScreenLayout(Vec<crate::ScreenLayout>),
+    /// The server refused our [crate::VncClient::set_desktop_size]
+    ///
+    /// The status is 1 if resizing is prohibited, 2 if the server is out of resources and 3 for an invalid layout
+    ///
+    DesktopSizeRejected(u16),
+    /// Generated first thing if the connection is not encrypted
+    ///
+    /// i.e. None or VncAuth security, or VeNCrypt without TLS, with `reason` telling which
+    ///
+    /// Meant to be shown to the user, e.g. as a "this connection is not encrypted" banner
+    ///
+    SecurityWarning { reason: String },
+    /// Will be generated if [crate::VncEncoding::XvpPseudo] is set and the server supports xvp
+    ///
+    /// Carries the xvp-extension-version in use, [crate::VncClient::xvp_operation] works from now on
+    ///
+    XvpReady(u8),
+    /// The server couldn't carry out an xvp operation
+    ///
+    XvpFailed,
+    /// Will be generated if [crate::VncEncoding::ContinuousUpdatesPseudo] is set and the server supports them
+    ///
+    /// The first one announces them, [crate::VncClient::enable_continuous_updates] works from now on,
+    ///
+    /// the later ones confirm they were disabled, the updates requested after it are sent as usual
+    ///
+    EndOfContinuousUpdates,
     /// Just ring a bell
     ///
     Bell,
     /// Will be generated everytime the vncserver's clipboarded get updated
     ///
-    /// Note that only Latin-1 character set is allowed
+    /// Note that only Latin-1 character set is allowed,
+    ///
+    /// unless the server speaks [crate::VncEncoding::ExtendedClipboardPseudo], which carries UTF-8
     ///
     /// According to [RFC6143](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6.4)
     ///
@@ -100,6 +206,78 @@ impl From<(u16, u16, u8)> for ClientMouseEvent {
     }
 }
 
+/// How wheel and trackpad scrolling is turned into the buttons 4 to 7 of the pointer events
+///
+/// See [ScrollConfig::pointer_events] and [crate::VncClient::scroll]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrollConfig {
+    /// "Natural" scrolling, where the content follows the fingers
+    pub invert_vertical: bool,
+    /// Send horizontal scrolling as the buttons 6 (left) and 7 (right), dropped otherwise
+    pub horizontal_enabled: bool,
+    /// Wheel clicks sent to the server per tick of the delta
+    pub lines_per_tick: u8,
+}
+
+impl Default for ScrollConfig {
+    fn default() -> Self {
+        Self {
+            invert_vertical: false,
+            horizontal_enabled: true,
+            lines_per_tick: 1,
+        }
+    }
+}
+
+impl ScrollConfig {
+    /// The press & release pairs scrolling by `delta` at `position`, with `buttons` held throughout
+    ///
+    /// `delta` is in ticks as reported by most windowing libraries, positive is up and right,
+    ///
+    /// a fraction of a tick still scrolls by one click
+    ///
+    pub fn pointer_events(
+        &self,
+        position: (u16, u16),
+        buttons: u8,
+        delta: (f32, f32),
+    ) -> Vec<ClientMouseEvent> {
+        // the bits of the buttons 4 (up), 5 (down), 6 (left) and 7 (right)
+        let (up, down) = if self.invert_vertical {
+            (1 << 4, 1 << 3)
+        } else {
+            (1 << 3, 1 << 4)
+        };
+        let horizontal = if self.horizontal_enabled {
+            delta.0
+        } else {
+            0.0
+        };
+        let clicks = |delta: f32| {
+            let ticks = delta.abs() * self.lines_per_tick as f32;
+            if ticks > 0.0 {
+                ticks.round().max(1.0) as usize
+            } else {
+                0
+            }
+        };
+
+        let (x, y) = position;
+        [
+            (if delta.1 > 0.0 { up } else { down }, clicks(delta.1)),
+            (
+                if horizontal > 0.0 { 1 << 6 } else { 1 << 5 },
+                clicks(horizontal),
+            ),
+        ]
+        .into_iter()
+        .flat_map(|(bit, clicks)| std::iter::repeat_n(bit, clicks))
+        .flat_map(|bit| [(x, y, buttons | bit).into(), (x, y, buttons).into()])
+        .collect()
+    }
+}
+
 /// Client-side event which used to ask the engine send some command to the vnc server
 ///
 #[non_exhaustive]
@@ -107,16 +285,75 @@ impl From<(u16, u16, u8)> for ClientMouseEvent {
 pub enum X11Event {
     /// Require a frame update
     ///
+    /// An incremental one for the whole screen, see [crate::VncClient::request_update] for a full one
+    ///
     Refresh,
     /// Key down/up
     ///
     KeyEvent(ClientKeyEvent),
+    /// Key down/up with the hardware scancode, for games and BIOS screens that keysyms can't drive
+    ///
+    /// `keycode` is the XT scancode, a 2 bytes one starting with 0xE0 has the high bit of its second byte set,
+    ///
+    /// e.g. right control (0xE0 0x1D) is 0x9D
+    ///
+    /// Needs [crate::VncEncoding::QemuExtendedKeyEventPseudo] and a server that announced it,
+    ///
+    /// see [crate::VncClient::supports_extended_key]; otherwise only `keysym` is sent as a [X11Event::KeyEvent]
+    ///
+    ExtendedKeyEvent {
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    },
     /// Mouse move/up/down/scroll
     ///
+    /// Only the position and the button mask reach the server, stylus pressure or tilt can't be sent:
+    ///
+    /// neither RFB nor the QEMU client messages (extended key events and audio) have a field for them
+    ///
     PointerEvent(ClientMouseEvent),
     /// Send data to the server's clipboard
     ///
-    /// Only Latin-1 character set is allowed
+    /// The text is sent as ISO 8859-1 (Latin-1), as RFB requires,
+    ///
+    /// so any character beyond U+00FF reaches the server as `?`
+    ///
+    /// With [crate::VncEncoding::ExtendedClipboardPseudo] it is UTF-8 once the server announced it
     ///
     CopyText(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masks(events: Vec<ClientMouseEvent>) -> Vec<u8> {
+        events.into_iter().map(|e| e.bottons).collect()
+    }
+
+    #[test]
+    fn scroll_to_buttons() {
+        let config = ScrollConfig::default();
+        assert_eq!(masks(config.pointer_events((5, 5), 0, (0.0, 1.0))), [8, 0]);
+        assert_eq!(
+            masks(config.pointer_events((5, 5), 1, (0.0, -0.2))),
+            [17, 1]
+        );
+        assert_eq!(
+            masks(config.pointer_events((5, 5), 0, (-1.0, 0.0))),
+            [32, 0]
+        );
+
+        let config = ScrollConfig {
+            invert_vertical: true,
+            horizontal_enabled: false,
+            lines_per_tick: 3,
+        };
+        assert_eq!(
+            masks(config.pointer_events((5, 5), 0, (2.0, 1.0))),
+            [16, 0, 16, 0, 16, 0]
+        );
+        assert!(config.pointer_events((5, 5), 0, (0.0, 0.0)).is_empty());
+    }
+}
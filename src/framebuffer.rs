@@ -0,0 +1,502 @@
+use crate::{protocol::ColourMap, PixelFormat, Rect, VncEvent};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The screen kept in memory, painted from the [VncEvent]s of a [crate::VncClient]
+///
+/// For headless users and tests that only want the current pixels, without a window to draw on
+///
+/// The client composites [crate::VncConnector::composite_frames] and [crate::VncClient::await_first_frame]
+///
+/// with the same type, so the pixels come out alike whichever way they were painted
+///
+/// ```
+/// use vnc::{framebuffer::Framebuffer, PixelFormat, Rect, Screen, VncEvent};
+///
+/// let mut fb = Framebuffer::new(PixelFormat::bgra());
+/// fb.apply(&VncEvent::SetResolution(Screen { width: 1, height: 1 }));
+/// let rect = Rect { x: 0, y: 0, width: 1, height: 1 };
+/// fb.apply(&VncEvent::RawImage(rect, vec![30, 20, 10, 0]));
+/// assert_eq!(fb.dimensions(), (1, 1));
+/// assert_eq!(fb.as_rgba_bytes(), vec![10, 20, 30, 255]);
+/// ```
+///
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Framebuffer {
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+    /// shared with the [VncEvent::Frame]s handed out, copied before painting only while one is held
+    pixels: Arc<Vec<u8>>,
+    cursor: Option<(Rect, Vec<u8>)>,
+    colour_map: ColourMap,
+    covered: Vec<bool>,
+    /// pixels not received since the last resize
+    missing: usize,
+    /// painted since the last [VncEvent::Frame], only kept track of for [Framebuffer::frame]
+    dirty: Option<Vec<Rect>>,
+    /// pixels kept room for, see [crate::VncConnector::preallocate_framebuffer]
+    reserved: usize,
+}
+
+impl Framebuffer {
+    /// An empty screen, until the first [VncEvent::SetResolution]
+    ///
+    /// `format` should be the one given to [crate::VncConnector::set_pixel_format]
+    ///
+    pub fn new(format: PixelFormat) -> Self {
+        Self::with_size(format, 0, 0)
+    }
+
+    /// A black screen of `width` x `height`
+    pub(crate) fn with_size(format: PixelFormat, width: u16, height: u16) -> Self {
+        let (width, height) = (width as usize, height as usize);
+        let bpp = format.bytes_per_pixel();
+        Self {
+            format,
+            width,
+            height,
+            pixels: Arc::new(vec![0; width * height * bpp]),
+            cursor: None,
+            colour_map: ColourMap::new(),
+            covered: vec![false; width * height],
+            missing: width * height,
+            dirty: None,
+            reserved: 0,
+        }
+    }
+
+    /// Keep track of the painted rectangles for [Framebuffer::frame]
+    pub(crate) fn for_frames(mut self) -> Self {
+        self.dirty = Some(Vec::new());
+        self
+    }
+
+    /// Apply `event`, the ones without any pixels are ignored
+    ///
+    /// Rectangles are clipped to the screen, and a [VncEvent::Frame] of the same size replaces it all
+    ///
+    /// [VncEvent::JpegImage] is skipped, enable the `jpeg` feature to have those as [VncEvent::RawImage]
+    ///
+    pub fn apply(&mut self, event: &VncEvent) {
+        match event {
+            VncEvent::SetResolution(screen) => self.resize(screen.width, screen.height),
+            VncEvent::SetPixelFormat(format) => {
+                let bpp = self.format.bytes_per_pixel();
+                self.format = *format;
+                if format.bytes_per_pixel() != bpp {
+                    self.resize(self.width as u16, self.height as u16);
+                }
+            }
+            VncEvent::SetColourMap { first, colors } => self.colour_map.update(*first, colors),
+            VncEvent::RawImage(rect, data) => self.paint(rect, data),
+            VncEvent::Copy(dst, src) => self.copy(dst, src),
+            // a frame of another size is stale, a SetResolution follows
+            VncEvent::Frame {
+                dirty_rects,
+                framebuffer_slice,
+            } if framebuffer_slice.len() == self.pixels.len() => {
+                self.pixels = framebuffer_slice.clone();
+                dirty_rects.iter().for_each(|rect| self.cover(rect));
+            }
+            VncEvent::SetCursor(rect, data, _) => {
+                self.cursor = (rect.width != 0 && rect.height != 0).then(|| (*rect, data.clone()));
+            }
+            _ => (),
+        }
+    }
+
+    /// [Framebuffer::apply] without copying the images, which are painted and not handed back
+    pub(crate) fn composite(&mut self, event: VncEvent) -> Option<VncEvent> {
+        self.apply(&event);
+        match event {
+            VncEvent::RawImage(..) | VncEvent::Copy(..) => None,
+            e => Some(e),
+        }
+    }
+
+    /// The width and height of the screen
+    ///
+    pub fn dimensions(&self) -> (u16, u16) {
+        (self.width as u16, self.height as u16)
+    }
+
+    /// The format the pixels are kept in
+    ///
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.format
+    }
+
+    /// The pixels row by row, as sent by the server in the [PixelFormat]
+    ///
+    /// These are colour map indexes when the format is not true colour
+    ///
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// The last [VncEvent::SetCursor] as RGBA, with the hotspot as the rect's x and y
+    ///
+    /// The cursor is not painted onto the screen, its position is up to the server
+    ///
+    pub fn cursor(&self) -> Option<(&Rect, &[u8])> {
+        self.cursor
+            .as_ref()
+            .map(|(rect, data)| (rect, data.as_slice()))
+    }
+
+    /// The screen as red, green, blue and alpha bytes, the alpha always 255
+    ///
+    /// Indexed pixels are looked up in the last [VncEvent::SetColourMap]s, black if never sent
+    ///
+    pub fn as_rgba_bytes(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel();
+        let pixels = self
+            .pixels
+            .chunks_exact(bpp)
+            .map(|bytes| self.read_pixel(bytes));
+        if self.format.true_color_flag == 0 {
+            return pixels
+                .flat_map(|pixel| self.colour_map.rgba(pixel as usize))
+                .collect();
+        }
+        let channel = |pixel: u32, max: u16, shift: u8| {
+            if max == 0 {
+                0
+            } else {
+                ((pixel >> shift) & max as u32) * 255 / max as u32
+            }
+        };
+        let f = &self.format;
+        let mut rgba = Vec::with_capacity(self.width * self.height * 4);
+        for pixel in pixels {
+            rgba.extend_from_slice(&[
+                channel(pixel, f.red_max, f.red_shift) as u8,
+                channel(pixel, f.green_max, f.green_shift) as u8,
+                channel(pixel, f.blue_max, f.blue_shift) as u8,
+                255,
+            ]);
+        }
+        rgba
+    }
+
+    /// Whether every pixel has been received since the last resize
+    pub(crate) fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+
+    /// The pixels, copied only if a [VncEvent::Frame] still holds them
+    pub(crate) fn into_pixels(self) -> Vec<u8> {
+        Arc::try_unwrap(self.pixels).unwrap_or_else(|pixels| pixels.to_vec())
+    }
+
+    /// Keep room for `max_width` x `max_height` pixels of up to 4 bytes, so smaller sizes never reallocate
+    pub(crate) fn reserve(&mut self, max_width: u16, max_height: u16) {
+        self.reserved = max_width as usize * max_height as usize;
+        self.resize(self.width as u16, self.height as u16);
+    }
+
+    /// Start over at `width` x `height`, in the buffers of the last size unless a frame still holds them
+    fn resize(&mut self, width: u16, height: u16) {
+        let (width, height) = (width as usize, height as usize);
+        let bpp = self.format.bytes_per_pixel();
+        let capacity = (width * height * bpp).max(self.reserved * 4);
+        match Arc::get_mut(&mut self.pixels) {
+            Some(pixels) => {
+                pixels.clear();
+                pixels.reserve(capacity);
+            }
+            None => self.pixels = Arc::new(Vec::with_capacity(capacity)),
+        }
+        Arc::get_mut(&mut self.pixels)
+            .unwrap()
+            .resize(width * height * bpp, 0);
+        self.covered.clear();
+        self.covered.reserve((width * height).max(self.reserved));
+        self.covered.resize(width * height, false);
+        self.width = width;
+        self.height = height;
+        self.missing = width * height;
+        if let Some(dirty) = &mut self.dirty {
+            dirty.clear();
+        }
+    }
+
+    /// Count the pixels outside of `area` as received already
+    pub(crate) fn expect_only(&mut self, area: Option<Rect>) {
+        let Some(area) = area else {
+            return;
+        };
+        let mut outside = vec![true; self.covered.len()];
+        for (_, pixels) in self.rows(&area) {
+            outside[pixels].fill(false);
+        }
+        for (covered, outside) in self.covered.iter_mut().zip(outside) {
+            *covered |= outside;
+        }
+        self.missing = self.covered.iter().filter(|covered| !**covered).count();
+    }
+
+    /// The dirty rectangles and the pixels as they are now
+    pub(crate) fn frame(&mut self) -> VncEvent {
+        VncEvent::Frame {
+            dirty_rects: self.dirty.as_mut().map(std::mem::take).unwrap_or_default(),
+            framebuffer_slice: self.pixels.clone(),
+        }
+    }
+
+    /// Count `rect` as received without painting it
+    pub(crate) fn cover(&mut self, rect: &Rect) {
+        for (_, pixels) in self.rows(rect) {
+            self.cover_pixels(pixels);
+        }
+    }
+
+    fn cover_pixels(&mut self, pixels: Range<usize>) {
+        for covered in &mut self.covered[pixels] {
+            if !*covered {
+                *covered = true;
+                self.missing -= 1;
+            }
+        }
+    }
+
+    fn read_pixel(&self, bytes: &[u8]) -> u32 {
+        let mut value = [0; 4];
+        if self.format.big_endian_flag != 0 {
+            value[4 - bytes.len()..].copy_from_slice(bytes);
+            u32::from_be_bytes(value)
+        } else {
+            value[..bytes.len()].copy_from_slice(bytes);
+            u32::from_le_bytes(value)
+        }
+    }
+
+    /// The pixels of each row of `rect`, clipped to the screen
+    fn rows(&self, rect: &Rect) -> Vec<(usize, Range<usize>)> {
+        let x = (rect.x as usize).min(self.width);
+        let end = (rect.x as usize + rect.width as usize).min(self.width);
+        let bottom = (rect.y as usize + rect.height as usize).min(self.height);
+        (rect.y as usize..bottom)
+            .map(|y| {
+                (
+                    y - rect.y as usize,
+                    y * self.width + x..y * self.width + end,
+                )
+            })
+            .collect()
+    }
+
+    fn paint(&mut self, rect: &Rect, data: &[u8]) {
+        let bpp = self.format.bytes_per_pixel();
+        let row_len = rect.width as usize * bpp;
+        let rows = self.rows(rect);
+        // copied only if the last frame is still held by the frontend
+        let frame = Arc::make_mut(&mut self.pixels);
+        let mut painted = Vec::with_capacity(rows.len());
+        for (row, pixels) in rows {
+            let src = row * row_len;
+            let len = pixels.len() * bpp;
+            // rows the data falls short of stay missing
+            if let Some(src) = data.get(src..src + len) {
+                frame[pixels.start * bpp..pixels.end * bpp].copy_from_slice(src);
+                painted.push(pixels);
+            }
+        }
+        painted
+            .into_iter()
+            .for_each(|pixels| self.cover_pixels(pixels));
+        if let Some(dirty) = &mut self.dirty {
+            dirty.push(*rect);
+        }
+    }
+
+    fn copy(&mut self, dst: &Rect, src: &Rect) {
+        let bpp = self.format.bytes_per_pixel();
+        let rows = self.rows(src);
+        let width = rows.first().map_or(0, |(_, pixels)| pixels.len());
+        let mut data = Vec::with_capacity(width * rows.len() * bpp);
+        for (_, pixels) in rows {
+            data.extend_from_slice(&self.pixels[pixels.start * bpp..pixels.end * bpp]);
+        }
+        self.paint(
+            &Rect {
+                width: width as u16,
+                ..*dst
+            },
+            &data,
+        );
+        self.cover(dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Screen;
+
+    #[test]
+    fn raw_image_then_copy() {
+        let mut fb = Framebuffer::new(PixelFormat::bgra());
+        fb.apply(&VncEvent::SetResolution(Screen {
+            width: 4,
+            height: 2,
+        }));
+        let (red, green) = ([0, 0, 255, 0], [0, 255, 0, 0]);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        fb.apply(&VncEvent::RawImage(rect, [red, green].concat()));
+        // onto the bottom right, the right half clipped away
+        let dst = Rect {
+            x: 3,
+            y: 1,
+            width: 2,
+            height: 1,
+        };
+        fb.apply(&VncEvent::Copy(dst, rect));
+
+        let (r, g, k) = ([255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 0, 255]);
+        assert_eq!(fb.dimensions(), (4, 2));
+        assert_eq!(fb.as_rgba_bytes(), [r, g, k, k, k, k, k, r].concat());
+    }
+
+    #[test]
+    fn rgba_from_16bpp() {
+        let rgb565 = PixelFormat::rgb565();
+        let mut fb = Framebuffer::new(rgb565);
+        fb.apply(&VncEvent::SetResolution(Screen {
+            width: 1,
+            height: 1,
+        }));
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        // full red and half a green
+        fb.apply(&VncEvent::RawImage(rect, 0xfc00_u16.to_le_bytes().to_vec()));
+        assert_eq!(fb.pixels(), [0x00, 0xfc]);
+        assert_eq!(fb.as_rgba_bytes(), [255, 129, 0, 255]);
+    }
+
+    #[test]
+    fn indexed_through_colour_map() {
+        let indexed =
+            PixelFormat::try_from([8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        let mut fb = Framebuffer::new(indexed);
+        fb.apply(&VncEvent::SetResolution(Screen {
+            width: 3,
+            height: 1,
+        }));
+        fb.apply(&VncEvent::SetColourMap {
+            first: 1,
+            colors: vec![[0xffff, 0x8000, 0]],
+        });
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 1,
+        };
+        fb.apply(&VncEvent::RawImage(rect, vec![1, 0, 9]));
+        assert_eq!(
+            fb.as_rgba_bytes(),
+            [[255, 128, 0, 255], [0, 0, 0, 255], [0, 0, 0, 255]].concat()
+        );
+    }
+
+    #[test]
+    fn frames_replace_the_screen() {
+        // what composite_frames sends instead of the images
+        let mut client = Framebuffer::with_size(PixelFormat::bgra(), 2, 1).for_frames();
+        let rect = Rect {
+            x: 1,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert!(client
+            .composite(VncEvent::RawImage(rect, vec![0, 0, 255, 0]))
+            .is_none());
+        let frame = client.frame();
+
+        let mut fb = Framebuffer::new(PixelFormat::bgra());
+        fb.apply(&VncEvent::SetResolution(Screen {
+            width: 2,
+            height: 1,
+        }));
+        fb.apply(&frame);
+        assert_eq!(fb.as_rgba_bytes(), [0, 0, 0, 255, 255, 0, 0, 255]);
+        // only the rectangle painted is in there
+        assert!(!fb.is_complete());
+        match frame {
+            VncEvent::Frame { dirty_rects, .. } => {
+                let [dirty] = dirty_rects[..] else {
+                    panic!("dirty {dirty_rects:?}")
+                };
+                assert_eq!((dirty.x, dirty.width), (1, 1));
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        // a frame of another size is stale
+        fb.apply(&VncEvent::SetResolution(Screen {
+            width: 1,
+            height: 1,
+        }));
+        fb.apply(&client.frame());
+        assert_eq!(fb.pixels(), [0; 4]);
+    }
+
+    #[test]
+    fn preallocated_framebuffer_is_reused() {
+        let mut frame = Framebuffer::with_size(PixelFormat::bgra(), 4, 3).for_frames();
+        frame.reserve(8, 8);
+        let pixels = frame.pixels.as_ptr();
+        frame.apply(&VncEvent::SetResolution((8, 8).into()));
+        assert_eq!(frame.pixels.as_ptr(), pixels);
+        assert_eq!((frame.pixels.len(), frame.missing), (8 * 8 * 4, 8 * 8));
+
+        // a frame held by the frontend isn't written over
+        let VncEvent::Frame {
+            framebuffer_slice, ..
+        } = frame.frame()
+        else {
+            unreachable!()
+        };
+        frame.apply(&VncEvent::SetResolution((2, 2).into()));
+        assert_eq!(framebuffer_slice.len(), 8 * 8 * 4);
+        assert_eq!(frame.pixels.len(), 2 * 2 * 4);
+        assert!(frame.pixels.capacity() >= 8 * 8 * 4);
+    }
+
+    #[test]
+    fn short_image_data_is_not_covered() {
+        let mut frame = Framebuffer::with_size(PixelFormat::bgra(), 2, 2);
+        // the second row is missing
+        frame.apply(&VncEvent::RawImage(
+            Rect {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+            },
+            vec![1; 2 * 4],
+        ));
+        assert_eq!(frame.missing, 2);
+
+        frame.expect_only(Some(Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        }));
+        assert_eq!(frame.missing, 0);
+    }
+}
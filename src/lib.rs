@@ -80,10 +80,15 @@ pub mod client;
 pub mod codec;
 pub mod error;
 pub mod events;
+pub mod framebuffer;
 pub mod protocol;
 
 // 重新导出常用类型，方便调用方使用
 pub use client::{Credentials, VncClient, VncConnector};
 pub use error::*;
 pub use events::*;
-pub use protocol::{PixelFormat, Rect, Screen, VncEncoding, VncVersion};
+pub use protocol::security::supported_security_types;
+pub use protocol::{
+    supported_encodings, PixelFormat, QualityPreset, Rect, Screen, ScreenLayout, VncEncoding,
+    VncVersion, XvpOperation,
+};
@@ -0,0 +1,104 @@
+use super::PixelFormat;
+
+/// The palette used to interpret pixels when `true_color_flag` is not set
+///
+/// Maintained by the SetColourMapEntries messages,
+///
+/// according to [RFC6143, section-7.6.2](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6.2)
+///
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColourMap {
+    colors: Vec<[u16; 3]>,
+}
+
+impl ColourMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Overwrite the entries from `first` on with `colors`, leaving the others untouched
+    ///
+    /// Entries skipped over by `first` and never sent are black
+    pub fn update(&mut self, first: u16, colors: &[[u16; 3]]) {
+        let first = first as usize;
+        let end = first + colors.len();
+        if self.colors.len() < end {
+            self.colors.resize(end, [0; 3]);
+        }
+        self.colors[first..end].copy_from_slice(colors);
+    }
+
+    /// The `[red, green, blue]` intensities of the entry `index`, each from 0 to 65535
+    pub fn get(&self, index: usize) -> Option<[u16; 3]> {
+        self.colors.get(index).copied()
+    }
+
+    /// The entry `index` as red, green, blue and alpha bytes, black if missing
+    pub fn rgba(&self, index: usize) -> [u8; 4] {
+        let [r, g, b] = self.get(index).unwrap_or_default();
+        [(r >> 8) as u8, (g >> 8) as u8, (b >> 8) as u8, 255]
+    }
+
+    /// Look every pixel of `pixels`, in `format`, up as red, green, blue and alpha bytes
+    pub fn to_rgba(&self, format: &PixelFormat, pixels: &[u8]) -> Vec<u8> {
+        let bpp = format.bytes_per_pixel().max(1);
+        let mut rgba = Vec::with_capacity(pixels.len() / bpp * 4);
+        for pixel in pixels.chunks_exact(bpp) {
+            let index = pixel.iter().enumerate().fold(0, |index, (i, &byte)| {
+                let shift = if format.big_endian_flag != 0 {
+                    (bpp - 1 - i) * 8
+                } else {
+                    i * 8
+                };
+                index | (byte as usize) << shift
+            });
+            rgba.extend_from_slice(&self.rgba(index));
+        }
+        rgba
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_partial_updates() {
+        let mut map = ColourMap::new();
+        map.update(2, &[[1; 3], [2; 3], [3; 3]]);
+        map.update(3, &[[4; 3], [5; 3], [6; 3]]);
+
+        let entries: Vec<_> = (0..7).map(|index| map.get(index)).collect();
+        assert_eq!(
+            entries,
+            vec![
+                Some([0; 3]),
+                Some([0; 3]),
+                Some([1; 3]),
+                Some([4; 3]),
+                Some([5; 3]),
+                Some([6; 3]),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn indexes_to_rgba() {
+        let mut map = ColourMap::new();
+        map.update(0, &[[0xffff, 0, 0], [0, 0x8000, 0xffff]]);
+        map.update(0x101, &[[0x1234, 0x5678, 0x9abc]]);
+
+        let indexed =
+            PixelFormat::try_from([8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            map.to_rgba(&indexed, &[1, 0, 7]),
+            [[0, 0x80, 0xff, 255], [0xff, 0, 0, 255], [0, 0, 0, 255]].concat()
+        );
+
+        let wide =
+            PixelFormat::try_from([16, 16, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(map.to_rgba(&wide, &[1, 1]), [0x12, 0x56, 0x9a, 255]);
+    }
+}
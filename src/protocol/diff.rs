@@ -0,0 +1,138 @@
+use crate::Rect;
+
+/// The rectangles covering every pixel that differs between `old` and `new`, e.g. to generate updates on the server side
+///
+/// Both are `width` x `height` pixels row by row, of any but the same number of bytes per pixel
+///
+/// The framebuffer is compared in tiles of `tile_size` x `tile_size`, the dirty tiles of a row are joined into runs
+///
+/// and runs spanning the same columns on consecutive rows into one rectangle, so the result stays short
+///
+/// but may cover some unchanged pixels of the dirty tiles
+///
+/// ```
+/// use vnc::protocol::diff::compute_dirty_rects;
+///
+/// let old = vec![0; 64 * 64];
+/// let mut new = old.clone();
+/// new[10 * 64 + 20] = 1;
+/// let rects = compute_dirty_rects(&old, &new, 64, 64, 16);
+/// assert_eq!((rects[0].x, rects[0].y, rects[0].width, rects[0].height), (16, 0, 16, 16));
+/// ```
+///
+/// # Panics
+///
+/// If `tile_size` is zero, or the lengths differ or don't fit the size
+pub fn compute_dirty_rects(
+    old: &[u8],
+    new: &[u8],
+    width: u16,
+    height: u16,
+    tile_size: u16,
+) -> Vec<Rect> {
+    assert!(tile_size > 0, "tile_size must not be zero");
+    assert_eq!(old.len(), new.len(), "the framebuffers differ in size");
+    let pixel_count = width as usize * height as usize;
+    if pixel_count == 0 {
+        return Vec::new();
+    }
+    assert_eq!(
+        old.len() % pixel_count,
+        0,
+        "the framebuffers don't fit the size"
+    );
+    let bpp = old.len() / pixel_count;
+    let stride = width as usize * bpp;
+
+    let tile_dirty = |x: u16, y: u16, w: u16, h: u16| {
+        (y..y + h).any(|row| {
+            let start = row as usize * stride + x as usize * bpp;
+            let end = start + w as usize * bpp;
+            old[start..end] != new[start..end]
+        })
+    };
+
+    let mut rects: Vec<Rect> = Vec::new();
+    // the rectangles reaching down to the previous row of tiles, to be extended
+    let mut open: Vec<usize> = Vec::new();
+    let mut y = 0;
+    while y < height {
+        let h = tile_size.min(height - y);
+        let mut still_open = Vec::new();
+        let mut x = 0;
+        while x < width {
+            // a run of dirty tiles
+            let start = x;
+            while x < width && tile_dirty(x, y, tile_size.min(width - x), h) {
+                x += tile_size.min(width - x);
+            }
+            if x > start {
+                let run_width = x - start;
+                match open
+                    .iter()
+                    .find(|&&i| rects[i].x == start && rects[i].width == run_width)
+                {
+                    Some(&i) => {
+                        rects[i].height += h;
+                        still_open.push(i);
+                    }
+                    None => {
+                        still_open.push(rects.len());
+                        rects.push(Rect {
+                            x: start,
+                            y,
+                            width: run_width,
+                            height: h,
+                        });
+                    }
+                }
+            } else {
+                x += tile_size.min(width - x);
+            }
+        }
+        open = still_open;
+        y += h;
+    }
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuples(rects: Vec<Rect>) -> Vec<(u16, u16, u16, u16)> {
+        rects
+            .into_iter()
+            .map(|r| (r.x, r.y, r.width, r.height))
+            .collect()
+    }
+
+    #[test]
+    fn identical_frames() {
+        let frame = vec![7; 30 * 20 * 4];
+        assert!(compute_dirty_rects(&frame, &frame, 30, 20, 8).is_empty());
+    }
+
+    #[test]
+    fn tiles_are_coalesced() {
+        // 20x20 at 2 bytes per pixel in 8x8 tiles, the last ones are 4 wide or high
+        let (width, height) = (20_usize, 20_usize);
+        let old = vec![0; width * height * 2];
+        let mut new = old.clone();
+        let mut touch = |x: usize, y: usize| new[(y * width + x) * 2 + 1] = 1;
+        // two side by side tiles on two rows make a single rectangle
+        touch(1, 1);
+        touch(9, 1);
+        touch(1, 9);
+        touch(9, 9);
+        // narrower than the run above, so a rectangle of its own
+        touch(5, 17);
+        // the bottom right corner, an edge tile
+        touch(19, 19);
+
+        assert_eq!(
+            tuples(compute_dirty_rects(&old, &new, 20, 20, 8)),
+            vec![(0, 0, 16, 16), (0, 16, 8, 4), (16, 16, 4, 4)]
+        );
+    }
+}
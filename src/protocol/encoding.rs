@@ -1,16 +1,158 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(i32)]
 pub enum VncEncoding {
     Raw = 0,
     CopyRect = 1,
-    // Rre = 2,
-    // Hextile = 5,
+    Rre = 2,
+    Hextile = 5,
     Tight = 7,
     Trle = 15,
     Zrle = 16,
     CursorPseudo = -239,
+    /// A cursor with a full alpha channel, preferred by the servers that have it over [VncEncoding::CursorPseudo]
+    CursorWithAlphaPseudo = -314,
     DesktopSizePseudo = -223,
     LastRectPseudo = -224,
+    /// Reports where the server's pointer is as [crate::VncEvent::CursorPosition]
+    PointerPosPseudo = -232,
+    /// Reports a new desktop name as [crate::VncEvent::SetDesktopName]
+    DesktopNamePseudo = -307,
+    /// Resizing with a layout of screens, see [crate::VncClient::set_desktop_size]
+    ExtendedDesktopSizePseudo = -308,
+    /// Fences are answered automatically, which servers use for flow control
+    FencePseudo = -312,
+    /// Lets the server announce continuous updates, see [crate::VncClient::enable_continuous_updates]
+    ContinuousUpdatesPseudo = -313,
+    /// Lets the server announce xvp, see [crate::VncClient::xvp_operation]
+    XvpPseudo = -309,
+    /// Lets the server announce QEMU extended key events, see [crate::X11Event::ExtendedKeyEvent]
+    QemuExtendedKeyEventPseudo = -258,
+    /// UTF-8 clipboard in both directions, falls back to Latin-1 if the server doesn't announce it
+    ExtendedClipboardPseudo = -1063131698,
+    // Tight options, the level is added to the base value
+    // https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#jpeg-quality-level-pseudo-encoding
+    JpegQualityLevel2Pseudo = -30,
+    JpegQualityLevel6Pseudo = -26,
+    JpegQualityLevel9Pseudo = -23,
+    CompressLevel1Pseudo = -255,
+    CompressLevel9Pseudo = -247,
+    // TurboVNC extensions
+    // https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#jpeg-fine-grained-quality-level-pseudo-encoding
+    JpegFineQualityLevel30Pseudo = -482,
+    JpegFineQualityLevel80Pseudo = -432,
+    JpegFineQualityLevel95Pseudo = -417,
+    JpegSubsampling1XPseudo = -768,
+    JpegSubsampling4XPseudo = -767,
+    JpegSubsampling2XPseudo = -766,
+}
+
+/// Named trade-offs between bandwidth and image quality, see [crate::VncConnector::set_quality_preset]
+///
+/// Each one informs both the standard quality / compression levels, which TigerVNC, QEMU
+///
+/// and most other servers follow, and the fine-grained TurboVNC ones, which TurboVNC
+///
+/// prefers over the former, so the same preset means about the same on either kind of server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityPreset {
+    /// Coarse JPEG with 4X chroma subsampling and the strongest zlib compression
+    LowBandwidth,
+    /// Medium JPEG with 2X subsampling and light compression
+    Balanced,
+    /// Near lossless JPEG without subsampling
+    HighQuality,
+    /// No JPEG at all, Tight then only sends lossless data
+    Lossless,
+}
+
+impl QualityPreset {
+    /// Whether `encoding` is one of the levels a preset is made of
+    pub(crate) fn is_level(encoding: VncEncoding) -> bool {
+        matches!(
+            encoding,
+            VncEncoding::JpegQualityLevel2Pseudo
+                | VncEncoding::JpegQualityLevel6Pseudo
+                | VncEncoding::JpegQualityLevel9Pseudo
+                | VncEncoding::CompressLevel1Pseudo
+                | VncEncoding::CompressLevel9Pseudo
+                | VncEncoding::JpegFineQualityLevel30Pseudo
+                | VncEncoding::JpegFineQualityLevel80Pseudo
+                | VncEncoding::JpegFineQualityLevel95Pseudo
+                | VncEncoding::JpegSubsampling1XPseudo
+                | VncEncoding::JpegSubsampling4XPseudo
+                | VncEncoding::JpegSubsampling2XPseudo
+        )
+    }
+
+    /// The pseudo-encodings the preset expands to
+    pub fn encodings(self) -> &'static [VncEncoding] {
+        match self {
+            QualityPreset::LowBandwidth => &[
+                VncEncoding::JpegQualityLevel2Pseudo,
+                VncEncoding::CompressLevel9Pseudo,
+                VncEncoding::JpegFineQualityLevel30Pseudo,
+                VncEncoding::JpegSubsampling4XPseudo,
+            ],
+            QualityPreset::Balanced => &[
+                VncEncoding::JpegQualityLevel6Pseudo,
+                VncEncoding::CompressLevel1Pseudo,
+                VncEncoding::JpegFineQualityLevel80Pseudo,
+                VncEncoding::JpegSubsampling2XPseudo,
+            ],
+            QualityPreset::HighQuality => &[
+                VncEncoding::JpegQualityLevel9Pseudo,
+                VncEncoding::CompressLevel1Pseudo,
+                VncEncoding::JpegFineQualityLevel95Pseudo,
+                VncEncoding::JpegSubsampling1XPseudo,
+            ],
+            QualityPreset::Lossless => &[VncEncoding::CompressLevel1Pseudo],
+        }
+    }
+}
+
+/// The encodings this crate can decode (or, for the settings, inform), most efficient first
+///
+/// Every variant of [VncEncoding] is listed at the moment,
+///
+/// a variant added ahead of its decoder is kept out of here until it works
+///
+/// ```
+/// assert!(vnc::supported_encodings().contains(&vnc::VncEncoding::Zrle));
+/// ```
+pub fn supported_encodings() -> &'static [VncEncoding] {
+    &[
+        VncEncoding::Tight,
+        VncEncoding::Zrle,
+        VncEncoding::Trle,
+        VncEncoding::Hextile,
+        VncEncoding::Rre,
+        VncEncoding::CopyRect,
+        VncEncoding::Raw,
+        VncEncoding::CursorWithAlphaPseudo,
+        VncEncoding::CursorPseudo,
+        VncEncoding::DesktopSizePseudo,
+        VncEncoding::LastRectPseudo,
+        VncEncoding::PointerPosPseudo,
+        VncEncoding::DesktopNamePseudo,
+        VncEncoding::ExtendedDesktopSizePseudo,
+        VncEncoding::FencePseudo,
+        VncEncoding::ContinuousUpdatesPseudo,
+        VncEncoding::XvpPseudo,
+        VncEncoding::QemuExtendedKeyEventPseudo,
+        VncEncoding::ExtendedClipboardPseudo,
+        VncEncoding::JpegQualityLevel2Pseudo,
+        VncEncoding::JpegQualityLevel6Pseudo,
+        VncEncoding::JpegQualityLevel9Pseudo,
+        VncEncoding::CompressLevel1Pseudo,
+        VncEncoding::CompressLevel9Pseudo,
+        VncEncoding::JpegFineQualityLevel30Pseudo,
+        VncEncoding::JpegFineQualityLevel80Pseudo,
+        VncEncoding::JpegFineQualityLevel95Pseudo,
+        VncEncoding::JpegSubsampling1XPseudo,
+        VncEncoding::JpegSubsampling4XPseudo,
+        VncEncoding::JpegSubsampling2XPseudo,
+    ]
 }
 
 impl From<VncEncoding> for u32 {
@@ -19,20 +161,60 @@ impl From<VncEncoding> for u32 {
     }
 }
 
-impl From<u32> for VncEncoding {
-    fn from(num: u32) -> Self {
-        match num {
+/// Fails with [crate::VncError::UnknownEncoding] for the encodings this crate doesn't know
+impl TryFrom<u32> for VncEncoding {
+    type Error = crate::VncError;
+
+    fn try_from(num: u32) -> Result<Self, Self::Error> {
+        Ok(match num {
             0 => VncEncoding::Raw,
             1 => VncEncoding::CopyRect,
-            // 2 => VncEncoding::Rre,
-            // 5 => VncEncoding::Hextile,
+            2 => VncEncoding::Rre,
+            5 => VncEncoding::Hextile,
             7 => VncEncoding::Tight,
             15 => VncEncoding::Trle,
             16 => VncEncoding::Zrle,
             val if val == -239i32 as u32 => VncEncoding::CursorPseudo,
+            val if val == -314i32 as u32 => VncEncoding::CursorWithAlphaPseudo,
             val if val == -223i32 as u32 => VncEncoding::DesktopSizePseudo,
             val if val == -224i32 as u32 => VncEncoding::LastRectPseudo,
-            _ => panic!("Unknown encoding: {num}"),
-        }
+            val if val == -232i32 as u32 => VncEncoding::PointerPosPseudo,
+            val if val == -307i32 as u32 => VncEncoding::DesktopNamePseudo,
+            val if val == -308i32 as u32 => VncEncoding::ExtendedDesktopSizePseudo,
+            val if val == -312i32 as u32 => VncEncoding::FencePseudo,
+            val if val == -313i32 as u32 => VncEncoding::ContinuousUpdatesPseudo,
+            val if val == -309i32 as u32 => VncEncoding::XvpPseudo,
+            val if val == -258i32 as u32 => VncEncoding::QemuExtendedKeyEventPseudo,
+            val if val == -1063131698i32 as u32 => VncEncoding::ExtendedClipboardPseudo,
+            val if val == -30i32 as u32 => VncEncoding::JpegQualityLevel2Pseudo,
+            val if val == -26i32 as u32 => VncEncoding::JpegQualityLevel6Pseudo,
+            val if val == -23i32 as u32 => VncEncoding::JpegQualityLevel9Pseudo,
+            val if val == -255i32 as u32 => VncEncoding::CompressLevel1Pseudo,
+            val if val == -247i32 as u32 => VncEncoding::CompressLevel9Pseudo,
+            val if val == -482i32 as u32 => VncEncoding::JpegFineQualityLevel30Pseudo,
+            val if val == -432i32 as u32 => VncEncoding::JpegFineQualityLevel80Pseudo,
+            val if val == -417i32 as u32 => VncEncoding::JpegFineQualityLevel95Pseudo,
+            val if val == -768i32 as u32 => VncEncoding::JpegSubsampling1XPseudo,
+            val if val == -767i32 as u32 => VncEncoding::JpegSubsampling4XPseudo,
+            val if val == -766i32 as u32 => VncEncoding::JpegSubsampling2XPseudo,
+            _ => return Err(crate::VncError::UnknownEncoding(num as i32)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_encoding_is_an_error() {
+        assert_eq!(
+            VncEncoding::try_from(-308_i32 as u32).unwrap(),
+            VncEncoding::ExtendedDesktopSizePseudo
+        );
+        assert!(matches!(
+            VncEncoding::try_from(-1_i32 as u32),
+            Err(crate::VncError::UnknownEncoding(-1))
+        ));
     }
 }
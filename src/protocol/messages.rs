@@ -1,6 +1,12 @@
-use crate::{PixelFormat, Rect, VncEncoding, VncError};
+use crate::protocol::text::{from_latin1, read_string, to_latin1};
+use crate::{PixelFormat, Rect, ScreenLayout, VncEncoding, VncError};
+use std::collections::HashMap;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// A message from the client, written by [ClientMsg::write]
+///
+/// according to [RFC6143, section-7.5](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.5)
+///
 #[derive(Debug)]
 pub enum ClientMsg {
     SetPixelFormat(PixelFormat),
@@ -9,9 +15,134 @@ pub enum ClientMsg {
     KeyEvent(u32, bool),
     PointerEvent(u16, u16, u8),
     ClientCutText(String),
+    Fence(u32, Vec<u8>),
+    /// Whether to enable them, and the area to keep up to date
+    EnableContinuousUpdates(bool, Rect),
+    /// The xvp-extension-version and the operation
+    Xvp(u8, XvpOperation),
+    /// The width, the height and the screens
+    SetDesktopSize(u16, u16, Vec<ScreenLayout>),
+    /// The flags and the payload of an extended ClientCutText, see [clipboard]
+    ExtendedClipboard(u32, Vec<u8>),
+    /// The keysym, the XT scancode and whether the key is down
+    QemuExtendedKeyEvent(u32, u32, bool),
+}
+
+/// The xvp-extension-version this crate speaks
+pub const XVP_VERSION: u8 = 1;
+
+/// Power operations of the xvp extension, sent with [crate::VncClient::xvp_operation]
+///
+/// according to [rfbproto](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#xvp-client-message)
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum XvpOperation {
+    Shutdown = 2,
+    Reboot = 3,
+    Reset = 4,
+}
+
+/// The flags of a Fence message
+///
+/// according to [rfbproto](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#fence)
+///
+pub mod fence {
+    pub const BLOCK_BEFORE: u32 = 1;
+    pub const BLOCK_AFTER: u32 = 1 << 1;
+    pub const SYNC_NEXT: u32 = 1 << 2;
+    pub const REQUEST: u32 = 1 << 31;
+}
+
+/// The flags of the extended clipboard messages, which carry UTF-8 text
+///
+/// according to [rfbproto](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#extended-clipboard-pseudo-encoding)
+///
+pub mod clipboard {
+    use super::ClientMsg;
+    use crate::VncError;
+    use std::io::{Read, Write};
+
+    // the formats
+    pub const TEXT: u32 = 1;
+    pub const RTF: u32 = 1 << 1;
+    pub const HTML: u32 = 1 << 2;
+    pub const DIB: u32 = 1 << 3;
+    pub const FILES: u32 = 1 << 4;
+    // the actions
+    pub const CAPS: u32 = 1 << 24;
+    pub const REQUEST: u32 = 1 << 25;
+    pub const PEEK: u32 = 1 << 26;
+    pub const NOTIFY: u32 = 1 << 27;
+    pub const PROVIDE: u32 = 1 << 28;
+
+    /// The most text taken from the server, as announced in our caps
+    pub const MAX_TEXT_LEN: u32 = 20 << 20;
+
+    /// Our caps: text only, with every action
+    pub(crate) fn caps() -> ClientMsg {
+        ClientMsg::ExtendedClipboard(
+            CAPS | REQUEST | PEEK | NOTIFY | PROVIDE | TEXT,
+            MAX_TEXT_LEN.to_be_bytes().to_vec(),
+        )
+    }
+
+    /// A provide message with `text`, as nul terminated UTF-8 with CRLF line endings in a zlib stream of its own
+    pub(crate) fn provide(text: &str) -> Result<ClientMsg, VncError> {
+        let mut data = text
+            .replace("\r\n", "\n")
+            .replace('\n', "\r\n")
+            .into_bytes();
+        data.push(0);
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&(data.len() as u32).to_be_bytes())?;
+        encoder.write_all(&data)?;
+        Ok(ClientMsg::ExtendedClipboard(
+            PROVIDE | TEXT,
+            encoder.finish()?,
+        ))
+    }
+
+    /// The text of a provide message, if it has any
+    ///
+    /// The formats come in the order of their bits, so the text is always the first one
+    pub(crate) fn provided_text(flags: u32, payload: &[u8]) -> Result<Option<String>, VncError> {
+        if flags & TEXT == 0 {
+            return Ok(None);
+        }
+        let mut decoder = flate2::read::ZlibDecoder::new(payload);
+        let mut len = [0; 4];
+        decoder.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_TEXT_LEN {
+            return Err(VncError::OversizedMessage(len as usize));
+        }
+        let mut data = vec![0; len as usize];
+        decoder.read_exact(&mut data)?;
+        let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+        Ok(Some(
+            String::from_utf8_lossy(&data[..end]).replace("\r\n", "\n"),
+        ))
+    }
 }
 
 impl ClientMsg {
+    /// Write the message as it goes on the wire
+    ///
+    /// These are the bytes a [crate::VncClient] sends, so a proxy can inject or re-encode messages without one
+    ///
+    /// ```
+    /// use vnc::{protocol::ClientMsg, VncError};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), VncError> {
+    ///     let mut wire = Vec::new();
+    ///     ClientMsg::KeyEvent(0xff0d, true).write(&mut wire).await?;
+    ///     assert_eq!(wire, [4, 1, 0, 0, 0, 0, 0xff, 0x0d]);
+    ///     Ok(())
+    /// }
+    /// ```
     pub async fn write<S>(self, writer: &mut S) -> Result<(), VncError>
     where
         S: AsyncWrite + Unpin,
@@ -110,9 +241,107 @@ impl ClientMsg {
                 //   | 4            | U32          | length       |
                 //   | length       | U8 array     | text         |
                 //   +--------------+--------------+--------------+
+                let text = to_latin1(&s);
                 let mut payload = vec![6_u8, 0, 0, 0];
-                payload.extend_from_slice(&(s.len() as u32).to_be_bytes());
-                payload.write_all(s.as_bytes()).await?;
+                payload.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                payload.extend_from_slice(&text);
+                writer.write_all(&payload).await?;
+                Ok(())
+            }
+            ClientMsg::ExtendedClipboard(flags, data) => {
+                // ClientCutText with a negative length, which covers the flags and the data
+                let len = -((data.len() + 4) as i32);
+                let mut payload = vec![6_u8, 0, 0, 0];
+                payload.extend_from_slice(&len.to_be_bytes());
+                payload.extend_from_slice(&flags.to_be_bytes());
+                payload.extend_from_slice(&data);
+                writer.write_all(&payload).await?;
+                Ok(())
+            }
+            ClientMsg::Fence(flags, data) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [248]     | message-type |
+                // | 3            |              | padding      |
+                // | 4            | U32          | flags        |
+                // | 1            | U8           | length       |
+                // | length       | U8 array     | payload      |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![248_u8, 0, 0, 0];
+                payload.extend_from_slice(&flags.to_be_bytes());
+                payload.push(data.len() as u8);
+                payload.extend_from_slice(&data);
+                writer.write_all(&payload).await?;
+                Ok(())
+            }
+            ClientMsg::EnableContinuousUpdates(enable, rect) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [150]     | message-type |
+                // | 1            | U8           | enable-flag  |
+                // | 2            | U16          | x-position   |
+                // | 2            | U16          | y-position   |
+                // | 2            | U16          | width        |
+                // | 2            | U16          | height       |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![150, enable as u8];
+                payload.extend_from_slice(&rect.x.to_be_bytes());
+                payload.extend_from_slice(&rect.y.to_be_bytes());
+                payload.extend_from_slice(&rect.width.to_be_bytes());
+                payload.extend_from_slice(&rect.height.to_be_bytes());
+                writer.write_all(&payload).await?;
+                Ok(())
+            }
+            ClientMsg::QemuExtendedKeyEvent(keysym, keycode, down) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [255]     | message-type |
+                // | 1            | U8 [0]       | submessage   |
+                // | 2            | U16          | down-flag    |
+                // | 4            | U32          | keysym       |
+                // | 4            | U32          | keycode      |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![255, 0];
+                payload.extend_from_slice(&(down as u16).to_be_bytes());
+                payload.extend_from_slice(&keysym.to_be_bytes());
+                payload.extend_from_slice(&keycode.to_be_bytes());
+                writer.write_all(&payload).await?;
+                Ok(())
+            }
+            ClientMsg::Xvp(version, op) => {
+                // +--------------+--------------+-----------------------+
+                // | No. of bytes | Type [Value] | Description           |
+                // +--------------+--------------+-----------------------+
+                // | 1            | U8 [250]     | message-type          |
+                // | 1            |              | padding               |
+                // | 1            | U8           | xvp-extension-version |
+                // | 1            | U8           | xvp-message-code      |
+                // +--------------+--------------+-----------------------+
+                writer.write_all(&[250, 0, version, op as u8]).await?;
+                Ok(())
+            }
+            ClientMsg::SetDesktopSize(width, height, screens) => {
+                // +--------------+--------------+-------------------+
+                // | No. of bytes | Type [Value] | Description       |
+                // +--------------+--------------+-------------------+
+                // | 1            | U8 [251]     | message-type      |
+                // | 1            |              | padding           |
+                // | 2            | U16          | width             |
+                // | 2            | U16          | height            |
+                // | 1            | U8           | number-of-screens |
+                // | 1            |              | padding           |
+                // +--------------+--------------+-------------------+
+                // followed by number-of-screens times the 16 bytes of a screen
+                let mut payload = vec![251, 0];
+                payload.extend_from_slice(&width.to_be_bytes());
+                payload.extend_from_slice(&height.to_be_bytes());
+                payload.extend_from_slice(&[screens.len() as u8, 0]);
+                for screen in screens {
+                    payload.extend_from_slice(&screen.to_bytes());
+                }
                 writer.write_all(&payload).await?;
                 Ok(())
             }
@@ -120,16 +349,55 @@ impl ClientMsg {
     }
 }
 
+/// A message from the server, read by [ServerMsg::read]
+///
+/// according to [RFC6143, section-7.6](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6)
+///
+/// [ServerMsg::FramebufferUpdate] is only the header with the number of rectangles,
+///
+/// the rectangles follow it and their length is only known by decoding them with the codecs of a [crate::VncClient]
+///
 #[derive(Debug)]
 pub enum ServerMsg {
     FramebufferUpdate(u16),
-    // SetColorMapEntries,
+    SetColourMapEntries(u16, Vec<[u16; 3]>),
     Bell,
     ServerCutText(String),
+    /// The flags and the payload of an extended ServerCutText, see [clipboard]
+    ExtendedClipboard(u32, Vec<u8>),
+    Fence(u32, Vec<u8>),
+    /// Sent once the server sees [VncEncoding::ContinuousUpdatesPseudo], and whenever they stop
+    EndOfContinuousUpdates,
+    /// The xvp-extension-version and the message-code, 0 for XVP_FAIL and 1 for XVP_INIT
+    Xvp(u8, u8),
+    Extension(u8, Vec<u8>),
 }
 
 impl ServerMsg {
-    pub async fn read<S>(reader: &mut S) -> Result<Self, VncError>
+    /// Read the next message from the server
+    ///
+    /// `extensions` maps the message-types not known to us to the length of their body,
+    ///
+    /// which is then read as a [ServerMsg::Extension], any other type is an error
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use vnc::{protocol::ServerMsg, VncError};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), VncError> {
+    ///     // a Bell, then a ServerCutText
+    ///     let mut wire: &[u8] = &[2, 3, 0, 0, 0, 0, 0, 0, 2, b'h', b'i'];
+    ///     let extensions = HashMap::new();
+    ///     assert!(matches!(ServerMsg::read(&mut wire, &extensions).await?, ServerMsg::Bell));
+    ///     assert!(matches!(
+    ///         ServerMsg::read(&mut wire, &extensions).await?,
+    ///         ServerMsg::ServerCutText(text) if text == "hi"
+    ///     ));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn read<S>(reader: &mut S, extensions: &HashMap<u8, usize>) -> Result<Self, VncError>
     where
         S: AsyncRead + Unpin,
     {
@@ -159,7 +427,27 @@ impl ServerMsg {
                 // | 2            | U16          | first-color      |
                 // | 2            | U16          | number-of-colors |
                 // +--------------+--------------+------------------+
-                unimplemented!()
+
+                // This is followed by number-of-colors repetitions of the following:
+                // +--------------+--------------+-------------+
+                // | No. of bytes | Type [Value] | Description |
+                // +--------------+--------------+-------------+
+                // | 2            | U16          | red         |
+                // | 2            | U16          | green       |
+                // | 2            | U16          | blue        |
+                // +--------------+--------------+-------------+
+                let _padding = reader.read_u8().await?;
+                let first_color = reader.read_u16().await?;
+                let num = reader.read_u16().await?;
+                let mut colors = Vec::with_capacity(num as usize);
+                for _ in 0..num {
+                    colors.push([
+                        reader.read_u16().await?,
+                        reader.read_u16().await?,
+                        reader.read_u16().await?,
+                    ]);
+                }
+                Ok(ServerMsg::SetColourMapEntries(first_color, colors))
             }
             2 => {
                 // Bell
@@ -182,14 +470,230 @@ impl ServerMsg {
                 // +--------------+--------------+--------------+
                 let mut padding = [0; 3];
                 reader.read_exact(&mut padding).await?;
-                let len = reader.read_u32().await?;
-                let mut buffer_str = vec![0; len as usize];
-                reader.read_exact(&mut buffer_str).await?;
-                Ok(Self::ServerCutText(
-                    String::from_utf8_lossy(&buffer_str).to_string(),
-                ))
-            }
-            _ => Err(VncError::WrongServerMessage),
+                let len = reader.read_i32().await?;
+                if len >= 0 {
+                    // the same limit as the extended clipboard, the length is up to the server
+                    if len as u32 > clipboard::MAX_TEXT_LEN {
+                        return Err(VncError::OversizedMessage(len as usize));
+                    }
+                    let mut text = vec![0; len as usize];
+                    reader.read_exact(&mut text).await?;
+                    Ok(Self::ServerCutText(from_latin1(&text)))
+                } else {
+                    // a negative length means the extended clipboard: the flags followed by the data
+                    let len = len.unsigned_abs() as usize;
+                    if len < 4 {
+                        return Err(VncError::WrongServerMessage);
+                    }
+                    if len > clipboard::MAX_TEXT_LEN as usize {
+                        return Err(VncError::OversizedMessage(len));
+                    }
+                    let flags = reader.read_u32().await?;
+                    let mut data = vec![0; len - 4];
+                    reader.read_exact(&mut data).await?;
+                    Ok(Self::ExtendedClipboard(flags, data))
+                }
+            }
+            248 => {
+                // ServerFence, same as the client one
+                let mut padding = [0; 3];
+                reader.read_exact(&mut padding).await?;
+                let flags = reader.read_u32().await?;
+                let len = reader.read_u8().await?;
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data).await?;
+                Ok(Self::Fence(flags, data))
+            }
+            150 => {
+                // EndOfContinuousUpdates, nothing but the message-type
+                Ok(Self::EndOfContinuousUpdates)
+            }
+            250 => {
+                // xvp, laid out as the client one
+                let _padding = reader.read_u8().await?;
+                let version = reader.read_u8().await?;
+                let code = reader.read_u8().await?;
+                Ok(Self::Xvp(version, code))
+            }
+            msg_type => match extensions.get(&msg_type) {
+                Some(&len) => {
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).await?;
+                    Ok(Self::Extension(msg_type, body))
+                }
+                // without the length there is no way to find the next message
+                None => Err(VncError::UnknownMessage(msg_type)),
+            },
+        }
+    }
+}
+
+/// Longest desktop name accepted unless configured otherwise, names are never legitimately large
+pub const DEFAULT_MAX_NAME_LEN: usize = 4096;
+
+/// The ServerInit message, which describes the desktop once the security handshake is done
+///
+/// according to [RFC6143, section-7.3.2](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.3.2)
+///
+#[derive(Debug, Clone)]
+pub struct ServerInit {
+    pub framebuffer_width: u16,
+    pub framebuffer_height: u16,
+    pub server_pixel_format: PixelFormat,
+    /// the pixel format as sent, to relay it byte for byte
+    pub raw_pixel_format: [u8; 16],
+    /// decoded lossily if not valid UTF-8
+    pub name: String,
+}
+
+impl ServerInit {
+    /// Read a ServerInit message, e.g. when relaying a connection without a [crate::VncClient]
+    ///
+    /// The name is limited to [DEFAULT_MAX_NAME_LEN] bytes
+    pub async fn read<S>(reader: &mut S) -> Result<Self, VncError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        Self::read_with_limit(reader, DEFAULT_MAX_NAME_LEN).await
+    }
+
+    /// Same as [ServerInit::read], failing with [VncError::OversizedMessage]
+    ///
+    /// if the name is longer than `max_name_len` bytes
+    pub async fn read_with_limit<S>(reader: &mut S, max_name_len: usize) -> Result<Self, VncError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +--------------+--------------+------------------------------+
+        // | No. of bytes | Type [Value] | Description                  |
+        // +--------------+--------------+------------------------------+
+        // | 2            | U16          | framebuffer-width in pixels  |
+        // | 2            | U16          | framebuffer-height in pixels |
+        // | 16           | PIXEL_FORMAT | server-pixel-format          |
+        // | 4            | U32          | name-length                  |
+        // | name-length  | U8 array     | name-string                  |
+        // +--------------+--------------+------------------------------+
+        let framebuffer_width = reader.read_u16().await?;
+        let framebuffer_height = reader.read_u16().await?;
+        let mut raw_pixel_format = [0; 16];
+        reader.read_exact(&mut raw_pixel_format).await?;
+        let server_pixel_format = raw_pixel_format.try_into()?;
+        let name = read_string(reader, max_name_len).await?;
+        Ok(Self {
+            framebuffer_width,
+            framebuffer_height,
+            server_pixel_format,
+            raw_pixel_format,
+            name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn client_cut_text_is_latin1() {
+        let mut written = Vec::new();
+        ClientMsg::ClientCutText("caf\u{e9} \u{20ac}5".to_string())
+            .write(&mut written)
+            .await
+            .unwrap();
+        // the euro sign has no Latin-1 form
+        let mut expected = vec![6, 0, 0, 0, 0, 0, 0, 7];
+        expected.extend_from_slice(&[b'c', b'a', b'f', 0xe9, b' ', b'?', b'5']);
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn oversized_cut_text_rejected() {
+        // only the length of a Latin-1 text, nothing of it follows
+        let msg = [3, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff];
+        assert!(matches!(
+            ServerMsg::read(&mut &msg[..], &HashMap::new()).await,
+            Err(VncError::OversizedMessage(0x7fff_ffff))
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_request_modes() {
+        let rect = Rect {
+            x: 1,
+            y: 2,
+            width: 640,
+            height: 480,
+        };
+        for incremental in [0, 1] {
+            let mut written = Vec::new();
+            ClientMsg::FramebufferUpdateRequest(rect, incremental)
+                .write(&mut written)
+                .await
+                .unwrap();
+            assert_eq!(written, [3, incremental, 0, 1, 0, 2, 2, 128, 1, 224]);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_desktop_size() {
+        let screen = ScreenLayout {
+            id: 0x01020304,
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            flags: 0,
+        };
+        let mut written = Vec::new();
+        ClientMsg::SetDesktopSize(1920, 1080, vec![screen])
+            .write(&mut written)
+            .await
+            .unwrap();
+        assert_eq!(
+            written,
+            [
+                251, 0, 7, 128, 4, 56, 1, 0, // the header
+                1, 2, 3, 4, 0, 0, 0, 0, 7, 128, 4, 56, 0, 0, 0, 0, // the screen
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn extended_cut_text() {
+        let ClientMsg::ExtendedClipboard(flags, payload) =
+            clipboard::provide("one\ntwo\r\n").unwrap()
+        else {
+            unreachable!()
+        };
+        // sent with CRLF line endings and a nul
+        let mut inflated = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(payload.as_slice()),
+            &mut inflated,
+        )
+        .unwrap();
+        assert_eq!(inflated, b"\0\0\0\x0bone\r\ntwo\r\n\0");
+
+        let mut written = Vec::new();
+        ClientMsg::ExtendedClipboard(flags, payload.clone())
+            .write(&mut written)
+            .await
+            .unwrap();
+        // the server message has the same layout
+        written[0] = 3;
+        match ServerMsg::read(&mut written.as_slice(), &HashMap::new())
+            .await
+            .unwrap()
+        {
+            ServerMsg::ExtendedClipboard(read_flags, read_payload) => {
+                assert_eq!(read_flags, clipboard::PROVIDE | clipboard::TEXT);
+                assert_eq!(read_payload, payload);
+                assert_eq!(
+                    clipboard::provided_text(read_flags, &read_payload).unwrap(),
+                    Some("one\ntwo\n".to_string())
+                );
+            }
+            m => panic!("unexpected message {m:?}"),
         }
     }
 }
@@ -1,12 +1,17 @@
+pub mod colour_map;
+pub mod convert;
+pub mod diff;
 pub mod encoding;
 pub mod messages;
 pub mod pixel_format;
 pub mod rect;
 pub mod security;
+pub(crate) mod text;
 pub mod version;
 
-pub use encoding::VncEncoding;
-pub use messages::{ClientMsg, ServerMsg};
+pub use colour_map::ColourMap;
+pub use encoding::{supported_encodings, QualityPreset, VncEncoding};
+pub use messages::{ClientMsg, ServerInit, ServerMsg, XvpOperation};
 pub use pixel_format::PixelFormat;
-pub use rect::{Rect, Screen};
+pub use rect::{Rect, Screen, ScreenLayout};
 pub use version::VncVersion;
@@ -1,5 +1,4 @@
 use crate::VncError;
-use tokio::io::{AsyncRead, AsyncReadExt};
 
 ///  Pixel Format Data Structure according to [RFC6143](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.4)
 ///
@@ -21,6 +20,7 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 /// +--------------+--------------+-----------------+
 /// ```
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PixelFormat {
     /// the number of bits used for each pixel value on the wire
     ///
@@ -156,12 +156,38 @@ impl PixelFormat {
         }
     }
 
-    pub(crate) async fn read<S>(reader: &mut S) -> Result<Self, VncError>
-    where
-        S: AsyncRead + Unpin,
-    {
-        let mut pixel_buffer = [0_u8; 16];
-        reader.read_exact(&mut pixel_buffer).await?;
-        pixel_buffer.try_into()
+    // (r << 11 | g << 5 | b) in le
+    // half the bytes of bgra, for targets that only draw 16 bits
+    pub fn rgb565() -> PixelFormat {
+        Self {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..Default::default()
+        }
+    }
+
+    // (b << 11 | g << 5 | r) in le
+    pub fn bgr565() -> PixelFormat {
+        Self {
+            red_shift: 0,
+            blue_shift: 11,
+            ..Self::rgb565()
+        }
+    }
+
+    /// Size of a single pixel in bytes
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Offset of the pixel (`x`, `y`) in a buffer of `stride` pixels per row
+    pub fn byte_offset(&self, x: usize, y: usize, stride: usize) -> usize {
+        (y * stride + x) * self.bytes_per_pixel()
     }
 }
@@ -1,5 +1,6 @@
 /// A rect where the image should be updated
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Rect {
     pub x: u16,
     pub y: u16,
@@ -7,6 +8,26 @@ pub struct Rect {
     pub height: u16,
 }
 
+impl Rect {
+    /// The part of `self` that is also in `other`, `None` if they don't overlap
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x as u32 + self.width as u32).min(other.x as u32 + other.width as u32);
+        let bottom = (self.y as u32 + self.height as u32).min(other.y as u32 + other.height as u32);
+        if right <= x as u32 || bottom <= y as u32 {
+            None
+        } else {
+            Some(Rect {
+                x,
+                y,
+                width: (right - x as u32) as u16,
+                height: (bottom - y as u32) as u16,
+            })
+        }
+    }
+}
+
 /// Resolution format to resize window
 #[derive(Debug, Clone)]
 pub struct Screen {
@@ -22,3 +43,47 @@ impl From<(u16, u16)> for Screen {
         }
     }
 }
+
+/// One screen of the desktop, as laid out by the ExtendedDesktopSize pseudo-encoding
+///
+/// according to [rfbproto](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#extendeddesktopsize-pseudo-encoding)
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenLayout {
+    /// chosen by the server, keep it when sending the layout back
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// unused so far, zero
+    pub flags: u32,
+}
+
+impl ScreenLayout {
+    pub(crate) async fn read<S>(reader: &mut S) -> Result<Self, crate::VncError>
+    where
+        S: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+        Ok(Self {
+            id: reader.read_u32().await?,
+            x: reader.read_u16().await?,
+            y: reader.read_u16().await?,
+            width: reader.read_u16().await?,
+            height: reader.read_u16().await?,
+            flags: reader.read_u32().await?,
+        })
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..4].copy_from_slice(&self.id.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.x.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.y.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.width.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.height.to_be_bytes());
+        bytes[12..].copy_from_slice(&self.flags.to_be_bytes());
+        bytes
+    }
+}
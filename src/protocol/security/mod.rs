@@ -1,5 +1,6 @@
 pub mod des;
+pub(crate) mod tight;
 pub mod types;
 pub mod vencrypt;
 
-pub use types::{AuthResult, SecurityType};
+pub use types::{supported_security_types, AuthResult, SecurityType};
@@ -0,0 +1,161 @@
+use crate::client::auth::AuthHelper;
+use crate::protocol::security::types::AuthResult;
+use crate::protocol::text::read_reason;
+use crate::{VncError, VncVersion};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, trace};
+
+/// The tunnel code of plain RFB, no tunnel at all
+const NO_TUNNEL: i32 = 0;
+const NO_AUTH: i32 = 1;
+const VNC_AUTH: i32 = 2;
+
+/// More tunnel or auth capabilities than any server has
+const MAX_CAPABILITIES: u32 = 64;
+
+/// A tunnel, auth, message or encoding the TightVNC server announces
+///
+/// ```text
+/// +--------------+--------------+-------------+
+/// | No. of bytes | Type [Value] | Description |
+/// +--------------+--------------+-------------+
+/// | 4            | S32          | code        |
+/// | 4            | U8 array     | vendor      |
+/// | 8            | U8 array     | name        |
+/// +--------------+--------------+-------------+
+/// ```
+struct Capability {
+    code: i32,
+    vendor: [u8; 4],
+    name: [u8; 8],
+}
+
+impl std::fmt::Debug for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}/{}",
+            self.code,
+            String::from_utf8_lossy(&self.vendor),
+            String::from_utf8_lossy(&self.name)
+        )
+    }
+}
+
+async fn read_capabilities<S>(reader: &mut S, count: usize) -> Result<Vec<Capability>, VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut capabilities = Vec::with_capacity(count);
+    for _ in 0..count {
+        let code = reader.read_i32().await?;
+        let mut vendor = [0; 4];
+        reader.read_exact(&mut vendor).await?;
+        let mut name = [0; 8];
+        reader.read_exact(&mut name).await?;
+        capabilities.push(Capability { code, vendor, name });
+    }
+    Ok(capabilities)
+}
+
+/// Read a tunnel or auth capability list, preceded by its U32 length
+async fn read_capability_list<S>(reader: &mut S) -> Result<Vec<Capability>, VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let count = reader.read_u32().await?;
+    if count > MAX_CAPABILITIES {
+        return Err(VncError::OversizedMessage(count as usize));
+    }
+    read_capabilities(reader, count as usize).await
+}
+
+/// Go through the Tight security type once chosen, up to and including the SecurityResult
+///
+/// No tunnel is ever used, and of the auths only none and VncAuth are known
+pub(crate) async fn authenticate<S>(
+    stream: &mut S,
+    password: Option<&str>,
+    version: VncVersion,
+    lenient: Option<Duration>,
+) -> Result<(), VncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tunnels = read_capability_list(stream).await?;
+    trace!("Tight tunnels: {:?}", tunnels);
+    // nothing is chosen from an empty list
+    if !tunnels.is_empty() {
+        if !tunnels.iter().any(|c| c.code == NO_TUNNEL) {
+            error!("Tight security with tunnels only: {:?}", tunnels);
+            return Err(VncError::General(
+                "The server requires a Tight tunnel".to_string(),
+            ));
+        }
+        stream.write_i32(NO_TUNNEL).await?;
+    }
+
+    let auths = read_capability_list(stream).await?;
+    trace!("Tight auths: {:?}", auths);
+    if auths.is_empty() || auths.iter().any(|c| c.code == NO_AUTH) {
+        if !auths.is_empty() {
+            stream.write_i32(NO_AUTH).await?;
+        }
+        // like SecurityType::None, only 3.8 sends a SecurityResult for it
+        if version == VncVersion::RFB38 {
+            if let AuthResult::Failed = AuthResult::read(stream, lenient).await? {
+                return Err(VncError::ServerRejected(read_reason(stream).await?));
+            }
+        }
+        return Ok(());
+    }
+    if !auths.iter().any(|c| c.code == VNC_AUTH) {
+        error!("No known Tight auth in {:?}", auths);
+        return Err(VncError::General(format!(
+            "None of the Tight auths {auths:?} is supported"
+        )));
+    }
+    stream.write_i32(VNC_AUTH).await?;
+    let Some(password) = password else {
+        return Err(VncError::MisingPassword);
+    };
+    let auth = AuthHelper::read(stream, password).await?;
+    auth.write(stream).await?;
+    if let AuthResult::Failed = AuthResult::read(stream, lenient).await? {
+        let reason = match version {
+            VncVersion::RFB38 => Some(read_reason(stream).await?),
+            _ => None,
+        };
+        error!("Tight VncAuth failed: {:?}", reason);
+        return Err(VncError::WrongPassword(reason));
+    }
+    Ok(())
+}
+
+/// Skip the interaction capabilities a Tight server sends right after the ServerInit
+///
+/// ```text
+/// +--------------+--------------+-------------------------+
+/// | No. of bytes | Type [Value] | Description             |
+/// +--------------+--------------+-------------------------+
+/// | 2            | U16          | number-of-server-msgs   |
+/// | 2            | U16          | number-of-client-msgs   |
+/// | 2            | U16          | number-of-encodings     |
+/// | 2            |              | padding                 |
+/// +--------------+--------------+-------------------------+
+/// ```
+///
+/// followed by as many capabilities of each, none of which are used
+pub(crate) async fn read_interaction_capabilities<S>(reader: &mut S) -> Result<(), VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let server_msgs = reader.read_u16().await? as usize;
+    let client_msgs = reader.read_u16().await? as usize;
+    let encodings = reader.read_u16().await? as usize;
+    let _padding = reader.read_u16().await?;
+    let capabilities = read_capabilities(reader, server_msgs + client_msgs + encodings).await?;
+    trace!("Tight interaction capabilities: {:?}", capabilities);
+    Ok(())
+}
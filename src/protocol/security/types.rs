@@ -1,4 +1,6 @@
+use crate::protocol::text::read_reason;
 use crate::{VncError, VncVersion};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[allow(dead_code)]
@@ -17,6 +19,7 @@ pub enum SecurityType {
     GtkVncSasl = 20,
     Md5Hash = 21,
     ColinDeanXvp = 22,
+    AppleRemoteDesktop = 30,
     RA2_256 = 129,
 }
 
@@ -36,12 +39,37 @@ impl TryFrom<u8> for SecurityType {
             20 => Ok(SecurityType::GtkVncSasl),
             21 => Ok(SecurityType::Md5Hash),
             22 => Ok(SecurityType::ColinDeanXvp),
+            30 => Ok(SecurityType::AppleRemoteDesktop),
             129 => Ok(SecurityType::RA2_256),
             invalid => Err(VncError::InvalidSecurityType(invalid)),
         }
     }
 }
 
+/// The security types the connector can go through, in the order it prefers them
+///
+/// Apple Remote Desktop is only among them with the `ard` feature
+///
+/// The other variants of [SecurityType] are only recognized, a server offering nothing but those
+///
+/// fails with [VncError::NoSupportedSecurityType]
+///
+/// ```
+/// use vnc::protocol::security::SecurityType;
+///
+/// assert!(!vnc::supported_security_types().contains(&SecurityType::Ultra));
+/// ```
+pub fn supported_security_types() -> &'static [SecurityType] {
+    &[
+        SecurityType::None,
+        SecurityType::VeNCrypt,
+        #[cfg(feature = "ard")]
+        SecurityType::AppleRemoteDesktop,
+        SecurityType::VncAuth,
+        SecurityType::Tight,
+    ]
+}
+
 impl From<SecurityType> for u8 {
     fn from(e: SecurityType) -> Self {
         e as u8
@@ -58,10 +86,8 @@ impl SecurityType {
                 let security_type = reader.read_u32().await?;
                 let security_type = (security_type as u8).try_into()?;
                 if let SecurityType::Invalid = security_type {
-                    let _ = reader.read_u32().await?;
-                    let mut err_msg = String::new();
-                    reader.read_to_string(&mut err_msg).await?;
-                    return Err(VncError::General(err_msg));
+                    let reason = read_reason(reader).await?;
+                    return Err(VncError::ServerRejected(reason));
                 }
                 Ok(vec![security_type])
             }
@@ -76,10 +102,8 @@ impl SecurityType {
                 let num = reader.read_u8().await?;
 
                 if num == 0 {
-                    let _ = reader.read_u32().await?;
-                    let mut err_msg = String::new();
-                    reader.read_to_string(&mut err_msg).await?;
-                    return Err(VncError::General(err_msg));
+                    let reason = read_reason(reader).await?;
+                    return Err(VncError::ServerRejected(reason));
                 }
                 let mut sec_types = vec![];
                 for _ in 0..num {
@@ -107,12 +131,47 @@ pub enum AuthResult {
     Failed = 1,
 }
 
-impl From<u32> for AuthResult {
-    fn from(num: u32) -> Self {
+impl AuthResult {
+    /// Read the SecurityResult, a U32 according to the RFC
+    ///
+    /// With `lenient` a single byte is accepted as well, as sent by some embedded servers,
+    ///
+    /// a zero byte followed by nothing for the duration given is then taken as success
+    pub(crate) async fn read<S>(reader: &mut S, lenient: Option<Duration>) -> Result<Self, VncError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let Some(wait) = lenient else {
+            return reader.read_u32().await?.try_into();
+        };
+        if reader.read_u8().await? != 0 {
+            // a U32 result always starts with 0, so this byte is the whole result
+            return Ok(AuthResult::Failed);
+        }
+        // after a single byte success the server waits for our ClientInit and sends nothing,
+        // so any further byte means a U32, however slowly the rest of it comes
+        let mut rest = [0; 3];
+        match tokio::time::timeout(wait, reader.read_u8()).await {
+            Ok(read) => rest[0] = read?,
+            Err(_) => {
+                tracing::trace!("Single byte SecurityResult");
+                return Ok(AuthResult::Ok);
+            }
+        }
+        reader.read_exact(&mut rest[1..]).await?;
+        u32::from_be_bytes([0, rest[0], rest[1], rest[2]]).try_into()
+    }
+}
+
+/// Fails with [VncError::InvalidAuthResult] for anything but 0 and 1
+impl TryFrom<u32> for AuthResult {
+    type Error = VncError;
+
+    fn try_from(num: u32) -> Result<Self, Self::Error> {
         match num {
-            0 => AuthResult::Ok,
-            1 => AuthResult::Failed,
-            _ => panic!("Invalid AuthResult value: {num}"),
+            0 => Ok(AuthResult::Ok),
+            1 => Ok(AuthResult::Failed),
+            _ => Err(VncError::InvalidAuthResult(num)),
         }
     }
 }
@@ -122,3 +181,76 @@ impl From<AuthResult> for u32 {
         e as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejected_with_reason() {
+        // no security types, a reason claiming 4GB but only 15 bytes before EOF
+        let mut msg = vec![0, 0xff, 0xff, 0xff, 0xff];
+        msg.extend_from_slice(b"Too many tries!");
+        match SecurityType::read(&mut &msg[..], &VncVersion::RFB38).await {
+            Err(VncError::ServerRejected(reason)) => assert_eq!(reason, "Too many tries!"),
+            r => panic!("unexpected result {r:?}"),
+        }
+
+        let mut msg = vec![0, 0, 0, 0, 0, 0, 0, 7];
+        msg.extend_from_slice(b"Blocked");
+        match SecurityType::read(&mut &msg[..], &VncVersion::RFB33).await {
+            Err(VncError::ServerRejected(reason)) => assert_eq!(reason, "Blocked"),
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn lenient_security_result() {
+        let wait = Some(Duration::from_millis(100));
+        let result = AuthResult::read(&mut &[0, 0, 0, 1][..], wait)
+            .await
+            .unwrap();
+        assert!(matches!(result, AuthResult::Failed));
+        let result = AuthResult::read(&mut &[1, 0, 0, 0][..], wait)
+            .await
+            .unwrap();
+        assert!(matches!(result, AuthResult::Failed));
+
+        // a lone zero while the server waits for the ClientInit, which costs the whole wait
+        let (mut server, mut client) = tokio::io::duplex(16);
+        server.write_u8(0).await.unwrap();
+        let started = std::time::Instant::now();
+        let result = AuthResult::read(&mut client, wait).await.unwrap();
+        assert!(matches!(result, AuthResult::Ok));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        // nothing is lost once the server goes on
+        server.write_all(&[0, 4]).await.unwrap();
+        assert_eq!(client.read_u16().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn lenient_security_result_in_slow_pieces() {
+        let (mut server, mut client) = tokio::io::duplex(16);
+        let result = tokio::spawn(async move {
+            AuthResult::read(&mut client, Some(Duration::from_millis(100))).await
+        });
+        // only the gap after the first byte is timed, the rest may take longer
+        for (piece, pause) in [(&[0][..], 50), (&[0], 300), (&[0, 1], 0)] {
+            server.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+        }
+        assert!(matches!(result.await.unwrap(), Ok(AuthResult::Failed)));
+    }
+
+    #[tokio::test]
+    async fn invalid_security_result() {
+        for lenient in [false, true] {
+            let lenient = lenient.then_some(Duration::from_millis(100));
+            assert!(matches!(
+                AuthResult::read(&mut &[0, 0, 0, 7][..], lenient).await,
+                Err(VncError::InvalidAuthResult(7))
+            ));
+        }
+    }
+}
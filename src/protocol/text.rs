@@ -0,0 +1,62 @@
+use crate::VncError;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Read a U32 length followed by that many bytes
+///
+/// Fails with [VncError::OversizedMessage] before allocating anything if the length exceeds `max_len`
+pub(crate) async fn read_bytes<S>(reader: &mut S, max_len: usize) -> Result<Vec<u8>, VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = reader.read_u32().await?;
+    if len as usize > max_len {
+        return Err(VncError::OversizedMessage(len as usize));
+    }
+    let mut buffer = vec![0_u8; len as usize];
+    reader.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+/// Read a length-prefixed string such as a failure reason or a desktop name
+///
+/// Invalid UTF-8 sequences are replaced rather than failing the connection
+pub(crate) async fn read_string<S>(reader: &mut S, max_len: usize) -> Result<String, VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    Ok(String::from_utf8_lossy(&read_bytes(reader, max_len).await?).into_owned())
+}
+
+/// Longest failure reason we are willing to take from the server
+const MAX_REASON_LEN: u64 = 4096;
+
+/// Read a length-prefixed failure reason sent before the server closes the connection
+///
+/// The reason is cut at [MAX_REASON_LEN] bytes and whatever arrived before EOF is kept,
+///
+/// so a bogus length neither allocates gigabytes nor hides the reason behind an IO error
+pub(crate) async fn read_reason<S>(reader: &mut S) -> Result<String, VncError>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = reader.read_u32().await?;
+    let mut buffer = Vec::new();
+    AsyncReadExt::take(reader, MAX_REASON_LEN.min(len as u64))
+        .read_to_end(&mut buffer)
+        .await?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Decode ISO 8859-1 (Latin-1) bytes, as used for the clipboard
+pub(crate) fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&c| c as char).collect()
+}
+
+/// Encode a string as ISO 8859-1 (Latin-1) for the clipboard
+///
+/// Characters beyond U+00FF have no Latin-1 form and are replaced with `?`
+pub(crate) fn to_latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .collect()
+}
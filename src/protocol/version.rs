@@ -26,6 +26,9 @@ impl From<[u8; 12]> for VncVersion {
             b"RFB 003.003\n" => VncVersion::RFB33,
             b"RFB 003.007\n" => VncVersion::RFB37,
             b"RFB 003.008\n" => VncVersion::RFB38,
+            // macOS Screen Sharing advertises 3.889, but handshakes like 3.8
+            // and expects the 3.8 banner back
+            b"RFB 003.889\n" => VncVersion::RFB38,
             // https://www.rfc-editor.org/rfc/rfc6143#section-7.1.1
             //  Other version numbers are reported by some servers and clients,
             //  but should be interpreted as 3.3 since they do not implement the
@@ -36,6 +39,15 @@ impl From<[u8; 12]> for VncVersion {
 }
 
 impl VncVersion {
+    /// Whether `banner` is a well-formed `"RFB xxx.yyy\n"` ProtocolVersion message
+    pub(crate) fn is_valid_banner(banner: &[u8; 12]) -> bool {
+        banner.starts_with(b"RFB ")
+            && banner[4..7].iter().all(u8::is_ascii_digit)
+            && banner[7] == b'.'
+            && banner[8..11].iter().all(u8::is_ascii_digit)
+            && banner[11] == b'\n'
+    }
+
     pub(crate) async fn read<S>(reader: &mut S) -> Result<Self, VncError>
     where
         S: AsyncRead + Unpin,
@@ -55,3 +67,14 @@ impl VncVersion {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apple_banner_is_rfb38() {
+        assert_eq!(VncVersion::from(*b"RFB 003.889\n"), VncVersion::RFB38);
+        assert_eq!(VncVersion::from(*b"RFB 003.005\n"), VncVersion::RFB33);
+    }
+}